@@ -0,0 +1,28 @@
+use std::time::{Duration, Instant};
+
+use synchronous_timer::Timer;
+
+const ROUNDS: usize = 20_000;
+
+/// Measure how long it takes for a single immediate task to start running on an otherwise idle
+/// timer
+fn main() {
+    let mut t = Timer::new();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut latencies = Vec::with_capacity(ROUNDS);
+    for _ in 0..ROUNDS {
+        let tx = tx.clone();
+        let scheduled = Instant::now();
+        t.schedule_immediately(move || tx.send(Instant::now()).unwrap());
+        let started = rx.recv().unwrap();
+        latencies.push(started - scheduled);
+    }
+    latencies.sort_unstable();
+    let mean = latencies.iter().sum::<Duration>() / ROUNDS as u32;
+    println!(
+        "single immediate task latency: mean {:?}, p50 {:?}, p99 {:?}",
+        mean,
+        latencies[ROUNDS / 2],
+        latencies[ROUNDS * 99 / 100]
+    );
+}
@@ -1,27 +1,44 @@
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
 use synchronous_timer::Timer;
 
-const TARGET: u32 = 200_000;
+const TARGET: u32 = 2_000_000;
 
 fn main() {
-    let mut t = Timer::new();
+    // Pass `--bucketed` or `--wheel` to compare against the coarsely-ordered queues
+    let args = std::env::args().collect::<Vec<_>>();
+    let mut t = if args.iter().any(|a| a == "--bucketed") {
+        Timer::builder().bucketed(Duration::from_millis(1)).build()
+    } else if args.iter().any(|a| a == "--wheel") {
+        Timer::builder()
+            .timer_wheel(Duration::from_millis(1), 256)
+            .build()
+    } else {
+        Timer::new()
+    };
     let val = Arc::new(AtomicU32::default());
-    let at = SystemTime::now() + Duration::from_millis(15);
-    for _ in 0..TARGET {
-        let their_val = Arc::clone(&val);
-        t.schedule_immediately(move || {
-            their_val.fetch_add(1, Ordering::SeqCst);
-        });
+    let start = SystemTime::now();
+    let scheduling = Instant::now();
+    for i in 0..TARGET {
         let their_val = Arc::clone(&val);
+        // Scatter the deadlines over a short horizon, which starts after they're all scheduled
+        let at = start + Duration::from_millis(3000 + u64::from(i) * 7919 % 1000);
         t.schedule_at(at, move || {
             their_val.fetch_add(1, Ordering::SeqCst);
         })
         .detach();
     }
-    while val.load(Ordering::SeqCst) != TARGET * 2 {
-        std::thread::sleep(Duration::from_millis(10));
-    }
+    println!("scheduled {} tasks in {:?}", TARGET, scheduling.elapsed());
+    let elapsed = t.run_n_blocking(TARGET as usize, |_| {
+        let their_val = Arc::clone(&val);
+        Box::new(move || {
+            their_val.fetch_add(1, Ordering::SeqCst);
+        })
+    });
+    println!("ran {} immediate tasks in {:?}", TARGET, elapsed);
+    t.flush();
+    assert_eq!(val.load(Ordering::SeqCst), TARGET * 2);
+    println!("ran everything in {:?}", scheduling.elapsed());
 }
@@ -0,0 +1,420 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use parking_lot::Mutex;
+
+use crate::clock::{Clock, SystemClock};
+use crate::executor::{DefaultBackend, ExecutorBackend, ExecutorRunner};
+use crate::observer::TimerObserver;
+use crate::queue::QueueKind;
+use crate::task::{BoxedTask, PendingTask, TaskGuard};
+use crate::timer::Timer;
+
+/// A user-provided callback stored in the configuration
+pub(crate) struct Hook<T: ?Sized>(pub Arc<T>);
+
+impl<T: ?Sized> Clone for Hook<T> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<T: ?Sized> std::fmt::Debug for Hook<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Hook(<unformattable>)")
+    }
+}
+
+pub(crate) type TaskWrapper = dyn Fn(BoxedTask) -> BoxedTask + Send + Sync;
+
+pub(crate) type MissObserver = dyn Fn(u128, Instant, Instant) + Send + Sync;
+
+pub(crate) type ExecutorHook = dyn Fn() + Send + Sync;
+
+pub(crate) type ExecutionGate = dyn Fn(&PendingTask<'_>) -> bool + Send + Sync;
+
+pub(crate) type Rng = Mutex<Box<dyn FnMut() -> u64 + Send>>;
+
+/// The random number generator used for jitter unless another is given with
+/// [`TimerBuilder::rng`]: xorshift64, seeded from the clock. Jitter only needs to be spread out,
+/// not unpredictable.
+fn default_rng() -> Hook<Rng> {
+    let mut state = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+        | 1;
+    Hook(Arc::new(Mutex::new(Box::new(move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    }))))
+}
+
+// About a year, which is longer than any sensible wall-clock deadline but much shorter than the
+// error from a deadline (or clock) which is accidentally at the epoch
+const DEFAULT_MAX_WALL_CLOCK_OFFSET: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// What to do with tasks which are scheduled for a wall-clock time which has already passed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PastPolicy {
+    /// Run the task as soon as possible
+    #[default]
+    RunImmediately,
+    /// Log and drop the task without running it
+    Skip,
+    /// Refuse to schedule the task; the fallible scheduling methods will return
+    /// [`ScheduleError::InThePast`](crate::ScheduleError::InThePast)
+    Error,
+}
+
+/// What to do with a repeating task which panics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Log the panic and never run the task again
+    #[default]
+    StopOnPanic,
+    /// Log the panic and keep running the task at its usual interval
+    ContinueOnPanic,
+}
+
+/// What the executor should do when the timer is shut down
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ShutdownMode {
+    /// Exit straight away without running any more tasks
+    #[default]
+    Immediate,
+    /// Run every repeating task one final time (whether or not it is due), as long as the total
+    /// time spent doing so stays within the given budget, then exit. This is useful for repeating
+    /// tasks which flush some buffered state. The final runs still go through the execution gate
+    /// and are reported to the observer as usual; one-off tasks are dropped without running.
+    FinalizeRepeating(Duration),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TimerConfig {
+    pub capacity: usize,
+    pub queue_kind: QueueKind,
+    pub executor_threads: usize,
+    pub max_batch_time: Option<Duration>,
+    pub condvar_backoff: Option<Duration>,
+    pub precision_window: Option<Duration>,
+    pub past_policy: PastPolicy,
+    pub task_wrapper: Option<Hook<TaskWrapper>>,
+    pub shutdown_mode: ShutdownMode,
+    pub miss_observer: Option<(Duration, Hook<MissObserver>)>,
+    pub clock: Hook<dyn Clock>,
+    pub fairness_weights: HashMap<String, u64>,
+    pub detach_by_default: bool,
+    pub on_executor_start: Option<Hook<ExecutorHook>>,
+    pub on_executor_stop: Option<Hook<ExecutorHook>>,
+    pub suspend_threshold: Option<Duration>,
+    pub max_wall_clock_offset: Duration,
+    pub execution_gate: Option<Hook<ExecutionGate>>,
+    pub executor_backend: Hook<dyn ExecutorBackend>,
+    pub fifo: bool,
+    pub observer: Option<Hook<dyn TimerObserver>>,
+    pub rng: Hook<Rng>,
+}
+
+impl Default for TimerConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 0,
+            queue_kind: QueueKind::default(),
+            executor_threads: 1,
+            max_batch_time: None,
+            condvar_backoff: None,
+            precision_window: None,
+            past_policy: PastPolicy::default(),
+            task_wrapper: None,
+            shutdown_mode: ShutdownMode::default(),
+            miss_observer: None,
+            clock: Hook(Arc::new(SystemClock)),
+            fairness_weights: HashMap::new(),
+            detach_by_default: false,
+            on_executor_start: None,
+            on_executor_stop: None,
+            suspend_threshold: None,
+            max_wall_clock_offset: DEFAULT_MAX_WALL_CLOCK_OFFSET,
+            execution_gate: None,
+            executor_backend: Hook(Arc::new(DefaultBackend)),
+            fifo: false,
+            observer: None,
+            rng: default_rng(),
+        }
+    }
+}
+
+/// A `TimerBuilder` is used to construct a [`Timer`] with non-default settings.
+#[derive(Debug, Default)]
+pub struct TimerBuilder {
+    config: TimerConfig,
+}
+
+impl TimerBuilder {
+    /// Construct a new builder with the default settings
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reserve underlying capacity for the given number of tasks as a microoptimization
+    pub fn capacity(mut self, cap: usize) -> Self {
+        self.config.capacity = cap;
+        self
+    }
+
+    /// Order pending tasks only coarsely, by grouping their deadlines into buckets of the given
+    /// granularity and running the tasks within a bucket in the order they were scheduled. This
+    /// makes scheduling and running tasks cheaper for very large queues, particularly when many
+    /// tasks become due at once, at the cost of tasks running up to `granularity` later than they
+    /// otherwise would (they never run early). This replaces any earlier call to
+    /// [`timer_wheel`](Self::timer_wheel). By default, tasks are ordered exactly.
+    pub fn bucketed(mut self, granularity: Duration) -> Self {
+        self.config.queue_kind = QueueKind::Bucketed(granularity);
+        self
+    }
+
+    /// Keep pending tasks in a hierarchical timing wheel, in which each level has `slots` slots
+    /// and the lowest level has one slot per `tick`. Scheduling a task is then O(1) no matter how
+    /// many tasks are pending, which suits millions of tasks with short horizons. Like
+    /// [`bucketed`](Self::bucketed), tasks within a tick run in the order they were scheduled,
+    /// so they may run up to `tick` later than they otherwise would (but never early). This
+    /// replaces any earlier call to `bucketed`, and vice versa.
+    ///
+    /// # Panics
+    /// Panics if `slots` is not a power of two, or is less than 2
+    pub fn timer_wheel(mut self, tick: Duration, slots: usize) -> Self {
+        assert!(
+            slots >= 2 && slots.is_power_of_two(),
+            "a timer wheel must have a power of two (and at least two) slots"
+        );
+        self.config.queue_kind = QueueKind::Wheel { tick, slots };
+        self
+    }
+
+    /// Run tasks on the given number of background threads, rather than just one. Tasks may then
+    /// run concurrently with each other, but a single repeating task will never run concurrently
+    /// with itself.
+    ///
+    /// # Panics
+    /// Panics if `threads` is zero
+    pub fn executor_threads(mut self, threads: usize) -> Self {
+        assert!(threads > 0, "must have at least one executor thread");
+        self.config.executor_threads = threads;
+        self
+    }
+
+    /// Use the given loop for running tasks on each executor thread, rather than
+    /// [`DefaultBackend`](crate::DefaultBackend)
+    pub fn executor<B: ExecutorBackend>(mut self, backend: B) -> Self {
+        self.config.executor_backend = Hook(Arc::new(backend));
+        self
+    }
+
+    /// Run due tasks in the order they were scheduled, rather than in order of their deadlines.
+    /// A task still never runs before its deadline, but once several tasks are due, the one which
+    /// was scheduled first runs first, even if another has an earlier deadline. This is useful for
+    /// replaying a queue of work. Repeating tasks keep their place from when they were first
+    /// scheduled.
+    pub fn fifo_mode(mut self) -> Self {
+        self.config.fifo = true;
+        self
+    }
+
+    /// Cap the total time spent executing a single batch of ready tasks. Once a batch has run for
+    /// longer than this, the remaining tasks in it are put back in the queue and the executor
+    /// re-checks for shutdown and newly-arrived tasks before continuing. By default, there is no
+    /// limit.
+    pub fn max_batch_time(mut self, max_batch_time: Duration) -> Self {
+        self.config.max_batch_time = Some(max_batch_time);
+        self
+    }
+
+    /// Under very heavy scheduling churn, the executor may repeatedly find that the queue changed
+    /// between deciding to sleep and actually sleeping, and immediately rescan it. After several
+    /// such rescans in a row, it backs off to let producers make progress; by default it just
+    /// yields its thread, but this makes it sleep for the given duration instead. This trades
+    /// some latency for less CPU use during churn.
+    pub fn condvar_backoff(mut self, backoff: Duration) -> Self {
+        self.config.condvar_backoff = Some(backoff);
+        self
+    }
+
+    /// Wake up more precisely for tasks which are due very soon. Waiting on a condition variable
+    /// tends to overshoot by up to a millisecond or so, depending on the platform, which matters
+    /// for tasks scheduled a few milliseconds out. With this set, the executor stops waiting
+    /// `window` before the next deadline, and then spins (yielding its thread) until the deadline
+    /// itself. This costs up to `window` of CPU time before each wakeup, but nothing while the
+    /// timer is idle; a couple of milliseconds is usually plenty.
+    pub fn precision_window(mut self, window: Duration) -> Self {
+        self.config.precision_window = Some(window);
+        self
+    }
+
+    /// Set what to do with tasks scheduled for a wall-clock time which has already passed. By
+    /// default, they are run immediately.
+    pub fn past_policy(mut self, past_policy: PastPolicy) -> Self {
+        self.config.past_policy = past_policy;
+        self
+    }
+
+    /// Wrap every scheduled task with the given function, which is passed the task (boxed) when
+    /// it is scheduled and should return the task to actually run. This can be used to add
+    /// cross-cutting behavior such as timing, logging, or capturing context from the scheduling
+    /// thread, without changing every call site. For repeating tasks, the wrapper is applied
+    /// around each run.
+    pub fn task_wrapper<F: Fn(BoxedTask) -> BoxedTask + Send + Sync + 'static>(
+        mut self,
+        f: F,
+    ) -> Self {
+        self.config.task_wrapper = Some(Hook(Arc::new(f)));
+        self
+    }
+
+    /// Set what the executor should do when the timer is shut down. By default, it exits straight
+    /// away.
+    pub fn shutdown_mode(mut self, shutdown_mode: ShutdownMode) -> Self {
+        self.config.shutdown_mode = shutdown_mode;
+        self
+    }
+
+    /// Report this timer's activity to the given observer, rather than to the global observer
+    /// (see [`set_global_observer`](crate::set_global_observer))
+    pub fn observer<O: TimerObserver>(mut self, observer: O) -> Self {
+        self.config.observer = Some(Hook(Arc::new(observer)));
+        self
+    }
+
+    /// Draw the random numbers used for jitter (see
+    /// [`Timer::schedule_in_jittered`](crate::Timer::schedule_in_jittered)) from `rng`, rather
+    /// than the built-in generator. This allows using a preferred source of randomness, such as
+    /// the `rand` crate, or a seeded deterministic one in tests.
+    pub fn rng(mut self, rng: Box<dyn FnMut() -> u64 + Send>) -> Self {
+        self.config.rng = Hook(Arc::new(Mutex::new(rng)));
+        self
+    }
+
+    /// Register a callback to be invoked whenever a repeating task starts running more than
+    /// `tolerance` after it was supposed to, which indicates that the executor is overloaded. The
+    /// callback is invoked on the executor thread with the task ID, the instant at which the task
+    /// should have run, and the instant at which it actually ran.
+    pub fn on_miss<F: Fn(u128, Instant, Instant) + Send + Sync + 'static>(
+        mut self,
+        tolerance: Duration,
+        f: F,
+    ) -> Self {
+        self.config.miss_observer = Some((tolerance, Hook(Arc::new(f))));
+        self
+    }
+
+    /// Register a callback to be invoked on the executor thread just before each task runs, which
+    /// can veto running it by returning `false`. A vetoed one-off task is discarded, while a
+    /// vetoed repeating task is skipped this time and rescheduled as usual (a task scheduled with
+    /// [`Timer::schedule_poll`] is tried again after [`Timer::DEFAULT_LOOP_TIME`]). This can be
+    /// used to disable categories of tasks at runtime, for example with a feature flag, without
+    /// cancelling them.
+    pub fn execution_gate<F: Fn(&PendingTask<'_>) -> bool + Send + Sync + 'static>(
+        mut self,
+        f: F,
+    ) -> Self {
+        self.config.execution_gate = Some(Hook(Arc::new(f)));
+        self
+    }
+
+    /// Register a callback to be invoked on the executor thread when it starts, before it runs
+    /// any tasks. This can be used to set up thread-local resources. With multiple executor
+    /// threads (see [`executor_threads`](Self::executor_threads)), it is invoked on each of them.
+    pub fn on_executor_start<F: Fn() + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.config.on_executor_start = Some(Hook(Arc::new(f)));
+        self
+    }
+
+    /// Register a callback to be invoked on the executor thread when it stops, after it has run
+    /// its last task (including any run because of the [`ShutdownMode`]). This can be used to
+    /// tear down thread-local resources. With multiple executor threads, it is invoked on each of
+    /// them.
+    pub fn on_executor_stop<F: Fn() + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.config.on_executor_stop = Some(Hook(Arc::new(f)));
+        self
+    }
+
+    /// Treat the executor waking up more than `threshold` later than it meant to as a sign that
+    /// the system was suspended (for example, a laptop going to sleep). When that happens, every
+    /// repeating task which was due in the meantime is pushed back to one interval from now and a
+    /// warning is logged, rather than all of them running at once to catch up. The threshold
+    /// should be comfortably longer than any scheduling hiccup. By default, suspends are not
+    /// detected.
+    pub fn suspend_threshold(mut self, threshold: Duration) -> Self {
+        self.config.suspend_threshold = Some(threshold);
+        self
+    }
+
+    /// Set how far from the current time a wall-clock time passed to
+    /// [`Timer::schedule_at_checked`] may be, in either direction, before it is rejected as
+    /// implausible. By default, this is 365 days.
+    pub fn max_wall_clock_offset(mut self, offset: Duration) -> Self {
+        self.config.max_wall_clock_offset = offset;
+        self
+    }
+
+    /// Use the given source of time when scheduling tasks, rather than the real system time
+    pub fn clock<C: Clock>(mut self, clock: C) -> Self {
+        self.config.clock = Hook(Arc::new(clock));
+        self
+    }
+
+    /// Give the fairness key `key` the given weight (by default, every key has a weight of 1).
+    /// When tasks scheduled with [`Timer::schedule_fair`] share a deadline, a key with weight `n`
+    /// gets `n` tasks run for every one task of a key with weight 1.
+    ///
+    /// # Panics
+    /// Panics if `weight` is zero
+    pub fn fairness_weight<K: Into<String>>(mut self, key: K, weight: u64) -> Self {
+        assert!(weight > 0, "fairness weights must be positive");
+        self.config.fairness_weights.insert(key.into(), weight);
+        self
+    }
+
+    /// If set, dropping the [`TaskGuard`](crate::TaskGuard) returned when scheduling a task does
+    /// not cancel the task, unless [`TaskGuard::arm`](crate::TaskGuard::arm) has been called on
+    /// it. This suits codebases which rarely cancel tasks, where accidentally dropping a guard
+    /// would otherwise silently cancel a task. Tasks can still be cancelled with
+    /// [`Timer::cancel`].
+    pub fn detach_by_default(mut self, detach_by_default: bool) -> Self {
+        self.config.detach_by_default = detach_by_default;
+        self
+    }
+
+    /// Construct the `Timer`. This will immediately start a background thread for executing tasks,
+    /// which will be shut down on drop.
+    pub fn build(self) -> Timer {
+        Timer::from_config(self.config)
+    }
+
+    /// Construct the `Timer` with the given tasks already scheduled, each to run once after its
+    /// duration, before the background thread is started. All of the durations are measured from
+    /// the same instant, and the executor sees every task from its very first look at the queue,
+    /// so there is no gap between construction and scheduling for them to race against. Returns
+    /// a guard for each task, in the same order.
+    pub fn build_with_initial_tasks(
+        self,
+        tasks: Vec<(Duration, BoxedTask)>,
+    ) -> (Timer, Vec<TaskGuard>) {
+        Timer::from_config_with_tasks(self.config, tasks)
+    }
+
+    /// Construct the `Timer` without starting a background thread; see
+    /// [`Timer::new_unstarted`].
+    pub fn build_unstarted(self) -> (Timer, ExecutorRunner) {
+        Timer::from_config_unstarted(self.config)
+    }
+
+    /// Construct the `Timer` with its executor running as a task on the given Tokio runtime; see
+    /// [`Timer::spawn_on`].
+    #[cfg(feature = "async")]
+    pub fn build_on(self, handle: &tokio::runtime::Handle) -> Timer {
+        Timer::from_config_on(self.config, handle)
+    }
+}
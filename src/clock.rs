@@ -0,0 +1,168 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use parking_lot::Mutex;
+
+/// A source of time. The wall-clock time is used to turn the [`SystemTime`]s passed to
+/// [`Timer::schedule_at`](crate::Timer::schedule_at) into deadlines, and the monotonic time is
+/// used for all other deadlines. This can be replaced with
+/// [`TimerBuilder::clock`](crate::TimerBuilder::clock) to make scheduling testable.
+pub trait Clock: Send + Sync + 'static {
+    /// The current wall-clock time
+    fn system_now(&self) -> SystemTime;
+
+    /// The current monotonic time. By default, this is [`Instant::now`].
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn system_now(&self) -> SystemTime {
+        (**self).system_now()
+    }
+
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
+
+/// The default [`Clock`], which reads the real system time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A [`Clock`] whose wall-clock time is only changed explicitly, for use in tests. Its monotonic
+/// time follows the real monotonic time, except that it can be made to jump forward with
+/// [`suspend`](Self::suspend). Wrap it in an [`Arc`] to keep a handle on it after passing it to
+/// the builder.
+#[cfg(feature = "testing")]
+#[derive(Debug)]
+pub struct TestClock {
+    now: parking_lot::Mutex<SystemTime>,
+    /// How far the monotonic time has been moved ahead of the real monotonic time
+    skipped: parking_lot::Mutex<std::time::Duration>,
+}
+
+#[cfg(feature = "testing")]
+impl TestClock {
+    /// Construct a new clock which reads `start` until it is changed
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            now: parking_lot::Mutex::new(start),
+            skipped: parking_lot::Mutex::new(std::time::Duration::ZERO),
+        }
+    }
+
+    /// Set the current wall-clock time, which may be earlier than before
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock() = now;
+    }
+
+    /// Move the current wall-clock time forward
+    pub fn advance(&self, by: std::time::Duration) {
+        *self.now.lock() += by;
+    }
+
+    /// Simulate the machine being suspended for the given duration, by moving both the
+    /// wall-clock and the monotonic time forward
+    pub fn suspend(&self, by: std::time::Duration) {
+        *self.now.lock() += by;
+        *self.skipped.lock() += by;
+    }
+}
+
+#[cfg(feature = "testing")]
+impl Clock for TestClock {
+    fn system_now(&self) -> SystemTime {
+        *self.now.lock()
+    }
+
+    fn now(&self) -> Instant {
+        Instant::now() + *self.skipped.lock()
+    }
+}
+
+/// Wraps a timer's [`Clock`] so that its monotonic time stands still while the timer is paused,
+/// and afterwards lags behind by however long the timer spent paused. Since every deadline is
+/// measured against this clock, pausing a timer shifts all of them (including those of tasks
+/// scheduled during the pause) by the length of the pause. The wall-clock time is unaffected.
+pub(crate) struct PausableClock {
+    inner: Arc<dyn Clock>,
+    /// Set the first time the timer is paused, so that a timer which is never paused doesn't
+    /// have to take the lock to read the time
+    ever_paused: AtomicBool,
+    state: Mutex<PauseState>,
+}
+
+#[derive(Debug, Default)]
+struct PauseState {
+    /// When the current pause started, according to the inner clock
+    since: Option<Instant>,
+    /// The total length of all finished pauses
+    total: Duration,
+}
+
+impl PausableClock {
+    pub(crate) fn new(inner: Arc<dyn Clock>) -> Self {
+        Self {
+            inner,
+            ever_paused: AtomicBool::new(false),
+            state: Mutex::new(PauseState::default()),
+        }
+    }
+
+    /// Stop the clock; returns `false` if it was already stopped
+    pub(crate) fn pause(&self) -> bool {
+        let mut state = self.state.lock();
+        if state.since.is_some() {
+            return false;
+        }
+        state.since = Some(self.inner.now());
+        self.ever_paused.store(true, Ordering::Release);
+        true
+    }
+
+    /// Start the clock again; returns `false` if it wasn't stopped
+    pub(crate) fn resume(&self) -> bool {
+        let mut state = self.state.lock();
+        let Some(since) = state.since.take() else {
+            return false;
+        };
+        state.total += self.inner.now().saturating_duration_since(since);
+        true
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        self.state.lock().since.is_some()
+    }
+}
+
+impl std::fmt::Debug for PausableClock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PausableClock")
+            .field("state", &*self.state.lock())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Clock for PausableClock {
+    fn system_now(&self) -> SystemTime {
+        self.inner.system_now()
+    }
+
+    fn now(&self) -> Instant {
+        if !self.ever_paused.load(Ordering::Acquire) {
+            return self.inner.now();
+        }
+        let state = self.state.lock();
+        let now = state.since.unwrap_or_else(|| self.inner.now());
+        now.checked_sub(state.total).unwrap_or(now)
+    }
+}
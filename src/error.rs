@@ -0,0 +1,140 @@
+use std::time::Duration;
+
+/// Errors which can be returned when scheduling a task
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ScheduleError {
+    /// The timer is no longer accepting new tasks (see [`Timer::stop_accepting`](crate::Timer::stop_accepting))
+    NotAccepting,
+    /// The timer is shutting down (see [`Timer::begin_shutdown`](crate::Timer::begin_shutdown)),
+    /// so the task would never run
+    ShuttingDown,
+    /// The task was scheduled for a time which had already passed by the given duration, and the
+    /// timer is configured with [`PastPolicy::Error`](crate::PastPolicy::Error)
+    InThePast(Duration),
+    /// The given task ID is already in use by a pending task
+    IdInUse(u128),
+    /// The task this was relative to is not pending or running
+    NoSuchTask,
+    /// The key already has the given maximum number of pending tasks
+    LimitExceeded(usize),
+}
+
+impl std::fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotAccepting => write!(f, "timer is not accepting new tasks"),
+            Self::ShuttingDown => write!(f, "timer is shutting down"),
+            Self::InThePast(d) => write!(f, "scheduled time is {:?} in the past", d),
+            Self::IdInUse(id) => write!(f, "task ID {} is already in use", id),
+            Self::NoSuchTask => write!(f, "no such task"),
+            Self::LimitExceeded(limit) => {
+                write!(f, "key already has the maximum of {} pending tasks", limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ScheduleError {}
+
+/// Errors which can be returned by [`Timer::schedule_at_checked`](crate::Timer::schedule_at_checked)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ClockError {
+    /// The scheduled time is implausibly far in the future (by the given duration), which
+    /// suggests that either it or the system clock is wrong
+    TooFarInFuture(Duration),
+    /// The scheduled time is implausibly far in the past (by the given duration), which suggests
+    /// that either it or the system clock is wrong
+    TooFarInPast(Duration),
+    /// The task could not be scheduled for some other reason
+    Schedule(ScheduleError),
+}
+
+impl std::fmt::Display for ClockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooFarInFuture(d) => {
+                write!(
+                    f,
+                    "scheduled time is implausibly far ({:?}) in the future",
+                    d
+                )
+            }
+            Self::TooFarInPast(d) => {
+                write!(f, "scheduled time is implausibly far ({:?}) in the past", d)
+            }
+            Self::Schedule(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ClockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Schedule(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ScheduleError> for ClockError {
+    fn from(e: ScheduleError) -> Self {
+        Self::Schedule(e)
+    }
+}
+
+/// Errors which can be returned when waiting for the result of a task
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TaskError {
+    /// The task was cancelled (or the timer shut down) before it ran
+    Cancelled,
+    /// The task panicked
+    Panicked,
+}
+
+impl std::fmt::Display for TaskError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cancelled => write!(f, "task was cancelled"),
+            Self::Panicked => write!(f, "task panicked"),
+        }
+    }
+}
+
+impl std::error::Error for TaskError {}
+
+/// A violated internal invariant, as found by
+/// [`Timer::verify_invariants`](crate::Timer::verify_invariants)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum InvariantViolation {
+    /// The queue is not correctly ordered by deadline
+    HeapOrder,
+    /// More than one pending or running task has the given ID
+    DuplicateId(u128),
+    /// A pending task has the reserved ID zero
+    ZeroId,
+    /// Followers are waiting to be attached to the given task, which is not running
+    OrphanedFollowers(u128),
+}
+
+impl std::fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::HeapOrder => write!(f, "task queue is not ordered by deadline"),
+            Self::DuplicateId(id) => write!(f, "task ID {} is used more than once", id),
+            Self::ZeroId => write!(f, "a task has the reserved ID 0"),
+            Self::OrphanedFollowers(id) => {
+                write!(
+                    f,
+                    "followers are pending for task {}, which is not running",
+                    id
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for InvariantViolation {}
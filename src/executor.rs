@@ -1,62 +1,276 @@
+use std::sync::atomic::{fence, AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use parking_lot::{Condvar, Mutex};
+use parking_lot::{Condvar, Mutex, MutexGuard};
 use smallvec::SmallVec;
 
-use crate::task::{Ready, Task};
-use crate::timer::TimerShared;
+use crate::builder::{ShutdownMode, TimerConfig};
+use crate::instrument;
+use crate::observer::{self, TaskOutcome, TimerObserver};
+use crate::task::{PendingTask, Ready, Task};
+use crate::timer::{CompletionCallback, TimerShared};
 
 // This value is the worst-case for how "late" an item can be in case we happen to miss the condvar
 // notification and it's added while we're executing another item.
-const DEFAULT_LOOP_TIME: Duration = Duration::from_millis(500);
+pub(crate) const DEFAULT_LOOP_TIME: Duration = Duration::from_millis(500);
 
 // In testing, there's a big (50%) speedup going from 1 to 4 items per loop (from locking
 // amortization), but basically no performance difference between 4 and 16, then a gradual falloff.
 // 8 seems to be a nice spot in the middle. This might be best off tuned based on system stuff, but
 // _shrug_
-const MAX_PER_LOOP: usize = 8;
+pub(crate) const MAX_PER_LOOP: usize = 8;
 
+// How many times in a row we'll immediately rescan because the queue changed under us before
+// backing off to let the producers get on with it
+const MAX_RESCANS_BEFORE_BACKOFF: u32 = 8;
+
+/// Record that a task ran without panicking, taking `duration`, in the metrics and with the
+/// observer. Also used by the timer for tasks run outside the executor.
+pub(crate) fn record_executed(
+    observer: Option<&dyn TimerObserver>,
+    task_id: u128,
+    duration: Duration,
+) {
+    instrument::task_executed(duration);
+    if let Some(observer) = observer {
+        observer.task_executed(task_id, duration);
+        observer.task_outcome(task_id, TaskOutcome::Completed);
+    }
+}
+
+/// Record that a task panicked, in the metrics and with the observer
+pub(crate) fn record_panicked(observer: Option<&dyn TimerObserver>, task_id: u128) {
+    instrument::task_panicked();
+    if let Some(observer) = observer {
+        observer.task_panicked(task_id);
+        observer.task_outcome(task_id, TaskOutcome::Panicked);
+    }
+}
+
+/// Drop tasks which were discarded because they had been cancelled, reporting each to the
+/// observer. Dropping a task can run user code, so the lock must not be held.
+pub(crate) fn discard_cancelled(observer: Option<&dyn TimerObserver>, cancelled: Vec<Task>) {
+    for task in cancelled {
+        if let Some(observer) = observer {
+            observer.task_outcome(task.id(), TaskOutcome::CancelledByGuard);
+        }
+        drop(task);
+    }
+}
+
+// The batch is moved straight out of this enum and it only ever lives on the stack for one loop,
+// so boxing it would just add an allocation per loop.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
-enum NextAction {
+pub(crate) enum NextAction {
     ExecuteSome(SmallVec<[Task; MAX_PER_LOOP]>),
     SleepAtLeast(Duration, u64),
     Exit,
+    /// Exit this executor only, leaving the timer running
+    Retire,
+}
+
+/// Why the executor last woke up from waiting for tasks to become due, as returned by
+/// [`Timer::last_wake_reason`](crate::Timer::last_wake_reason)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WakeReason {
+    /// It was notified that the queue changed
+    Notified,
+    /// It slept until the next task was due (or for the default loop time)
+    Timeout,
+    /// The timer was shut down
+    Shutdown,
+    /// The queue changed between the executor deciding to sleep and actually sleeping, so it
+    /// rescanned the queue without sleeping at all
+    Rescan,
+}
+
+pub(crate) type BatchIds = SmallVec<[u128; MAX_PER_LOOP]>;
+
+/// What became of a batch, which the executor settles up with its `last_batch`
+pub(crate) struct BatchResult {
+    /// Tasks which were put back unrun because the batch exceeded `max_batch_time`. They are back
+    /// in the queue rather than in flight, so their completion callbacks mustn't fire yet.
+    unexecuted: BatchIds,
+    /// Followers of the tasks which were run or discarded, which finished a run (or for good)
+    /// along with them
+    followers: BatchIds,
+}
+
+impl BatchResult {
+    pub(crate) fn settle(self, last_batch: &mut BatchIds) {
+        last_batch.retain(|id| !self.unexecuted.contains(id));
+        last_batch.extend(self.followers);
+    }
+}
+
+/// How many batches of each size have been run, indexed by the number of tasks in the batch
+pub(crate) type BatchSizes = [AtomicU64; MAX_PER_LOOP + 1];
+
+/// The ID of the task one executor is running right now, or zero if it is idle. This is only
+/// written by its own executor. An ID doesn't fit in one atomic, so this is a small seqlock: the
+/// sequence number is odd while the two halves are being written.
+#[derive(Debug, Default)]
+pub(crate) struct TaskSlot {
+    seq: AtomicU64,
+    high: AtomicU64,
+    low: AtomicU64,
 }
 
+impl TaskSlot {
+    fn set(&self, task_id: u128) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.high.store((task_id >> 64) as u64, Ordering::Relaxed);
+        self.low.store(task_id as u64, Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+
+    pub fn get(&self) -> Option<u128> {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let high = self.high.load(Ordering::Relaxed);
+            let low = self.low.load(Ordering::Relaxed);
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                let task_id = (u128::from(high) << 64) | u128::from(low);
+                return (task_id != 0).then_some(task_id);
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
 pub(crate) struct Executor {
     changed: Arc<Condvar>,
+    /// Notified whenever tasks stop being in flight
+    completed: Arc<Condvar>,
     shared: Arc<Mutex<TimerShared>>,
+    config: TimerConfig,
+    /// Set to make just this executor exit after its current batch
+    retired: Arc<AtomicBool>,
+    batch_sizes: Arc<BatchSizes>,
+    /// This executor's entry in [`TimerShared::current_tasks`]
+    current_task: Arc<TaskSlot>,
 }
 
 impl Executor {
-    pub fn new(shared: Arc<Mutex<TimerShared>>, changed: Arc<Condvar>) -> Self {
-        Self { changed, shared }
+    pub fn new(
+        shared: Arc<Mutex<TimerShared>>,
+        changed: Arc<Condvar>,
+        completed: Arc<Condvar>,
+        config: TimerConfig,
+    ) -> Self {
+        let current_task = Arc::new(TaskSlot::default());
+        let batch_sizes = {
+            let mut shared = shared.lock();
+            shared.current_tasks.push(Arc::clone(&current_task));
+            Arc::clone(&shared.batch_sizes)
+        };
+        Self {
+            changed,
+            completed,
+            shared,
+            config,
+            retired: Arc::default(),
+            batch_sizes,
+            current_task,
+        }
     }
 
-    fn get_next_action(&self) -> NextAction {
+    /// Decide what to do next. `last_batch` holds the IDs of the tasks this executor took in its
+    /// previous batch, which are no longer in flight; any completion callbacks for them are moved
+    /// into `completions`, to be invoked once the lock has been released.
+    pub(crate) fn get_next_action(
+        &self,
+        last_batch: &mut BatchIds,
+        completions: &mut Vec<CompletionCallback>,
+    ) -> NextAction {
         let mut shared = self.shared.lock();
+        // Anything that's still waiting to be attached to a task from the last batch is for a task
+        // which didn't get rescheduled, so will never run
+        let TimerShared {
+            in_flight,
+            in_flight_repeating,
+            pending_followers,
+            completion_callbacks,
+            cancel_on_return,
+            flushing,
+            finished,
+            ..
+        } = &mut *shared;
+        if *flushing > 0 {
+            finished.extend_from_slice(last_batch);
+        }
+        if !last_batch.is_empty() {
+            for task_id in last_batch.drain(..) {
+                if !pending_followers.is_empty() {
+                    pending_followers.remove(&task_id);
+                }
+                if !cancel_on_return.is_empty() {
+                    cancel_on_return.remove(&task_id);
+                }
+                if !completion_callbacks.is_empty() {
+                    if let Some(callbacks) = completion_callbacks.remove(&task_id) {
+                        completions.extend(callbacks);
+                    }
+                }
+                if let Some(pos) = in_flight.iter().position(|&id| id == task_id) {
+                    in_flight.swap_remove(pos);
+                }
+                if !in_flight_repeating.is_empty() {
+                    in_flight_repeating.remove(&task_id);
+                }
+            }
+            self.completed.notify_all();
+        }
         if shared.done {
             return NextAction::Exit;
         }
-        let next_id = shared.next_id;
-        let mut ready = SmallVec::new();
-        let now = Instant::now();
+        if self.retired.load(Ordering::Relaxed) {
+            return NextAction::Retire;
+        }
+        let epoch = shared.epoch;
+        if shared.paused {
+            // Resuming bumps the epoch and wakes us up
+            return NextAction::SleepAtLeast(DEFAULT_LOOP_TIME, epoch);
+        }
+        let mut ready = SmallVec::<[Task; MAX_PER_LOOP]>::new();
+        let now = self.now();
+        let cancelled = shared.release_deferred(now);
+        if !cancelled.is_empty() {
+            self.completed.notify_all();
+        }
+        shared.tasks.advance(now);
         loop {
             if ready.len() == MAX_PER_LOOP {
                 break;
             }
-            match shared.tasks.peek().map(|t| t.ready(now)) {
+            match shared.tasks.peek_at(now).map(|t| t.ready(now)) {
                 Some(Ready::Now) => {
                     // There's no condition where this isn't Some(task) since we just peeked it,
                     // but BinaryHeap has no operation to avoid this Option
-                    if let Some(task) = shared.tasks.pop() {
-                        ready.push(task)
+                    if let Some(mut task) = shared.tasks.pop_at(now) {
+                        match task.held_for() {
+                            Some(d) => {
+                                // Due by its deadline, but not allowed to run yet
+                                task.set_next_execution(now + d);
+                                shared.tasks.push(task);
+                            }
+                            None => ready.push(task),
+                        }
                     }
                 }
                 Some(Ready::In(d)) => {
                     if ready.is_empty() {
-                        return NextAction::SleepAtLeast(d, next_id);
+                        return NextAction::SleepAtLeast(d, epoch);
                     } else {
                         break;
                     }
@@ -64,66 +278,625 @@ impl Executor {
                 None => break,
             }
         }
+        let duplicates = Self::coalesce_duplicates(&mut ready);
+        if !cancelled.is_empty() || !duplicates.is_empty() {
+            // Dropping a task can run user code which calls back into the timer, so leave it
+            // until the lock is released, along with the completion callbacks
+            let observer = self.config.observer.clone();
+            completions.push(Box::new(move || {
+                discard_cancelled(observer::resolve(observer.as_ref()), cancelled);
+                drop(duplicates);
+            }));
+        }
+        for task in &ready {
+            shared.in_flight.push(task.id());
+            if task.is_repeating() {
+                shared.in_flight_repeating.insert(task.id());
+            }
+            last_batch.push(task.id());
+        }
+        let len = shared.tasks.len();
+        if let Some(watermark) = shared.watermark.as_mut() {
+            watermark.check_low(len);
+        }
+        instrument::queue_depth(len);
         if ready.is_empty() {
-            NextAction::SleepAtLeast(DEFAULT_LOOP_TIME, next_id)
+            NextAction::SleepAtLeast(DEFAULT_LOOP_TIME, epoch)
         } else {
             NextAction::ExecuteSome(ready)
         }
     }
 
-    pub fn run_until_done(self) {
+    /// Discard all but the first of any entries in `ready` for the same repeating task, so that
+    /// it can never run twice in one batch. Well-behaved code never creates such duplicates (see
+    /// [`InvariantViolation::DuplicateId`](crate::InvariantViolation::DuplicateId)), but if they
+    /// do turn up, running the same periodic task several times back-to-back would be worse.
+    /// The duplicates are returned rather than dropped, since the lock is held.
+    fn coalesce_duplicates(ready: &mut SmallVec<[Task; MAX_PER_LOOP]>) -> Vec<Task> {
+        let mut duplicates = Vec::new();
+        if ready.len() < 2 {
+            return duplicates;
+        }
+        let mut seen = BatchIds::new();
+        let mut i = 0;
+        while i < ready.len() {
+            let task = &ready[i];
+            if task.is_repeating() && seen.contains(&task.id()) {
+                log::warn!(
+                    "discarding duplicate entry for repeating task {}",
+                    task.id()
+                );
+                duplicates.push(ready.remove(i));
+            } else {
+                if task.is_repeating() {
+                    seen.push(task.id());
+                }
+                i += 1;
+            }
+        }
+        duplicates
+    }
+
+    fn observer(&self) -> Option<&dyn TimerObserver> {
+        observer::resolve(self.config.observer.as_ref())
+    }
+
+    fn report(&self, task_id: u128, outcome: TaskOutcome) {
+        if let Some(observer) = self.observer() {
+            observer.task_outcome(task_id, outcome);
+        }
+    }
+
+    /// Run a batch of tasks. The caller must settle the result with its `last_batch` before the
+    /// completion callbacks for it are fired.
+    pub(crate) fn execute_batch(&self, items: SmallVec<[Task; MAX_PER_LOOP]>) -> BatchResult {
+        debug_assert!(
+            items
+                .iter()
+                .enumerate()
+                .all(|(i, task)| !task.is_repeating()
+                    || items[i + 1..].iter().all(|other| other.id() != task.id())),
+            "repeating task appears twice in one batch"
+        );
+        self.batch_sizes[items.len()].fetch_add(1, Ordering::Relaxed);
+        // Execute those items serially. This will not hold the lock
+        let started = Instant::now();
+        let mut remainders = SmallVec::<[Task; MAX_PER_LOOP]>::new();
+        let mut timings = SmallVec::<[(u128, Duration); MAX_PER_LOOP]>::new();
+        let mut finished = SmallVec::<[u128; MAX_PER_LOOP]>::new();
+        let mut vetoed = SmallVec::<[u128; MAX_PER_LOOP]>::new();
+        let mut followers = BatchIds::new();
+        // The clock is only read, so a panicking task can't leave it inconsistent
+        let clock = std::panic::AssertUnwindSafe(&*self.config.clock.0);
+        let mut items = items.into_iter();
+        for item in items.by_ref() {
+            let task_id = item.id();
+            followers.extend(item.follower_ids());
+            let repeating = item.is_repeating();
+            if item.dropped() {
+                log::debug!("encountered dropped task {}", task_id);
+                self.report(task_id, TaskOutcome::CancelledByGuard);
+                if repeating {
+                    finished.push(task_id);
+                }
+            } else if item.expired(self.now()) {
+                log::debug!("task {} expired before it could run", task_id);
+                if let Some(observer) = self.observer() {
+                    observer.task_expired(task_id);
+                }
+                self.report(task_id, TaskOutcome::Expired);
+            } else if !self.allowed(&item) {
+                log::debug!("execution gate skipped task {}", task_id);
+                self.report(task_id, TaskOutcome::GateVetoed);
+                match item.skip(self.now(), DEFAULT_LOOP_TIME) {
+                    Some(remainder) => {
+                        vetoed.push(task_id);
+                        remainders.push(remainder)
+                    }
+                    None if repeating => finished.push(task_id),
+                    None => {}
+                }
+            } else {
+                let run_started = Instant::now();
+                if repeating {
+                    self.check_missed(&item, self.now());
+                }
+                self.current_task.set(task_id);
+                let result = std::panic::catch_unwind(|| item.run(*clock));
+                self.current_task.set(0);
+                match result {
+                    Ok(Some(remainder)) => {
+                        let duration = run_started.elapsed();
+                        record_executed(self.observer(), task_id, duration);
+                        timings.push((task_id, duration));
+                        remainders.push(remainder)
+                    }
+                    Ok(None) => {
+                        record_executed(self.observer(), task_id, run_started.elapsed());
+                        if repeating {
+                            finished.push(task_id);
+                        }
+                    }
+                    Err(e) => {
+                        log::error!("uncaught panic when running task: {:?}", e);
+                        record_panicked(self.observer(), task_id);
+                        if repeating {
+                            finished.push(task_id);
+                        }
+                    }
+                }
+            }
+            if let Some(max) = self.config.max_batch_time {
+                if started.elapsed() > max {
+                    break;
+                }
+            }
+        }
+        // If we ran out of time, put the unexecuted items back so that we can re-check for
+        // anything more urgent before getting to them
+        let unexecuted = items.as_slice().iter().map(Task::id).collect::<BatchIds>();
+        if !unexecuted.is_empty() {
+            log::debug!(
+                "batch exceeded max_batch_time; deferring {} tasks",
+                unexecuted.len()
+            );
+        }
+        remainders.extend(items);
+        // Reinsert any periodic timers to the list in one big chunk
+        if !remainders.is_empty() || !finished.is_empty() {
+            let mut s = self.shared.lock();
+            for (task_id, duration) in timings {
+                s.stats.entry(task_id).or_default().record(duration);
+            }
+            for task_id in finished {
+                s.stats.remove(&task_id);
+                s.gated.remove(&task_id);
+            }
+            for task_id in &unexecuted {
+                if let Some(pos) = s.in_flight.iter().position(|id| id == task_id) {
+                    s.in_flight.swap_remove(pos);
+                }
+                s.in_flight_repeating.remove(task_id);
+            }
+            for mut item in remainders {
+                if vetoed.contains(&item.id()) {
+                    s.gated.insert(item.id());
+                } else if !s.gated.is_empty() {
+                    s.gated.remove(&item.id());
+                }
+                if let Some(followers) = s.pending_followers.remove(&item.id()) {
+                    item.add_followers(followers);
+                }
+                if item.is_repeating() && s.cancel_on_return.remove(&item.id()) {
+                    item.cancel();
+                }
+                s.tasks.push(item);
+            }
+            instrument::queue_depth(s.tasks.len());
+        }
+        BatchResult {
+            unexecuted,
+            followers,
+        }
+    }
+
+    pub(crate) fn run_completions(completions: &mut Vec<CompletionCallback>) {
+        for callback in completions.drain(..) {
+            if let Err(e) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(callback)) {
+                log::error!("uncaught panic in completion callback: {:?}", e);
+            }
+        }
+    }
+
+    /// Check the execution gate, if any, for whether the task may run
+    fn allowed(&self, item: &Task) -> bool {
+        self.config
+            .execution_gate
+            .as_ref()
+            .is_none_or(|gate| (gate.0)(&PendingTask::new(item)))
+    }
+
+    fn check_missed(&self, item: &Task, now: Instant) {
+        if let Some((tolerance, observer)) = &self.config.miss_observer {
+            let expected = item.next_execution();
+            if now.saturating_duration_since(expected) > *tolerance {
+                (observer.0)(item.id(), expected, now);
+            }
+        }
+    }
+
+    fn now(&self) -> Instant {
+        self.config.clock.0.now()
+    }
+
+    /// Having woken up from waiting until `expected` at the latest, check whether we woke so
+    /// much later that the system must have been suspended in the meantime. If so, skip the runs
+    /// of repeating tasks which were missed, rather than running them all at once.
+    fn check_suspended(&self, shared: &mut TimerShared, expected: Instant) {
+        let Some(threshold) = self.config.suspend_threshold else {
+            return;
+        };
+        let now = self.now();
+        let overslept = now.saturating_duration_since(expected);
+        if overslept > threshold {
+            let skipped = shared.skip_missed_repeating(now);
+            log::warn!(
+                "executor woke {:?} late, so assuming the system was suspended; skipped missed runs of {} repeating tasks",
+                overslept,
+                skipped
+            );
+        }
+    }
+
+    fn record_wake(shared: &mut TimerShared, reason: WakeReason) {
+        log::trace!("executor woke: {:?}", reason);
+        shared.last_wake = Some(reason);
+    }
+
+    /// Wait for the next item to be ready, or for the queue to change. This will only briefly
+    /// hold the lock to check for shutdown.
+    pub(crate) fn wait(&self, d: Duration, seen_epoch: u64) -> WakeReason {
+        let mut shared = self.shared.lock();
+        let reason = if shared.done {
+            WakeReason::Shutdown
+        } else if shared.epoch != seen_epoch {
+            // This means someone changed the structure between when we read it at the top
+            // and here, so let's rescan
+            shared.rescans += 1;
+            WakeReason::Rescan
+        } else if self
+            .config
+            .precision_window
+            .is_some_and(|window| d <= window)
+        {
+            // Close enough to spin, without holding the lock
+            let deadline = Instant::now() + d;
+            MutexGuard::unlocked(&mut shared, || {
+                while Instant::now() < deadline {
+                    std::thread::yield_now();
+                }
+            });
+            if shared.done {
+                WakeReason::Shutdown
+            } else {
+                WakeReason::Timeout
+            }
+        } else {
+            // Wake up early enough to spin for the rest of the wait
+            let d = d.saturating_sub(self.config.precision_window.unwrap_or_default());
+            let expected = self.now() + d;
+            let deadline = Instant::now() + d;
+            // Only a change to the queue, shutdown, or retirement is worth waking for; on any
+            // other wakeup, go straight back to waiting rather than rescanning the whole queue
+            let woken = |shared: &TimerShared| {
+                shared.done || shared.epoch != seen_epoch || self.retired.load(Ordering::Relaxed)
+            };
+            let mut timed_out = false;
+            while !woken(&shared) {
+                if self.changed.wait_until(&mut shared, deadline).timed_out() {
+                    timed_out = true;
+                    break;
+                }
+                if !woken(&shared) {
+                    shared.spurious_wakeups += 1;
+                    instrument::spurious_wakeup();
+                }
+            }
+            self.check_suspended(&mut shared, expected);
+            if shared.done {
+                WakeReason::Shutdown
+            } else if timed_out {
+                WakeReason::Timeout
+            } else {
+                WakeReason::Notified
+            }
+        };
+        Self::record_wake(&mut shared, reason);
+        reason
+    }
+
+    /// Run tasks as they come due until the timer is shut down or this executor is retired. This
+    /// is the loop used by [`DefaultBackend`].
+    fn run_loop(&self) {
+        let mut consecutive_rescans = 0;
+        let mut last_batch = BatchIds::new();
+        let mut completions = Vec::new();
         loop {
             // Grab some items (this will briefly hold the lock while it's grabbing them)
-            let action = self.get_next_action();
+            let action = self.get_next_action(&mut last_batch, &mut completions);
+            Self::run_completions(&mut completions);
             match action {
                 NextAction::Exit => break,
+                NextAction::Retire => {
+                    log::debug!("executor thread retired");
+                    break;
+                }
                 NextAction::ExecuteSome(items) => {
-                    // Execute those items serially. This will not hold the lock
-                    let remainders = items
-                        .into_iter()
-                        .filter_map(|item| {
-                            if item.dropped() {
-                                log::debug!("encountered dropped task {}", item.id());
-                                return None;
-                            }
-                            match std::panic::catch_unwind(|| item.run()) {
-                                Ok(remainder) => remainder,
-                                Err(e) => {
-                                    log::error!("uncaught panic when running task: {:?}", e);
-                                    None
-                                }
+                    consecutive_rescans = 0;
+                    self.execute_batch(items).settle(&mut last_batch);
+                }
+                NextAction::SleepAtLeast(d, seen_epoch) => match self.wait(d, seen_epoch) {
+                    WakeReason::Shutdown => break,
+                    WakeReason::Rescan => {
+                        consecutive_rescans += 1;
+                        if consecutive_rescans >= MAX_RESCANS_BEFORE_BACKOFF {
+                            // Under heavy churn, we could otherwise spin here
+                            consecutive_rescans = 0;
+                            match self.config.condvar_backoff {
+                                Some(backoff) => std::thread::sleep(backoff),
+                                None => std::thread::yield_now(),
                             }
-                        })
-                        .collect::<SmallVec<[Task; MAX_PER_LOOP]>>();
-                    // Reinsert any periodic timers to the list in one big chunk
-                    if !remainders.is_empty() {
-                        let mut s = self.shared.lock();
-                        for item in remainders {
-                            s.tasks.push(item);
                         }
                     }
+                    WakeReason::Notified | WakeReason::Timeout => consecutive_rescans = 0,
+                },
+            }
+        }
+    }
+
+    /// Do any last work before the executor exits, according to the configured shutdown mode
+    pub(crate) fn finalize(&self) {
+        let budget = match self.config.shutdown_mode {
+            ShutdownMode::Immediate => return,
+            ShutdownMode::FinalizeRepeating(budget) => budget,
+        };
+        // One-off tasks are left in the queue, to be reported as dropped along with any
+        // repeating tasks which there isn't time for
+        let repeating = {
+            let mut shared = self.shared.lock();
+            let (repeating, rest): (Vec<_>, Vec<_>) = shared
+                .tasks
+                .take_all()
+                .into_iter()
+                .partition(|t| t.is_repeating() && !t.dropped());
+            shared.tasks.extend(rest);
+            repeating
+        };
+        let started = Instant::now();
+        let total = repeating.len();
+        let mut finalized = 0;
+        let clock = std::panic::AssertUnwindSafe(&*self.config.clock.0);
+        let mut abandoned = Vec::new();
+        for item in repeating {
+            if started.elapsed() > budget {
+                abandoned.push(item);
+                continue;
+            }
+            finalized += 1;
+            let task_id = item.id();
+            if !self.allowed(&item) {
+                log::debug!("execution gate skipped finalizing task {}", task_id);
+                self.report(task_id, TaskOutcome::GateVetoed);
+                continue;
+            }
+            let run_started = Instant::now();
+            self.current_task.set(task_id);
+            let result = std::panic::catch_unwind(|| item.run(*clock));
+            self.current_task.set(0);
+            match result {
+                Ok(_) => record_executed(self.observer(), task_id, run_started.elapsed()),
+                Err(e) => {
+                    log::error!("uncaught panic when finalizing task: {:?}", e);
+                    record_panicked(self.observer(), task_id);
                 }
-                NextAction::SleepAtLeast(d, seen_epoch) => {
-                    // Wait for the next item to be ready. This will only briefly hold the lock to
-                    // check for shutdown.
-                    let mut shared = self.shared.lock();
-                    if shared.done {
-                        break;
-                    }
-                    // This means someone changed the structure between when we read it at the top
-                    // and here, so let's rescan
-                    if shared.next_id != seen_epoch {
-                        continue;
+            }
+            let mut completions = self
+                .shared
+                .lock()
+                .completion_callbacks
+                .remove(&task_id)
+                .unwrap_or_default();
+            Self::run_completions(&mut completions);
+        }
+        if !abandoned.is_empty() {
+            self.shared.lock().tasks.extend(abandoned);
+        }
+        if finalized < total {
+            log::warn!(
+                "ran out of time finalizing repeating tasks; abandoned {} of {}",
+                total - finalized,
+                total
+            );
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Executor {
+    pub async fn run_until_done_async(self, changed: Arc<tokio::sync::Notify>) {
+        if let Some(on_start) = &self.config.on_executor_start {
+            (on_start.0)();
+        }
+        let mut last_batch = BatchIds::new();
+        let mut completions = Vec::new();
+        loop {
+            let action = self.get_next_action(&mut last_batch, &mut completions);
+            Self::run_completions(&mut completions);
+            match action {
+                // Only executor threads spawned by the timer are ever retired
+                NextAction::Exit | NextAction::Retire => break,
+                NextAction::ExecuteSome(items) => {
+                    // Run the batch on the blocking pool so that we don't block the runtime
+                    let executor = self.clone();
+                    match tokio::task::spawn_blocking(move || executor.execute_batch(items)).await {
+                        Ok(result) => result.settle(&mut last_batch),
+                        Err(e) => log::error!("error running batch on blocking pool: {:?}", e),
                     }
-                    if !self
-                        .changed
-                        .wait_until(&mut shared, Instant::now() + d)
-                        .timed_out()
+                }
+                NextAction::SleepAtLeast(d, seen_epoch) => {
                     {
-                        log::debug!("something changed");
+                        let mut shared = self.shared.lock();
+                        if shared.done {
+                            Self::record_wake(&mut shared, WakeReason::Shutdown);
+                            break;
+                        }
+                        if shared.epoch != seen_epoch {
+                            shared.rescans += 1;
+                            Self::record_wake(&mut shared, WakeReason::Rescan);
+                            continue;
+                        }
                     }
+                    // Notify stores a permit if nobody is waiting yet, so we won't miss a change
+                    // that happens between checking above and waiting here
+                    let expected = self.now() + d;
+                    let timed_out = tokio::time::timeout(d, changed.notified()).await.is_err();
+                    let mut shared = self.shared.lock();
+                    self.check_suspended(&mut shared, expected);
+                    let reason = if shared.done {
+                        WakeReason::Shutdown
+                    } else if timed_out {
+                        WakeReason::Timeout
+                    } else {
+                        WakeReason::Notified
+                    };
+                    Self::record_wake(&mut shared, reason);
+                }
+            }
+        }
+        let on_stop = self.config.on_executor_stop.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || self.finalize()).await {
+            log::error!("error finalizing on blocking pool: {:?}", e);
+        }
+        if let Some(on_stop) = on_stop {
+            (on_stop.0)();
+        }
+    }
+}
+
+/// The loop which runs a timer's tasks as they come due, as set with
+/// [`TimerBuilder::executor`](crate::TimerBuilder::executor). This separates how the executor
+/// waits for tasks from the `Timer` API, so that (for example) a backend can tick at a fixed
+/// resolution rather than sleeping until exactly the next deadline.
+///
+/// A backend is handed an [`ExecutorRunner`] on each executor thread, and drives it with
+/// [`ExecutorRunner::run_due`] until [`ExecutorRunner::should_exit`] returns `true`. Starting and
+/// stopping the executor (including the [`ShutdownMode`](crate::ShutdownMode)) is handled around
+/// the backend. Backends are not used for an executor running on a Tokio runtime.
+pub trait ExecutorBackend: Send + Sync + 'static {
+    /// Run tasks on the current thread as they come due, returning once the runner should exit
+    fn run_until_done(&self, runner: &mut ExecutorRunner);
+}
+
+/// The default [`ExecutorBackend`], which sleeps until the earliest pending task is due (or the
+/// queue changes) and then runs every due task
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultBackend;
+
+impl ExecutorBackend for DefaultBackend {
+    fn run_until_done(&self, runner: &mut ExecutorRunner) {
+        runner.executor.run_loop()
+    }
+}
+
+/// The executor half of a [`Timer`](crate::Timer) constructed with
+/// [`Timer::new_unstarted`](crate::Timer::new_unstarted), for running the executor loop on a
+/// caller-provided thread.
+pub struct ExecutorRunner {
+    executor: Executor,
+}
+
+impl ExecutorRunner {
+    pub(crate) fn new(executor: Executor) -> Self {
+        Self { executor }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn executor(&self) -> &Executor {
+        &self.executor
+    }
+
+    /// Make another runner for the same timer, to run on an additional thread
+    pub(crate) fn duplicate(&self) -> Self {
+        let current_task = Arc::new(TaskSlot::default());
+        self.executor
+            .shared
+            .lock()
+            .current_tasks
+            .push(Arc::clone(&current_task));
+        Self::new(Executor {
+            retired: Arc::default(),
+            current_task,
+            ..self.executor.clone()
+        })
+    }
+
+    /// The flag which makes this runner exit without shutting down the timer
+    pub(crate) fn retired(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.executor.retired)
+    }
+
+    /// Run scheduled tasks on the current thread until the corresponding `Timer` is dropped or
+    /// shut down, using the timer's [`ExecutorBackend`].
+    pub fn run(mut self) {
+        let config = self.executor.config.clone();
+        let thread = std::thread::current().id();
+        self.executor.shared.lock().executor_thread_ids.push(thread);
+        if let Some(on_start) = &config.on_executor_start {
+            (on_start.0)();
+        }
+        config.executor_backend.0.run_until_done(&mut self);
+        // The remaining executors carry on with the queue, so there's nothing to finalize
+        if !self.executor.retired.load(Ordering::Relaxed) {
+            self.executor.finalize();
+        }
+        let mut shared = self.executor.shared.lock();
+        shared.executor_thread_ids.retain(|&id| id != thread);
+        shared
+            .current_tasks
+            .retain(|slot| !Arc::ptr_eq(slot, &self.executor.current_task));
+        drop(shared);
+        if let Some(on_stop) = &config.on_executor_stop {
+            (on_stop.0)();
+        }
+    }
+
+    /// Run every task which is currently due on the current thread, returning the number of tasks
+    /// run. Cancelled tasks are discarded without being counted. This is the building block for
+    /// an [`ExecutorBackend`].
+    pub fn run_due(&mut self) -> usize {
+        let mut last_batch = BatchIds::new();
+        let mut completions = Vec::new();
+        let mut ran = 0;
+        loop {
+            let action = self
+                .executor
+                .get_next_action(&mut last_batch, &mut completions);
+            Executor::run_completions(&mut completions);
+            match action {
+                NextAction::ExecuteSome(items) => {
+                    let live = items
+                        .iter()
+                        .filter(|t| !t.dropped())
+                        .map(Task::id)
+                        .collect::<BatchIds>();
+                    let result = self.executor.execute_batch(items);
+                    ran += live
+                        .iter()
+                        .filter(|id| !result.unexecuted.contains(id))
+                        .count();
+                    result.settle(&mut last_batch);
                 }
+                NextAction::SleepAtLeast(..) | NextAction::Exit | NextAction::Retire => break,
             }
         }
+        ran
+    }
+
+    /// Whether an [`ExecutorBackend`] running this should return, because the timer has been shut
+    /// down or this executor thread has been removed (see
+    /// [`Timer::remove_executor_thread`](crate::Timer::remove_executor_thread))
+    pub fn should_exit(&self) -> bool {
+        self.executor.retired.load(Ordering::Relaxed) || self.executor.shared.lock().done
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) async fn run_async(self, changed: Arc<tokio::sync::Notify>) {
+        self.executor.run_until_done_async(changed).await
+    }
+}
+
+impl std::fmt::Debug for ExecutorRunner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExecutorRunner").finish_non_exhaustive()
     }
 }
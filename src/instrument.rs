@@ -0,0 +1,36 @@
+//! Emission of metrics via the `metrics` crate facade. Without the `metrics` feature, these are all
+//! no-ops.
+#![cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+
+use std::time::Duration;
+
+pub(crate) fn task_scheduled(queue_depth: usize) {
+    #[cfg(feature = "metrics")]
+    {
+        metrics::counter!("synchronous_timer.scheduled").increment(1);
+        metrics::gauge!("synchronous_timer.queue_depth").set(queue_depth as f64);
+    }
+}
+
+pub(crate) fn task_executed(duration: Duration) {
+    #[cfg(feature = "metrics")]
+    {
+        metrics::counter!("synchronous_timer.executed").increment(1);
+        metrics::histogram!("synchronous_timer.task_duration").record(duration);
+    }
+}
+
+pub(crate) fn task_panicked() {
+    #[cfg(feature = "metrics")]
+    metrics::counter!("synchronous_timer.panics").increment(1);
+}
+
+pub(crate) fn queue_depth(queue_depth: usize) {
+    #[cfg(feature = "metrics")]
+    metrics::gauge!("synchronous_timer.queue_depth").set(queue_depth as f64);
+}
+
+pub(crate) fn spurious_wakeup() {
+    #[cfg(feature = "metrics")]
+    metrics::counter!("synchronous_timer.spurious_wakeups").increment(1);
+}
@@ -1,22 +1,70 @@
-//! This module implements a relatively simple synchronous Timer/Scheduler backed by the standard library BinaryHeap type. It is suitable for a reasonably large number of tasks, but you should really use some kind of timer-wheel implementation if you want to have millions and millions of tasks.
+//! This module implements a relatively simple synchronous Timer/Scheduler backed by the standard library BinaryHeap type. It is suitable for a reasonably large number of tasks; if you want to have millions and millions of tasks, use a timer wheel instead with [`TimerBuilder::timer_wheel`].
 //!
 //! # Panics
 //! Panics in a scheduled task will be caught and logged; repeating task will *not* be rerun after they panics.
+//! This can be changed for individual repeating tasks with
+//! [`Timer::schedule_repeating_with_panic_policy`].
 //!
+//! This relies on unwinding, so if your application is built with `panic = "abort"`, a panic in a
+//! scheduled task will abort the whole process. A warning is logged when a `Timer` is constructed
+//! in such a build; you can check for this with [`Timer::catches_panics`].
+//!
+//! # Features
+//! - `async`: adds `Timer::spawn_on` for running the executor as a task on a Tokio runtime
+//!   instead of on a dedicated thread
+//! - `testing`: adds helpers which are only meant to be used in tests, such as `TestClock`
+//! - `test-util`: adds `ManualTimer`, for downstream crates to deterministically test code which
+//!   uses a timer (implies `testing`)
+//! - `metrics`: emits metrics through the [`metrics`](https://docs.rs/metrics) crate facade, all
+//!   named under `synchronous_timer.`: `scheduled`, `executed` and `panics` counters, a
+//!   `queue_depth` gauge, and a `task_duration` histogram (in seconds)
+//!
+mod builder;
+mod clock;
+mod error;
 mod executor;
+mod instrument;
+#[cfg(feature = "test-util")]
+mod manual;
+mod observer;
+mod queue;
+mod result;
+mod scope;
+mod single;
+mod stats;
 mod task;
 mod timer;
 
-pub use task::TaskGuard;
-pub use timer::Timer;
+pub use builder::{PanicPolicy, PastPolicy, ShutdownMode, TimerBuilder};
+#[cfg(feature = "testing")]
+pub use clock::TestClock;
+pub use clock::{Clock, SystemClock};
+pub use error::{ClockError, InvariantViolation, ScheduleError, TaskError};
+pub use executor::{DefaultBackend, ExecutorBackend, ExecutorRunner, WakeReason};
+#[cfg(feature = "test-util")]
+pub use manual::ManualTimer;
+pub use observer::{set_global_observer, TaskOutcome, TimerObserver};
+pub use queue::Tier;
+pub use result::{OverflowPolicy, TaskResult};
+pub use scope::Scope;
+pub use single::SingleTimer;
+pub use stats::{DrainReport, TaskStats};
+pub use task::{
+    BoxedRepeatingTask, BoxedTask, PendingTask, RaceHandle, TaskBundle, TaskGuard, Yielder,
+};
+pub use timer::{Timer, TimerShared};
 
 #[cfg(test)]
 mod tests {
-    use std::sync::atomic::{AtomicU32, Ordering};
-    use std::sync::Arc;
-    use std::time::{Duration, SystemTime};
+    use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant, SystemTime};
 
-    use super::Timer;
+    use super::{
+        BoxedRepeatingTask, BoxedTask, DrainReport, OverflowPolicy, PanicPolicy, PastPolicy,
+        ScheduleError, ShutdownMode, SingleTimer, TaskError, TaskGuard, Tier, Timer, TimerBuilder,
+        WakeReason,
+    };
 
     #[test]
     fn test_once() {
@@ -83,4 +131,2981 @@ mod tests {
         std::thread::sleep(Duration::from_millis(100));
         assert_eq!(h.load(Ordering::SeqCst), 1);
     }
+
+    #[test]
+    fn test_max_batch_time() {
+        let mut t = TimerBuilder::new()
+            .max_batch_time(Duration::from_millis(10))
+            .build();
+        let h = Arc::new(AtomicU32::new(0));
+        for _ in 0..4 {
+            let h2 = Arc::clone(&h);
+            t.schedule_in(Duration::from_millis(10), move || {
+                h2.fetch_add(1, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(100));
+            })
+            .detach();
+        }
+        std::thread::sleep(Duration::from_millis(50));
+        // The executor should notice the shutdown after the first slow task rather than finishing
+        // the whole batch
+        drop(t);
+        assert_eq!(h.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_task_stats() {
+        let mut t = Timer::new();
+        let guard = t.schedule_repeating(Duration::from_millis(20), || {
+            std::thread::sleep(Duration::from_millis(10));
+        });
+        let task_id = guard.task_id();
+        std::thread::sleep(Duration::from_millis(150));
+        let stats = t.task_stats(task_id).expect("should have stats");
+        assert!(stats.runs >= 3);
+        assert!(stats.avg_duration >= Duration::from_millis(10));
+        assert!(stats.avg_duration < Duration::from_millis(50));
+        assert!(stats.max_duration >= stats.avg_duration);
+        drop(guard);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(t.task_stats(task_id), None);
+    }
+
+    #[test]
+    fn test_immediate_burst_while_sleeping() {
+        let mut t = Timer::new();
+        // Give the executor time to go to sleep on an empty queue
+        std::thread::sleep(Duration::from_millis(50));
+        let h = Arc::new(AtomicU32::new(0));
+        let start = Instant::now();
+        for _ in 0..20 {
+            let h2 = Arc::clone(&h);
+            t.schedule_immediately(move || {
+                h2.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        while h.load(Ordering::SeqCst) < 20 && start.elapsed() < Duration::from_secs(2) {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(h.load(Ordering::SeqCst), 20);
+        // This should take a few batches at most, rather than one loop timeout per task
+        assert!(start.elapsed() < Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_stop_accepting() {
+        let mut t = Timer::new();
+        let h = Arc::new(AtomicU32::new(0));
+        let h2 = Arc::clone(&h);
+        t.schedule_in(Duration::from_millis(50), move || {
+            h2.fetch_add(1, Ordering::SeqCst);
+        })
+        .detach();
+        t.stop_accepting();
+        let h2 = Arc::clone(&h);
+        let res = t.try_schedule_immediately(move || {
+            h2.fetch_add(10, Ordering::SeqCst);
+        });
+        assert_eq!(res, Err(ScheduleError::NotAccepting));
+        let h2 = Arc::clone(&h);
+        t.schedule_immediately(move || {
+            h2.fetch_add(10, Ordering::SeqCst);
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(h.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_high_watermark() {
+        let mut t = Timer::new();
+        let h = Arc::new(AtomicU32::new(0));
+        let h2 = Arc::clone(&h);
+        t.on_high_watermark(5, move |len| {
+            assert_eq!(len, 6);
+            h2.fetch_add(1, Ordering::SeqCst);
+        });
+        let guards = (0..10)
+            .map(|_| t.schedule_in(Duration::from_secs(10), || {}))
+            .collect::<Vec<_>>();
+        assert_eq!(h.load(Ordering::SeqCst), 1);
+        drop(guards);
+    }
+
+    #[test]
+    fn test_reschedule_repeating() {
+        let mut t = Timer::new();
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let fired2 = Arc::clone(&fired);
+        let guard = t.schedule_repeating(Duration::from_secs(10), move || {
+            fired2.lock().unwrap().push(Instant::now());
+        });
+        let start = Instant::now();
+        assert!(t.reschedule_repeating(
+            guard.task_id(),
+            Duration::from_millis(30),
+            start + Duration::from_millis(50)
+        ));
+        std::thread::sleep(Duration::from_millis(150));
+        drop(guard);
+        let fired = fired.lock().unwrap();
+        assert!(fired.len() >= 3);
+        let first = fired[0] - start;
+        assert!(first >= Duration::from_millis(50));
+        assert!(first < Duration::from_millis(90));
+        for pair in fired.windows(2) {
+            assert!(pair[1] - pair[0] < Duration::from_millis(60));
+        }
+        assert!(!t.reschedule_repeating(12345, Duration::from_millis(1), start));
+    }
+
+    #[test]
+    fn test_catches_panics() {
+        // Tests are always built with unwinding, so we should always catch panics here
+        assert!(Timer::catches_panics());
+        let mut t = Timer::new();
+        t.schedule_immediately(|| panic!("oh no"));
+        let h = Arc::new(AtomicU32::new(0));
+        let h2 = Arc::clone(&h);
+        t.schedule_in(Duration::from_millis(10), move || {
+            h2.fetch_add(1, Ordering::SeqCst);
+        })
+        .detach();
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(h.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_schedule_every_nth() {
+        let mut t = Timer::new();
+        let master_count = Arc::new(AtomicU32::new(0));
+        let sub_count = Arc::new(AtomicU32::new(0));
+        let m2 = Arc::clone(&master_count);
+        let master = t.schedule_repeating(Duration::from_millis(10), move || {
+            m2.fetch_add(1, Ordering::SeqCst);
+        });
+        let m2 = Arc::clone(&master_count);
+        let s2 = Arc::clone(&sub_count);
+        t.schedule_every_nth(&master, 3, move || {
+            assert_eq!(m2.load(Ordering::SeqCst) % 3, 0);
+            s2.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap()
+        .detach();
+        std::thread::sleep(Duration::from_millis(200));
+        drop(master);
+        std::thread::sleep(Duration::from_millis(50));
+        let master_count = master_count.load(Ordering::SeqCst);
+        assert!(master_count >= 9);
+        assert_eq!(sub_count.load(Ordering::SeqCst), master_count / 3);
+        let once = t.schedule_in(Duration::from_millis(1), || {});
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(
+            t.schedule_every_nth(&once, 3, || {}).map(|_| ()),
+            Err(ScheduleError::NoSuchTask)
+        );
+        // A pending one-off task has no runs to count either
+        let later = t.schedule_in(Duration::from_secs(10), || {});
+        assert_eq!(
+            t.schedule_every_nth(&later, 3, || {}).map(|_| ()),
+            Err(ScheduleError::NoSuchTask)
+        );
+
+        // A follower's ID is in use, and it completes along with its master's next run
+        let master = t.schedule_repeating(Duration::from_millis(50), || {});
+        let follower = t.schedule_every_nth(&master, 1, || {}).unwrap();
+        assert_eq!(
+            t.schedule_in_with_id(follower.task_id(), Duration::ZERO, || {})
+                .map(|_| ()),
+            Err(ScheduleError::IdInUse(follower.task_id()))
+        );
+        let (tx, rx) = std::sync::mpsc::channel();
+        t.on_complete(follower.task_id(), move || tx.send(()).unwrap());
+        assert!(rx.try_recv().is_err());
+        assert_eq!(rx.recv_timeout(Duration::from_millis(500)), Ok(()));
+    }
+
+    #[test]
+    fn test_time_remaining() {
+        let mut t = Timer::new();
+        let guard = t.schedule_in(Duration::from_secs(10), || {});
+        let remaining = t.time_remaining(guard.task_id()).unwrap();
+        assert!(remaining <= Duration::from_secs(10));
+        assert!(remaining > Duration::from_secs(9));
+        let soon = t.schedule_in(Duration::from_millis(10), || {});
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(t.time_remaining(soon.task_id()), None);
+        let task_id = guard.task_id();
+        drop(guard);
+        assert_eq!(t.time_remaining(task_id), None);
+    }
+
+    #[test]
+    fn test_schedule_after_begin_shutdown() {
+        let mut t = Timer::new();
+        let h = Arc::new(AtomicU32::new(0));
+        t.begin_shutdown();
+        let h2 = Arc::clone(&h);
+        let res = t.try_schedule_immediately(move || {
+            h2.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(res, Err(ScheduleError::ShuttingDown));
+        let h2 = Arc::clone(&h);
+        let guard = t.schedule_in(Duration::from_millis(1), move || {
+            h2.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(guard.task_id(), 0);
+        drop(t);
+        assert_eq!(h.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_new_unstarted() {
+        let (mut t, runner) = Timer::new_unstarted();
+        let (tx, rx) = std::sync::mpsc::channel();
+        t.schedule_immediately(move || {
+            tx.send(std::thread::current().name().map(String::from))
+                .unwrap();
+        });
+        let handle = std::thread::Builder::new()
+            .name("my-executor".into())
+            .spawn(|| runner.run())
+            .unwrap();
+        let name = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(name.as_deref(), Some("my-executor"));
+        drop(t);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_condvar_backoff() {
+        let mut t = TimerBuilder::new()
+            .condvar_backoff(Duration::from_micros(100))
+            .build();
+        let h = Arc::new(AtomicU32::new(0));
+        let start = Instant::now();
+        let mut expected = 0;
+        let mut i = 0;
+        while start.elapsed() < Duration::from_millis(200) {
+            // Churn the queue with tasks that are cancelled straight away
+            drop(t.schedule_in(Duration::from_secs(60), || {}));
+            if i % 100 == 0 {
+                let h2 = Arc::clone(&h);
+                t.schedule_immediately(move || {
+                    h2.fetch_add(1, Ordering::SeqCst);
+                });
+                expected += 1;
+            }
+            i += 1;
+        }
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while h.load(Ordering::SeqCst) < expected && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(h.load(Ordering::SeqCst), expected);
+        log::debug!("rescanned {} times", t.rescan_count());
+    }
+
+    #[test]
+    fn test_re_arm() {
+        let mut t = Timer::new();
+        let h = Arc::new(AtomicU32::new(0));
+        let h2 = Arc::clone(&h);
+        let mut guard = t.schedule_in(Duration::from_millis(50), move || {
+            h2.fetch_add(1, Ordering::SeqCst);
+        });
+        let original_id = guard.task_id();
+        let completed = Arc::new(AtomicBool::new(false));
+        let their_completed = Arc::clone(&completed);
+        t.on_complete(original_id, move || {
+            their_completed.store(true, Ordering::SeqCst)
+        });
+        for _ in 0..10 {
+            std::thread::sleep(Duration::from_millis(20));
+            assert!(guard.re_arm(&t, Duration::from_millis(50)));
+        }
+        assert_ne!(guard.task_id(), original_id);
+        assert_eq!(h.load(Ordering::SeqCst), 0);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(h.load(Ordering::SeqCst), 1);
+        // The completion callback moved with the task
+        assert!(completed.load(Ordering::SeqCst));
+        assert!(!guard.re_arm(&t, Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn test_past_policy() {
+        let past = SystemTime::now() - Duration::from_secs(60);
+        let h = Arc::new(AtomicU32::new(0));
+
+        let mut t = Timer::new();
+        let h2 = Arc::clone(&h);
+        t.try_schedule_at(past, move || {
+            h2.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap()
+        .detach();
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(h.load(Ordering::SeqCst), 1);
+
+        let mut t = TimerBuilder::new().past_policy(PastPolicy::Skip).build();
+        let h2 = Arc::clone(&h);
+        t.try_schedule_at(past, move || {
+            h2.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap()
+        .detach();
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(h.load(Ordering::SeqCst), 1);
+
+        let mut t = TimerBuilder::new().past_policy(PastPolicy::Error).build();
+        let h2 = Arc::clone(&h);
+        match t.try_schedule_at(past, move || {
+            h2.fetch_add(1, Ordering::SeqCst);
+        }) {
+            Err(ScheduleError::InThePast(d)) => assert!(d >= Duration::from_secs(60)),
+            other => panic!("unexpected result {:?}", other),
+        }
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(h.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_spawn_on() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let h = Arc::new(AtomicU32::new(0));
+        let h2 = Arc::clone(&h);
+        rt.block_on(async move {
+            let mut t = Timer::spawn_on(&tokio::runtime::Handle::current());
+            t.schedule_in(Duration::from_millis(10), move || {
+                h2.fetch_add(1, Ordering::SeqCst);
+            })
+            .detach();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        });
+        assert_eq!(h.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_upcoming() {
+        let mut t = Timer::new();
+        let c = t.schedule_in(Duration::from_secs(3), || {});
+        let a = t.schedule_in(Duration::from_secs(1), || {});
+        let far = t.schedule_in(Duration::from_secs(60), || {});
+        let b = t.schedule_in(Duration::from_secs(2), || {});
+        let cancelled = t.schedule_in(Duration::from_millis(1500), || {});
+        drop(cancelled);
+        let upcoming = t
+            .upcoming(Duration::from_secs(10))
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        assert_eq!(upcoming, vec![a.task_id(), b.task_id(), c.task_id()]);
+        drop(far);
+    }
+
+    #[test]
+    fn test_nested_scope() {
+        let mut t = Timer::new();
+        let h = Arc::new(AtomicU32::new(0));
+        let parent = t.scope();
+        let child = parent.child();
+        let h2 = Arc::clone(&h);
+        t.schedule_in_scope(&child, Duration::from_millis(50), move || {
+            h2.fetch_add(1, Ordering::SeqCst);
+        })
+        .detach();
+        let h2 = Arc::clone(&h);
+        t.schedule_repeating_in_scope(&child, Duration::from_millis(10), move || {
+            h2.fetch_add(1, Ordering::SeqCst);
+        })
+        .detach();
+        let h2 = Arc::clone(&h);
+        t.schedule_in(Duration::from_millis(50), move || {
+            h2.fetch_add(100, Ordering::SeqCst);
+        })
+        .detach();
+        drop(parent);
+        assert!(child.is_cancelled());
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(h.load(Ordering::SeqCst), 100);
+    }
+
+    #[test]
+    fn test_schedule_boxed() {
+        let mut t = Timer::new();
+        let h = Arc::new(AtomicU32::new(0));
+        let h2 = Arc::clone(&h);
+        let once: BoxedTask = Box::new(move || {
+            h2.fetch_add(100, Ordering::SeqCst);
+        });
+        t.schedule_in_boxed(Duration::from_millis(10), once)
+            .detach();
+        let h2 = Arc::clone(&h);
+        let plugin: Box<dyn Fn() + Send> = Box::new(move || {
+            h2.fetch_add(1, Ordering::SeqCst);
+        });
+        let plugin = std::panic::AssertUnwindSafe(plugin);
+        let repeating: BoxedRepeatingTask = Box::new(move || {
+            let plugin = &plugin;
+            (plugin.0)()
+        });
+        let guard = t.schedule_repeating_boxed(Duration::from_millis(10), repeating);
+        std::thread::sleep(Duration::from_millis(55));
+        drop(guard);
+        let ran = h.load(Ordering::SeqCst);
+        assert!(ran > 100);
+        assert!(ran < 110);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_reset_ids() {
+        let mut t = Timer::new();
+        let guard = t.schedule_in(Duration::from_secs(10), || {});
+        assert_eq!(guard.task_id() as u64, 1);
+        assert_eq!(
+            t.reset_ids(guard.task_id()),
+            Err(ScheduleError::IdInUse(guard.task_id()))
+        );
+        t.reset_ids(100).unwrap();
+        assert_eq!(t.schedule_in(Duration::from_secs(10), || {}).task_id(), 100);
+        drop(guard);
+    }
+
+    #[test]
+    fn test_task_wrapper() {
+        let wrapped = Arc::new(AtomicU32::new(0));
+        let wrapped2 = Arc::clone(&wrapped);
+        let mut t = TimerBuilder::new()
+            .task_wrapper(move |f: BoxedTask| {
+                let wrapped = Arc::clone(&wrapped2);
+                Box::new(move || {
+                    f();
+                    wrapped.fetch_add(1, Ordering::SeqCst);
+                })
+            })
+            .build();
+        let h = Arc::new(AtomicU32::new(0));
+        let h2 = Arc::clone(&h);
+        t.schedule_immediately(move || {
+            h2.fetch_add(1, Ordering::SeqCst);
+        });
+        let h2 = Arc::clone(&h);
+        let guard = t.schedule_repeating(Duration::from_millis(20), move || {
+            h2.fetch_add(1, Ordering::SeqCst);
+        });
+        std::thread::sleep(Duration::from_millis(70));
+        drop(guard);
+        std::thread::sleep(Duration::from_millis(30));
+        let ran = h.load(Ordering::SeqCst);
+        assert!(ran >= 3);
+        assert_eq!(wrapped.load(Ordering::SeqCst), ran);
+    }
+
+    #[test]
+    fn test_shutdown_finalize_repeating() {
+        let mut t = TimerBuilder::new()
+            .shutdown_mode(ShutdownMode::FinalizeRepeating(Duration::from_secs(1)))
+            .build();
+        let h = Arc::new(AtomicU32::new(0));
+        let h2 = Arc::clone(&h);
+        t.schedule_repeating(Duration::from_secs(10), move || {
+            h2.fetch_add(1, Ordering::SeqCst);
+        })
+        .detach();
+        let h2 = Arc::clone(&h);
+        t.schedule_in(Duration::from_secs(10), move || {
+            h2.fetch_add(100, Ordering::SeqCst);
+        })
+        .detach();
+        let cancelled = t.schedule_repeating(Duration::from_secs(10), || panic!("cancelled"));
+        drop(cancelled);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(h.load(Ordering::SeqCst), 0);
+        drop(t);
+        assert_eq!(h.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_schedule_with_id() {
+        let mut t = Timer::new();
+        let h = Arc::new(AtomicU32::new(0));
+        let h2 = Arc::clone(&h);
+        let id = 1 << 63;
+        let guard = t
+            .schedule_in_with_id(id, Duration::from_millis(50), move || {
+                h2.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        assert_eq!(guard.task_id(), id);
+        guard.detach();
+        assert_eq!(
+            t.schedule_in_with_id(id, Duration::from_millis(50), || {})
+                .map(|_| ()),
+            Err(ScheduleError::IdInUse(id))
+        );
+        assert!(t.cancel(id));
+        assert!(!t.cancel(id));
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(h.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_on_miss() {
+        let misses = Arc::new(Mutex::new(Vec::new()));
+        let misses2 = Arc::clone(&misses);
+        let mut t = TimerBuilder::new()
+            .on_miss(Duration::from_millis(20), move |id, expected, actual| {
+                misses2.lock().unwrap().push((id, actual - expected));
+            })
+            .build();
+        let fast = t.schedule_repeating(Duration::from_millis(10), || {});
+        for _ in 0..3 {
+            t.schedule_immediately(|| std::thread::sleep(Duration::from_millis(50)));
+        }
+        std::thread::sleep(Duration::from_millis(200));
+        let fast_id = fast.task_id();
+        drop(fast);
+        let misses = misses.lock().unwrap();
+        assert!(!misses.is_empty());
+        for (id, lateness) in misses.iter() {
+            assert_eq!(*id, fast_id);
+            assert!(*lateness > Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn test_shared_parts() {
+        let mut t = Timer::new();
+        let (shared, _changed) = t.shared_parts();
+        let observer = std::thread::spawn(move || {
+            let mut counts = Vec::new();
+            for _ in 0..2 {
+                std::thread::sleep(Duration::from_millis(50));
+                counts.push(shared.lock().task_count());
+            }
+            counts
+        });
+        let a = t.schedule_in(Duration::from_secs(10), || {});
+        let b = t.schedule_in(Duration::from_secs(10), || {});
+        std::thread::sleep(Duration::from_millis(75));
+        let c = t.schedule_in(Duration::from_secs(10), || {});
+        assert_eq!(observer.join().unwrap(), vec![2, 3]);
+        drop((a, b, c));
+    }
+
+    #[test]
+    fn test_schedule_poll() {
+        let mut t = Timer::new();
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let fired2 = Arc::clone(&fired);
+        let start = Instant::now();
+        let guard = t.schedule_poll(Duration::from_millis(10), move || {
+            let mut fired = fired2.lock().unwrap();
+            fired.push(Instant::now());
+            // Alternate between short and long delays
+            if fired.len() % 2 == 0 {
+                Duration::from_millis(10)
+            } else {
+                Duration::from_millis(60)
+            }
+        });
+        std::thread::sleep(Duration::from_millis(200));
+        drop(guard);
+        let fired = fired.lock().unwrap();
+        assert!(fired.len() >= 4);
+        assert!(fired[0] - start >= Duration::from_millis(10));
+        let long = fired[1] - fired[0];
+        let short = fired[2] - fired[1];
+        assert!(long >= Duration::from_millis(60));
+        assert!(short >= Duration::from_millis(10));
+        assert!(short < Duration::from_millis(50));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        recorder.install().unwrap();
+        let mut t = Timer::new();
+        t.schedule_immediately(|| {});
+        t.schedule_immediately(|| panic!("oh no"));
+        std::thread::sleep(Duration::from_millis(50));
+        let snapshot = snapshotter.snapshot().into_hashmap();
+        let counter = |name: &str| {
+            snapshot
+                .iter()
+                .find(|(k, _)| k.key().name() == name)
+                .map(|(_, (_, _, v))| match v {
+                    DebugValue::Counter(c) => *c,
+                    other => panic!("unexpected value {:?}", other),
+                })
+                .unwrap_or(0)
+        };
+        // Other tests may be running at the same time, so these are lower bounds
+        assert!(counter("synchronous_timer.scheduled") >= 2);
+        assert!(counter("synchronous_timer.executed") >= 1);
+        assert!(counter("synchronous_timer.panics") >= 1);
+        assert!(snapshot
+            .keys()
+            .any(|k| k.key().name() == "synchronous_timer.task_duration"));
+        assert!(snapshot
+            .keys()
+            .any(|k| k.key().name() == "synchronous_timer.queue_depth"));
+    }
+
+    #[test]
+    fn test_take_and_put_tasks() {
+        let mut t = Timer::new();
+        let h = Arc::new(AtomicU32::new(0));
+        let h2 = Arc::clone(&h);
+        t.schedule_in(Duration::from_millis(50), move || {
+            h2.fetch_add(1, Ordering::SeqCst);
+        })
+        .detach();
+        let h2 = Arc::clone(&h);
+        let guard = t.schedule_repeating(Duration::from_millis(20), move || {
+            h2.fetch_add(100, Ordering::SeqCst);
+        });
+        drop(t.schedule_in(Duration::from_millis(50), || {}));
+        let bundle = t.take_tasks();
+        assert_eq!(bundle.len(), 2);
+        drop(t);
+        assert_eq!(h.load(Ordering::SeqCst), 0);
+        let t = Timer::new();
+        t.put_tasks(bundle);
+        std::thread::sleep(Duration::from_millis(70));
+        drop(guard);
+        let ran = h.load(Ordering::SeqCst);
+        assert_eq!(ran % 100, 1);
+        assert!(ran > 100);
+    }
+
+    #[test]
+    fn test_ready_count() {
+        // Never start the executor, so that nothing actually gets run
+        let (mut t, _runner) = Timer::new_unstarted();
+        for _ in 0..3 {
+            t.schedule_immediately(|| {});
+        }
+        drop(t.schedule_in(Duration::from_millis(1), || {}));
+        let later = t.schedule_in(Duration::from_secs(10), || {});
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(t.ready_count(), 3);
+        drop(later);
+    }
+
+    #[test]
+    fn test_single_flight_repeating() {
+        let mut t = Timer::builder().executor_threads(4).build();
+        let running = Arc::new(AtomicBool::new(false));
+        let overlaps = Arc::new(AtomicU64::new(0));
+        let runs = Arc::new(AtomicU64::new(0));
+        let guard = {
+            let running = Arc::clone(&running);
+            let overlaps = Arc::clone(&overlaps);
+            let runs = Arc::clone(&runs);
+            t.schedule_repeating(Duration::from_millis(1), move || {
+                if running.swap(true, Ordering::SeqCst) {
+                    overlaps.fetch_add(1, Ordering::SeqCst);
+                }
+                std::thread::sleep(Duration::from_millis(2));
+                runs.fetch_add(1, Ordering::SeqCst);
+                running.store(false, Ordering::SeqCst);
+            })
+        };
+        // Keep the other executors busy too
+        let others = Arc::new(AtomicU64::new(0));
+        for _ in 0..200 {
+            let others = Arc::clone(&others);
+            t.schedule_immediately(move || {
+                others.fetch_add(1, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_micros(200));
+            });
+        }
+        std::thread::sleep(Duration::from_millis(200));
+        drop(guard);
+        drop(t);
+        assert_eq!(overlaps.load(Ordering::SeqCst), 0);
+        assert!(runs.load(Ordering::SeqCst) > 10);
+        assert_eq!(others.load(Ordering::SeqCst), 200);
+    }
+
+    #[test]
+    fn test_schedule_repeating_prime() {
+        let mut t = Timer::new();
+        let counter = Arc::new(AtomicU32::new(0));
+        let guard = {
+            let counter = Arc::clone(&counter);
+            t.schedule_repeating_prime(Duration::from_millis(50), move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        std::thread::sleep(Duration::from_millis(75));
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+        drop(guard);
+    }
+
+    #[test]
+    fn test_schedule_in_limited() {
+        let mut t = Timer::new();
+        let counter = Arc::new(AtomicU32::new(0));
+        let schedule = |t: &mut Timer, key: &str, duration: Duration| {
+            let counter = Arc::clone(&counter);
+            t.schedule_in_limited(key, 2, duration, move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+        let short = Duration::from_millis(10);
+        let first = schedule(&mut t, "a", short).unwrap();
+        let far = schedule(&mut t, "a", Duration::from_secs(10)).unwrap();
+        assert_eq!(
+            schedule(&mut t, "a", short).unwrap_err(),
+            ScheduleError::LimitExceeded(2)
+        );
+        // Other keys are unaffected
+        schedule(&mut t, "b", short).unwrap().detach();
+        // Once a task has run, there's room again
+        first.detach();
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+        schedule(&mut t, "a", short).unwrap().detach();
+        assert!(schedule(&mut t, "a", short).is_err());
+        // And likewise once one has been cancelled
+        drop(far);
+        schedule(&mut t, "a", short).unwrap().detach();
+        // Keys are forgotten once they have no pending tasks
+        let limits = Arc::clone(&t.shared_parts().0.lock().limits);
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(limits.lock().is_empty());
+    }
+
+    #[test]
+    fn test_schedule_mirrored() {
+        let primary = Timer::new();
+        let backup = Timer::new();
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let next_instance = AtomicU32::new(0);
+        let guards =
+            Timer::schedule_mirrored(&[&primary, &backup], Duration::from_millis(10), || {
+                let fired = Arc::clone(&fired);
+                let instance = next_instance.fetch_add(1, Ordering::SeqCst);
+                Box::new(move || fired.lock().unwrap().push(instance))
+            });
+        assert_eq!(guards.len(), 2);
+        guards.into_iter().for_each(TaskGuard::detach);
+        std::thread::sleep(Duration::from_millis(50));
+        let mut fired = fired.lock().unwrap().clone();
+        fired.sort();
+        assert_eq!(fired, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_returning_cancelled() {
+        let mut t = Timer::new();
+        let (guard, result) = t.schedule_in_returning(Duration::from_millis(10), || 42);
+        guard.detach();
+        assert_eq!(result.recv(), Ok(42));
+
+        let (guard, result) = t.schedule_in_returning(Duration::from_secs(10), || 42);
+        drop(guard);
+        let started = Instant::now();
+        assert_eq!(result.recv(), Err(TaskError::Cancelled));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let (guard, result) = t.schedule_in_returning(Duration::from_secs(10), || 42);
+        assert!(t.cancel(guard.task_id()));
+        assert_eq!(
+            result.recv_timeout(Duration::from_secs(1)),
+            Some(Err(TaskError::Cancelled))
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_schedule_at_test_clock() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let clock = Arc::new(crate::TestClock::new(start));
+        let mut t = Timer::builder()
+            .clock(Arc::clone(&clock))
+            .past_policy(PastPolicy::Error)
+            .build();
+        let deadline = start + Duration::from_secs(60);
+        let guard = t.try_schedule_at(deadline, || {}).unwrap();
+        let remaining = t.time_remaining(guard.task_id()).unwrap();
+        assert!(remaining > Duration::from_secs(59) && remaining <= Duration::from_secs(60));
+
+        // Once the wall clock has moved past the deadline, the task is due straight away
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(
+            t.try_schedule_at(deadline, || {}).unwrap_err(),
+            ScheduleError::InThePast(Duration::from_secs(30))
+        );
+        clock.set(deadline);
+        let h = Arc::new(AtomicU32::new(0));
+        let h2 = Arc::clone(&h);
+        t.try_schedule_at(deadline, move || {
+            h2.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap()
+        .detach();
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(h.load(Ordering::SeqCst), 1);
+        drop(guard);
+    }
+
+    #[test]
+    fn test_flush() {
+        let mut t = Timer::new();
+        let counter = Arc::new(AtomicU32::new(0));
+        for i in 0..5 {
+            let counter = Arc::clone(&counter);
+            t.schedule_in(Duration::from_millis(10 * i), move || {
+                std::thread::sleep(Duration::from_millis(5));
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .detach();
+        }
+        let repeating = t.schedule_repeating(Duration::from_millis(5), || {});
+        let started = Instant::now();
+        t.flush();
+        assert_eq!(counter.load(Ordering::SeqCst), 5);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        drop(repeating);
+    }
+
+    #[test]
+    fn test_schedule_fair() {
+        let run_order = |weight: u64| {
+            let (mut t, runner) = Timer::builder()
+                .fairness_weight("a", weight)
+                .build_unstarted();
+            let order = Arc::new(Mutex::new(String::new()));
+            let at = Instant::now();
+            for key in ["a", "b"] {
+                for _ in 0..100 {
+                    let order = Arc::clone(&order);
+                    t.schedule_fair(key, at, move || order.lock().unwrap().push_str(key))
+                        .detach();
+                }
+            }
+            let executor = std::thread::spawn(move || runner.run());
+            let fairness = Arc::clone(&t.shared_parts().0.lock().fairness);
+            // Each key is forgotten once its last task has run
+            while !fairness.lock().is_empty() {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            assert_eq!(order.lock().unwrap().len(), 200);
+            drop(t);
+            executor.join().unwrap();
+            let order = order.lock().unwrap().clone();
+            order
+        };
+        assert!(run_order(1).starts_with(&"ab".repeat(100)));
+        assert!(run_order(2).starts_with(&"aab".repeat(50)));
+    }
+
+    #[test]
+    fn test_stop_after_next() {
+        let mut t = Timer::new();
+        let counter = Arc::new(AtomicU32::new(0));
+        let guard = {
+            let counter = Arc::clone(&counter);
+            t.schedule_repeating(Duration::from_millis(20), move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        assert!(t.stop_after_next(guard.task_id()));
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+        assert!(!t.stop_after_next(guard.task_id()));
+        let once = t.schedule_in(Duration::from_secs(10), || {});
+        assert!(!t.stop_after_next(once.task_id()));
+    }
+
+    #[test]
+    fn test_schedule_batch_at() {
+        let mut t = Timer::new();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let when = SystemTime::now() + Duration::from_millis(20);
+        let others = (0..20)
+            .map(|_| {
+                let events = Arc::clone(&events);
+                t.schedule_at(when, move || events.lock().unwrap().push("other"))
+            })
+            .collect::<Vec<_>>();
+        let batch = (0..5)
+            .map(|_| {
+                let events = Arc::clone(&events);
+                Box::new(move || events.lock().unwrap().push("batch")) as BoxedTask
+            })
+            .collect();
+        let guard = t.schedule_batch_at(when, batch);
+        std::thread::sleep(Duration::from_millis(60));
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 25);
+        let first = events.iter().position(|e| *e == "batch").unwrap();
+        assert!(events[first..first + 5].iter().all(|e| *e == "batch"));
+        drop(guard);
+        drop(others);
+    }
+
+    #[test]
+    fn test_last_wake_reason() {
+        let (mut t, runner) = Timer::new_unstarted();
+
+        // Pretend that the queue changed after the executor decided to sleep
+        let (shared, _) = t.shared_parts();
+        let stale_epoch = shared.lock().epoch;
+        shared.lock().epoch += 1;
+        let reason = runner
+            .executor()
+            .wait(Duration::from_millis(10), stale_epoch);
+        assert_eq!(reason, WakeReason::Rescan);
+        assert_eq!(t.last_wake_reason(), Some(WakeReason::Rescan));
+
+        let executor = std::thread::spawn(move || runner.run());
+        std::thread::sleep(Duration::from_millis(10));
+        t.schedule_in(Duration::from_millis(20), || {}).detach();
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(t.last_wake_reason(), Some(WakeReason::Notified));
+        std::thread::sleep(Duration::from_millis(40));
+        assert_eq!(t.last_wake_reason(), Some(WakeReason::Timeout));
+
+        t.begin_shutdown();
+        executor.join().unwrap();
+        assert_eq!(t.last_wake_reason(), Some(WakeReason::Shutdown));
+    }
+
+    #[test]
+    fn test_detach_by_default() {
+        let mut t = Timer::builder().detach_by_default(true).build();
+        let h = Arc::new(AtomicU32::new(0));
+        let h2 = Arc::clone(&h);
+        drop(t.schedule_in(Duration::from_millis(10), move || {
+            h2.fetch_add(1, Ordering::SeqCst);
+        }));
+        let h2 = Arc::clone(&h);
+        drop(
+            t.schedule_in(Duration::from_millis(10), move || {
+                h2.fetch_add(10, Ordering::SeqCst);
+            })
+            .arm(),
+        );
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(h.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_verify_invariants() {
+        use crate::task::{Task, TaskCallable, TaskOptions};
+        use crate::InvariantViolation;
+
+        let mut t = Timer::new();
+        let guards = (0..20)
+            .map(|i| t.schedule_in(Duration::from_millis(100 + (i * 7) % 13), || {}))
+            .collect::<Vec<_>>();
+        let repeating = t.schedule_repeating(Duration::from_millis(50), || {});
+        assert!(t.reschedule_repeating(
+            repeating.task_id(),
+            Duration::from_millis(2),
+            Instant::now() + Duration::from_millis(1)
+        ));
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(t.verify_invariants(), Ok(()));
+
+        let (shared, _) = t.shared_parts();
+        let id = guards[0].task_id();
+        shared.lock().tasks.push(Task::new(
+            id,
+            Instant::now() + Duration::from_secs(10),
+            TaskCallable::new_once(|| {}),
+            TaskOptions::default(),
+        ));
+        assert_eq!(
+            t.verify_invariants(),
+            Err(InvariantViolation::DuplicateId(id))
+        );
+    }
+
+    #[test]
+    fn test_bucketed() {
+        let granularity = Duration::from_millis(20);
+        let mut t = Timer::builder().bucketed(granularity).build();
+        let lateness = Arc::new(Mutex::new(Vec::new()));
+        let start = Instant::now();
+        for i in (0..50).rev() {
+            let lateness = Arc::clone(&lateness);
+            let deadline = start + Duration::from_millis(2 * i);
+            t.schedule_in(deadline - Instant::now(), move || {
+                lateness
+                    .lock()
+                    .unwrap()
+                    .push(Instant::now().checked_duration_since(deadline))
+            })
+            .detach();
+        }
+        assert_eq!(t.verify_invariants(), Ok(()));
+        std::thread::sleep(Duration::from_millis(200));
+        let lateness = lateness.lock().unwrap();
+        assert_eq!(lateness.len(), 50);
+        for late in lateness.iter() {
+            // Never early, and never later than one bucket (with some slack for slow test machines)
+            let late = late.expect("task ran early");
+            assert!(late < granularity + Duration::from_millis(30), "{:?}", late);
+        }
+    }
+
+    #[test]
+    fn test_boost_key() {
+        let mut t = Timer::new();
+        let counter = Arc::new(AtomicU32::new(0));
+        let mut guards = Vec::new();
+        for key in ["a", "a", "b"] {
+            let counter = Arc::clone(&counter);
+            guards.push(
+                t.schedule_in_limited(key, 10, Duration::from_secs(60), move || {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                })
+                .unwrap(),
+            );
+        }
+        assert_eq!(t.boost_key("a", Duration::from_secs(120)), 2);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+        assert!(t.time_remaining(guards[2].task_id()).unwrap() > Duration::from_secs(59));
+    }
+
+    #[test]
+    fn test_executor_lifecycle_callbacks() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let t = {
+            let on_start = Arc::clone(&events);
+            let on_stop = Arc::clone(&events);
+            Timer::builder()
+                .on_executor_start(move || {
+                    let name = std::thread::current().name().map(str::to_owned);
+                    on_start.lock().unwrap().push(("start", name));
+                })
+                .on_executor_stop(move || {
+                    let name = std::thread::current().name().map(str::to_owned);
+                    on_stop.lock().unwrap().push(("stop", name));
+                })
+                .build()
+        };
+        std::thread::sleep(Duration::from_millis(10));
+        drop(t);
+        let executor = Some("timer-executor".to_owned());
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![("start", executor.clone()), ("stop", executor)]
+        );
+    }
+
+    #[test]
+    fn test_schedule_in_with_context() {
+        let mut t = Timer::new();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let guard = t.schedule_in_with_context(
+            String::from("request-1234"),
+            Duration::from_millis(20),
+            move |ctx: &String| tx.send(ctx.clone()).unwrap(),
+        );
+        let ctx = t.task_context(guard.task_id()).unwrap();
+        assert_eq!(ctx.downcast::<String>().unwrap().as_str(), "request-1234");
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            "request-1234"
+        );
+        assert!(t.task_context(guard.task_id()).is_none());
+    }
+
+    #[test]
+    fn test_panic_policy() {
+        let mut t = Timer::new();
+        let mut schedule = |policy| {
+            let runs = Arc::new(AtomicU32::new(0));
+            let their_runs = Arc::clone(&runs);
+            let guard = t.schedule_repeating_with_panic_policy(
+                Duration::from_millis(10),
+                policy,
+                move || {
+                    their_runs.fetch_add(1, Ordering::SeqCst);
+                    panic!("oh no");
+                },
+            );
+            (runs, guard)
+        };
+        let (stopped, _stop_guard) = schedule(PanicPolicy::StopOnPanic);
+        let (continued, _continue_guard) = schedule(PanicPolicy::ContinueOnPanic);
+        std::thread::sleep(Duration::from_millis(75));
+        assert_eq!(stopped.load(Ordering::SeqCst), 1);
+        assert!(continued.load(Ordering::SeqCst) >= 3);
+    }
+
+    #[test]
+    fn test_guard_wait() {
+        let mut t = Timer::new();
+        let h = Arc::new(AtomicU32::new(0));
+        let h2 = Arc::clone(&h);
+        let guard = t.schedule_in(Duration::from_millis(20), move || {
+            h2.fetch_add(1, Ordering::SeqCst);
+        });
+        assert!(!guard.is_finished());
+        guard.wait();
+        assert!(guard.is_finished());
+        assert_eq!(h.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_guard_future() {
+        struct Unparker(std::thread::Thread);
+        impl std::task::Wake for Unparker {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        let mut t = Timer::new();
+        let h = Arc::new(AtomicU32::new(0));
+        let h2 = Arc::clone(&h);
+        let mut guard = std::pin::pin!(t.schedule_in(Duration::from_millis(20), move || {
+            h2.fetch_add(1, Ordering::SeqCst);
+        }));
+        let waker = Arc::new(Unparker(std::thread::current())).into();
+        let mut cx = std::task::Context::from_waker(&waker);
+        while std::future::Future::poll(guard.as_mut(), &mut cx).is_pending() {
+            std::thread::park();
+        }
+        assert_eq!(h.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_scale_executor_threads() {
+        let mut t = Timer::new();
+        assert!(!t.remove_executor_thread());
+        let running = Arc::new(AtomicU32::new(0));
+        let max_running = Arc::new(AtomicU32::new(0));
+        let completed = Arc::new(AtomicU32::new(0));
+        let schedule = |t: &mut Timer| {
+            let running = Arc::clone(&running);
+            let max_running = Arc::clone(&max_running);
+            let completed = Arc::clone(&completed);
+            t.schedule_immediately(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(20));
+                running.fetch_sub(1, Ordering::SeqCst);
+                completed.fetch_add(1, Ordering::SeqCst);
+            });
+        };
+        for _ in 0..3 {
+            t.add_executor_thread();
+        }
+        assert_eq!(t.executor_thread_count(), 4);
+        // Tasks are taken in batches, so schedule them a few at a time to spread them out
+        for _ in 0..8 {
+            schedule(&mut t);
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(completed.load(Ordering::SeqCst), 8);
+        assert!(max_running.load(Ordering::SeqCst) > 1);
+        for _ in 0..3 {
+            assert!(t.remove_executor_thread());
+        }
+        assert!(!t.remove_executor_thread());
+        assert_eq!(t.executor_thread_count(), 1);
+        std::thread::sleep(Duration::from_millis(50));
+        max_running.store(0, Ordering::SeqCst);
+        for _ in 0..4 {
+            schedule(&mut t);
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(completed.load(Ordering::SeqCst), 12);
+        assert_eq!(max_running.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_schedule_repeating_logged() {
+        static LOGGED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+        struct Capture;
+        impl log::Log for Capture {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                true
+            }
+            fn log(&self, record: &log::Record) {
+                LOGGED.lock().unwrap().push(record.args().to_string());
+            }
+            fn flush(&self) {}
+        }
+        let _ = log::set_logger(&Capture);
+        log::set_max_level(log::LevelFilter::Warn);
+        let mut t = Timer::new();
+        let runs = Arc::new(AtomicU32::new(0));
+        let their_runs = Arc::clone(&runs);
+        let guard =
+            t.schedule_repeating_logged(Duration::from_millis(20), move || {
+                match their_runs.fetch_add(1, Ordering::SeqCst) % 2 {
+                    0 => Err("flaky poller failed"),
+                    _ => Ok(()),
+                }
+            });
+        std::thread::sleep(Duration::from_millis(110));
+        // Let any in-progress run finish logging before counting
+        drop(guard);
+        std::thread::sleep(Duration::from_millis(30));
+        let runs = runs.load(Ordering::SeqCst);
+        assert!((3..=5).contains(&runs), "ran {} times", runs);
+        let failures = LOGGED
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|m| m.contains("flaky poller failed"))
+            .count();
+        assert_eq!(failures as u32, runs.div_ceil(2));
+    }
+
+    #[test]
+    fn test_on_complete() {
+        let mut t = Timer::new();
+        let ran = Arc::new(AtomicBool::new(false));
+        let their_ran = Arc::clone(&ran);
+        let guard = t.schedule_in(Duration::from_millis(20), move || {
+            their_ran.store(true, Ordering::SeqCst);
+        });
+        let (tx, rx) = std::sync::mpsc::channel();
+        let task_id = guard.task_id();
+        let their_ran = Arc::clone(&ran);
+        t.on_complete(task_id, move || {
+            tx.send(their_ran.load(Ordering::SeqCst)).unwrap();
+        });
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok(true));
+        // Once the task has finished, callbacks are invoked straight away
+        let (tx, rx) = std::sync::mpsc::channel();
+        t.on_complete(task_id, move || tx.send(()).unwrap());
+        assert_eq!(rx.try_recv(), Ok(()));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_suspend_skips_missed_repeating() {
+        let clock = Arc::new(crate::TestClock::new(SystemTime::now()));
+        let mut t = Timer::builder()
+            .clock(Arc::clone(&clock))
+            .suspend_threshold(Duration::from_secs(1))
+            .build();
+        let runs = Arc::new(AtomicU32::new(0));
+        let _guards = (0..5)
+            .map(|_| {
+                let runs = Arc::clone(&runs);
+                t.schedule_repeating(Duration::from_secs(10), move || {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                })
+            })
+            .collect::<Vec<_>>();
+        std::thread::sleep(Duration::from_millis(20));
+        clock.suspend(Duration::from_secs(3600));
+        // Scheduling something wakes the executor, which notices how late it is
+        let (tx, rx) = std::sync::mpsc::channel();
+        t.schedule_immediately(move || tx.send(()).unwrap());
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(runs.load(Ordering::SeqCst), 0);
+        assert_eq!(t.upcoming(Duration::from_secs(9)), vec![]);
+        assert_eq!(t.upcoming(Duration::from_secs(11)).len(), 5);
+    }
+
+    #[test]
+    fn test_schedule_in_earliest() {
+        let mut t = Timer::new();
+        let h = Arc::new(AtomicU32::new(0));
+        let h2 = Arc::clone(&h);
+        let guard = t.schedule_in_earliest(
+            &[
+                Duration::from_secs(5),
+                Duration::from_millis(200),
+                Duration::from_secs(1),
+            ],
+            move || {
+                h2.fetch_add(1, Ordering::SeqCst);
+            },
+        );
+        let remaining = t.time_remaining(guard.task_id()).unwrap();
+        assert!(remaining <= Duration::from_millis(200));
+        // A later candidate doesn't delay the task, but a sooner one brings it forward
+        assert!(!t.reschedule_earliest(guard.task_id(), Duration::from_secs(10)));
+        assert!(t.time_remaining(guard.task_id()).unwrap() <= remaining);
+        assert!(t.reschedule_earliest(guard.task_id(), Duration::from_millis(20)));
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(h.load(Ordering::SeqCst), 1);
+        assert!(!t.reschedule_earliest(guard.task_id(), Duration::ZERO));
+    }
+
+    #[test]
+    fn test_restart() {
+        let mut t = Timer::new();
+        assert!(!t.restart());
+        t.begin_shutdown();
+        assert_eq!(
+            t.try_schedule_in(Duration::from_millis(10), || {})
+                .unwrap_err(),
+            ScheduleError::ShuttingDown
+        );
+        assert!(t.restart());
+        let (tx, rx) = std::sync::mpsc::channel();
+        t.schedule_in(Duration::from_millis(10), move || {
+            tx.send(std::thread::current().name().map(str::to_owned))
+                .unwrap()
+        })
+        .detach();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)),
+            Ok(Some("timer-executor".to_owned()))
+        );
+
+        // An executor the timer didn't start might still be running, so can't be restarted
+        let (mut t, runner) = Timer::new_unstarted();
+        let executor = std::thread::spawn(|| runner.run());
+        t.begin_shutdown();
+        assert!(!t.restart());
+        executor.join().unwrap();
+        assert!(!t.restart());
+    }
+
+    #[test]
+    fn test_run_n_blocking() {
+        let mut t = Timer::new();
+        let h = Arc::new(AtomicU32::new(0));
+        let elapsed = t.run_n_blocking(100, |i| {
+            let h = Arc::clone(&h);
+            Box::new(move || {
+                if i == 50 {
+                    panic!("oh no");
+                }
+                std::thread::sleep(Duration::from_micros(100));
+                h.fetch_add(1, Ordering::SeqCst);
+            })
+        });
+        assert_eq!(h.load(Ordering::SeqCst), 99);
+        assert!(elapsed >= Duration::from_millis(9));
+    }
+
+    #[test]
+    fn test_cancel_with_grace() {
+        let mut t = Timer::new();
+        let h = Arc::new(AtomicU32::new(0));
+        let mut schedule = |delay| {
+            let h = Arc::clone(&h);
+            t.schedule_in(delay, move || {
+                h.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+        let soon = schedule(Duration::from_millis(50));
+        let later = schedule(Duration::from_millis(150));
+        let (soon_id, later_id) = (soon.task_id(), later.task_id());
+        soon.cancel_with_grace(&t, Duration::from_millis(100));
+        later.cancel_with_grace(&t, Duration::from_millis(100));
+        assert!(t.time_remaining(soon_id).is_some());
+        assert!(t.time_remaining(later_id).is_none());
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(h.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(feature = "test-util")]
+    #[test]
+    fn test_cancel_with_grace_on_virtual_time() {
+        // The grace period is measured on the timer's clock, however far that is from real time
+        let mut t = crate::ManualTimer::new();
+        t.advance(Duration::from_secs(3600));
+        let h = Arc::new(AtomicU32::new(0));
+        let their_h = Arc::clone(&h);
+        let guard = t.schedule_in(Duration::from_secs(600), move || {
+            their_h.fetch_add(1, Ordering::SeqCst);
+        });
+        guard.cancel_with_grace(&t, Duration::from_secs(1200));
+        t.advance(Duration::from_secs(600));
+        assert_eq!(h.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_schedule_at_checked() {
+        let mut t = Timer::builder()
+            .max_wall_clock_offset(Duration::from_secs(86400))
+            .build();
+        match t.schedule_at_checked(SystemTime::UNIX_EPOCH, || {}) {
+            Err(crate::ClockError::TooFarInPast(d)) => assert!(d > Duration::from_secs(86400)),
+            other => panic!("unexpected result {:?}", other),
+        }
+        let far_future = SystemTime::now() + Duration::from_secs(2 * 86400);
+        match t.schedule_at_checked(far_future, || {}) {
+            Err(crate::ClockError::TooFarInFuture(d)) => assert!(d > Duration::from_secs(86400)),
+            other => panic!("unexpected result {:?}", other),
+        }
+        let h = Arc::new(AtomicU32::new(0));
+        let h2 = Arc::clone(&h);
+        t.schedule_at_checked(SystemTime::now() + Duration::from_millis(10), move || {
+            h2.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap()
+        .detach();
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(h.load(Ordering::SeqCst), 1);
+        t.begin_shutdown();
+        assert_eq!(
+            t.schedule_at_checked(SystemTime::now(), || {}).unwrap_err(),
+            crate::ClockError::Schedule(ScheduleError::ShuttingDown)
+        );
+    }
+
+    #[test]
+    fn test_loop_defaults() {
+        let (mut t, runner) = Timer::new_unstarted();
+        let mut last_batch = Default::default();
+        let mut completions = Vec::new();
+        match runner
+            .executor()
+            .get_next_action(&mut last_batch, &mut completions)
+        {
+            crate::executor::NextAction::SleepAtLeast(d, _) => {
+                assert_eq!(d, Timer::DEFAULT_LOOP_TIME)
+            }
+            other => panic!("unexpected action {:?}", other),
+        }
+        for _ in 0..Timer::MAX_PER_LOOP + 2 {
+            t.schedule_immediately(|| {});
+        }
+        match runner
+            .executor()
+            .get_next_action(&mut last_batch, &mut completions)
+        {
+            crate::executor::NextAction::ExecuteSome(batch) => {
+                assert_eq!(batch.len(), Timer::MAX_PER_LOOP)
+            }
+            other => panic!("unexpected action {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_catchup_bounded() {
+        let mut t = Timer::new();
+        let runs = Arc::new(Mutex::new(Vec::new()));
+        let their_runs = Arc::clone(&runs);
+        let _guard =
+            t.schedule_repeating_catchup_bounded(Duration::from_millis(20), 2, move || {
+                their_runs.lock().unwrap().push(Instant::now());
+            });
+        // Stall the executor for about ten intervals
+        let stall_end = Arc::new(Mutex::new(None));
+        let their_stall_end = Arc::clone(&stall_end);
+        t.schedule_in(Duration::from_millis(5), move || {
+            std::thread::sleep(Duration::from_millis(200));
+            *their_stall_end.lock().unwrap() = Some(Instant::now());
+        })
+        .detach();
+        std::thread::sleep(Duration::from_millis(300));
+        let stall_end = stall_end.lock().unwrap().unwrap();
+        let runs = runs.lock().unwrap();
+        assert!(runs.iter().all(|&r| r >= stall_end));
+        let burst = runs
+            .iter()
+            .filter(|&&r| r - stall_end < Duration::from_millis(5))
+            .count();
+        assert_eq!(burst, 2);
+        // It then carries on at its usual cadence
+        assert!((5..=8).contains(&runs.len()), "ran {} times", runs.len());
+    }
+
+    #[test]
+    fn test_execution_gate() {
+        let enabled = Arc::new(AtomicBool::new(false));
+        let gated_id = Arc::new(std::sync::OnceLock::new());
+        let (their_enabled, their_gated_id) = (Arc::clone(&enabled), Arc::clone(&gated_id));
+        let mut t = Timer::builder()
+            .execution_gate(move |task| {
+                their_gated_id.get() != Some(&task.id()) || their_enabled.load(Ordering::SeqCst)
+            })
+            .build();
+        let runs = Arc::new(AtomicU32::new(0));
+        let their_runs = Arc::clone(&runs);
+        let guard = t.schedule_repeating(Duration::from_millis(10), move || {
+            their_runs.fetch_add(1, Ordering::SeqCst);
+        });
+        gated_id.set(guard.task_id()).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(runs.load(Ordering::SeqCst), 0);
+        assert!(t.time_remaining(guard.task_id()).is_some());
+
+        enabled.store(true, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(runs.load(Ordering::SeqCst) >= 2);
+    }
+
+    #[test]
+    fn test_schedule_coupled() {
+        let mut t = Timer::new();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let their_log = Arc::clone(&log);
+        let primary = t.schedule_repeating(Duration::from_millis(20), move || {
+            their_log.lock().unwrap().push('A');
+        });
+        let their_log = Arc::clone(&log);
+        let _coupled = t
+            .schedule_coupled(&primary, move || {
+                their_log.lock().unwrap().push('B');
+            })
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(70));
+        drop(primary);
+        // Let any run in progress finish
+        std::thread::sleep(Duration::from_millis(10));
+        let log = log.lock().unwrap().iter().collect::<String>();
+        assert!(log.len() >= 4, "{}", log);
+        assert_eq!(log, "AB".repeat(log.len() / 2));
+    }
+
+    #[test]
+    fn test_concurrent_schedule_immediately() {
+        let t = Arc::new(Mutex::new(Timer::builder().executor_threads(4).build()));
+        let counts = Arc::new((0..2000).map(|_| AtomicU32::new(0)).collect::<Vec<_>>());
+        let threads = (0..4u32)
+            .map(|thread| {
+                let (t, counts) = (Arc::clone(&t), Arc::clone(&counts));
+                std::thread::spawn(move || {
+                    for i in 0..500u32 {
+                        let counts = Arc::clone(&counts);
+                        t.lock().unwrap().schedule_immediately(move || {
+                            counts[(thread * 500 + i) as usize].fetch_add(1, Ordering::SeqCst);
+                        });
+                        if i % 50 == 0 {
+                            std::thread::sleep(Duration::from_millis(1));
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        t.lock().unwrap().flush();
+        assert!(counts.iter().all(|c| c.load(Ordering::SeqCst) == 1));
+    }
+
+    #[test]
+    fn test_immediate_task_overtakes_slotted_task() {
+        let mut t = Timer::new();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let their_log = Arc::clone(&log);
+        // Lands in the fast-path slot of the empty queue...
+        t.schedule_in(Duration::from_millis(30), move || {
+            their_log.lock().unwrap().push("later");
+        })
+        .detach();
+        // ...but an earlier task scheduled afterwards must still run first
+        let their_log = Arc::clone(&log);
+        t.schedule_immediately(move || {
+            their_log.lock().unwrap().push("now");
+        });
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(*log.lock().unwrap(), vec!["now", "later"]);
+        assert!(t.verify_invariants().is_ok());
+    }
+
+    #[test]
+    fn test_pending_counts_by_kind() {
+        let (mut t, _runner) = Timer::new_unstarted();
+        let repeating = (0..2)
+            .map(|_| t.schedule_repeating(Duration::from_secs(1), || {}))
+            .collect::<Vec<_>>();
+        let poll = t.schedule_poll(Duration::from_secs(1), || Duration::from_secs(1));
+        let once = (0..4)
+            .map(|_| t.schedule_in(Duration::from_secs(10), || {}))
+            .collect::<Vec<_>>();
+        drop(t.schedule_in(Duration::from_secs(10), || {}));
+        assert_eq!(t.pending_repeating_count(), 3);
+        assert_eq!(t.pending_once_count(), 4);
+        drop((repeating, poll, once));
+    }
+
+    #[test]
+    fn test_custom_executor_backend() {
+        /// Runs due tasks at a fixed resolution, like a timer wheel would
+        struct TickingBackend(Arc<AtomicU32>);
+
+        impl crate::ExecutorBackend for TickingBackend {
+            fn run_until_done(&self, runner: &mut crate::ExecutorRunner) {
+                while !runner.should_exit() {
+                    runner.run_due();
+                    self.0.fetch_add(1, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+            }
+        }
+
+        let ticks = Arc::new(AtomicU32::new(0));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let their_stopped = Arc::clone(&stopped);
+        let mut t = Timer::builder()
+            .executor(TickingBackend(Arc::clone(&ticks)))
+            .on_executor_stop(move || their_stopped.store(true, Ordering::SeqCst))
+            .build();
+        let (tx, rx) = std::sync::mpsc::channel();
+        t.schedule_in(Duration::from_millis(20), move || tx.send(()).unwrap())
+            .detach();
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(ticks.load(Ordering::SeqCst) >= 3);
+        drop(t);
+        assert!(stopped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_timer_wheel_matches_heap() {
+        fn run(builder: TimerBuilder) -> (Vec<u64>, u32) {
+            let mut t = builder.build();
+            let order = Arc::new(Mutex::new(Vec::new()));
+            // Out of order, and far enough apart to cross several levels of a small wheel
+            let guards = [45, 5, 120, 30, 80, 12, 60]
+                .into_iter()
+                .map(|ms| {
+                    let order = Arc::clone(&order);
+                    t.schedule_in(Duration::from_millis(ms), move || {
+                        order.lock().unwrap().push(ms)
+                    })
+                })
+                .collect::<Vec<_>>();
+            drop(t.schedule_in(Duration::from_millis(20), || panic!("cancelled")));
+            let far = t.schedule_in(Duration::from_secs(3600), || panic!("too early"));
+            let runs = Arc::new(AtomicU32::new(0));
+            let their_runs = Arc::clone(&runs);
+            let repeating = t.schedule_repeating(Duration::from_millis(25), move || {
+                their_runs.fetch_add(1, Ordering::SeqCst);
+            });
+            std::thread::sleep(Duration::from_millis(160));
+            assert!(t.verify_invariants().is_ok());
+            assert!(t.time_remaining(far.task_id()).is_some());
+            drop((guards, far, repeating));
+            let order = order.lock().unwrap().clone();
+            (order, runs.load(Ordering::SeqCst))
+        }
+
+        let (heap_order, heap_runs) = run(Timer::builder());
+        let (wheel_order, wheel_runs) =
+            run(Timer::builder().timer_wheel(Duration::from_millis(1), 4));
+        assert_eq!(heap_order, vec![5, 12, 30, 45, 60, 80, 120]);
+        assert_eq!(wheel_order, heap_order);
+        assert!(heap_runs.abs_diff(wheel_runs) <= 1);
+        assert!(wheel_runs >= 5);
+    }
+
+    #[test]
+    fn test_approx_memory_bytes() {
+        let (mut t, _runner) = Timer::new_unstarted();
+        let empty = t.approx_memory_bytes();
+        let small = t.schedule_in(Duration::from_secs(10), || {});
+        let with_one = t.approx_memory_bytes();
+        assert!(with_one > empty);
+        // A closure which captures more takes up more
+        let captured = [0u8; 1024];
+        let big = t.schedule_in(Duration::from_secs(10), move || assert_eq!(captured[0], 0));
+        assert!(t.approx_memory_bytes() >= with_one + 1024);
+        let many = (0..100)
+            .map(|_| t.schedule_in(Duration::from_secs(10), || {}))
+            .collect::<Vec<_>>();
+        assert!(t.approx_memory_bytes() > with_one + 1024 + 100 * std::mem::size_of::<u64>());
+        drop((small, big, many));
+    }
+
+    #[test]
+    fn test_schedule_race() {
+        let timers = [Timer::new(), Timer::new(), Timer::new()];
+        let runs = Arc::new(AtomicU32::new(0));
+        let race = Timer::schedule_race(
+            &timers.iter().collect::<Vec<_>>(),
+            Duration::from_millis(10),
+            || {
+                let runs = Arc::clone(&runs);
+                Box::new(move || {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                })
+            },
+        );
+        assert_eq!(race.winner(), None);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert!(race.winner().is_some_and(|w| w < 3));
+
+        // Dropping the handle cancels every instance
+        let race = Timer::schedule_race(
+            &timers.iter().collect::<Vec<_>>(),
+            Duration::from_millis(10),
+            || {
+                let runs = Arc::clone(&runs);
+                Box::new(move || {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                })
+            },
+        );
+        drop(race);
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_replace_all_repeating() {
+        let mut t = Timer::new();
+        let counter = |runs: &Arc<AtomicU32>| -> BoxedRepeatingTask {
+            let runs = Arc::clone(runs);
+            Box::new(move || {
+                runs.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+        let old = Arc::new(AtomicU32::new(0));
+        let _old_guard = t.schedule_repeating_boxed(Duration::from_millis(5), counter(&old));
+        let one_shot = Arc::new(AtomicBool::new(false));
+        let their_one_shot = Arc::clone(&one_shot);
+        let _one_shot_guard = t.schedule_in(Duration::from_millis(60), move || {
+            their_one_shot.store(true, Ordering::SeqCst)
+        });
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(old.load(Ordering::SeqCst) >= 2);
+
+        let new = Arc::new(AtomicU32::new(0));
+        let guards = t.replace_all_repeating(vec![(Duration::from_millis(5), counter(&new))]);
+        assert_eq!(guards.len(), 1);
+        // Let any run which was in progress finish
+        std::thread::sleep(Duration::from_millis(5));
+        let old_runs = old.load(Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(old.load(Ordering::SeqCst), old_runs);
+        assert!(new.load(Ordering::SeqCst) >= 3);
+        assert!(one_shot.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_try_schedule_at_strict() {
+        // Even with the default policy of running past tasks immediately
+        let mut t = Timer::new();
+        let past = SystemTime::now() - Duration::from_secs(1);
+        match t.try_schedule_at_strict(past, || panic!("should not run")) {
+            Err(ScheduleError::InThePast(d)) => {
+                assert!(d >= Duration::from_secs(1) && d < Duration::from_secs(2))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        let (tx, rx) = std::sync::mpsc::channel();
+        let future = SystemTime::now() + Duration::from_millis(10);
+        t.try_schedule_at_strict(future, move || tx.send(()).unwrap())
+            .unwrap()
+            .detach();
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    }
+
+    #[test]
+    fn test_schedule_repeating_yielding() {
+        let mut t = Timer::new();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let their_log = Arc::clone(&log);
+        let mut remaining = 5;
+        let _yielding = t.schedule_repeating_yielding(Duration::from_millis(10), move |yielder| {
+            if remaining == 0 {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+            their_log.lock().unwrap().push('Y');
+            remaining -= 1;
+            if remaining > 0 {
+                yielder.yield_and_reschedule();
+            }
+        });
+        let their_log = Arc::clone(&log);
+        let _other = t.schedule_in(Duration::from_millis(12), move || {
+            their_log.lock().unwrap().push('O');
+        });
+        std::thread::sleep(Duration::from_millis(60));
+        let log = log.lock().unwrap().iter().collect::<String>();
+        // The other task ran between chunks, rather than waiting for all of them
+        assert_eq!(log.len(), 6, "{}", log);
+        assert!(log.starts_with('Y') && !log.ends_with('O'), "{}", log);
+    }
+
+    #[test]
+    fn test_shutdown_draining_timeout() {
+        let (mut t, _runner) = TimerBuilder::default().build_unstarted();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut guards = Vec::new();
+        for (delay, name, sleep) in [
+            (0, "quick", 0),
+            (0, "quick", 0),
+            (1, "slow", 30),
+            (2, "slow", 30),
+        ] {
+            let their_log = Arc::clone(&log);
+            guards.push(t.schedule_in(Duration::from_millis(delay), move || {
+                std::thread::sleep(Duration::from_millis(sleep));
+                their_log.lock().unwrap().push(name);
+            }));
+        }
+        let their_log = Arc::clone(&log);
+        guards.push(t.schedule_in(Duration::from_secs(10), move || {
+            their_log.lock().unwrap().push("later");
+        }));
+        std::thread::sleep(Duration::from_millis(5));
+        let report = t.shutdown_draining_timeout(Duration::from_millis(10));
+        // The first slow task started within the budget, so it was allowed to finish
+        assert_eq!(
+            report,
+            DrainReport {
+                drained: 3,
+                abandoned: 1
+            }
+        );
+        assert_eq!(*log.lock().unwrap(), vec!["quick", "quick", "slow"]);
+    }
+
+    #[test]
+    fn test_dropped_pending() {
+        let mut t = Timer::new();
+        let kept = t.schedule_in(Duration::from_secs(3600), || {});
+        let dropped = (0..3)
+            .map(|_| {
+                let guard = t.schedule_in(Duration::from_secs(3600), || {});
+                let id = guard.task_id();
+                drop(guard);
+                id
+            })
+            .collect::<Vec<_>>();
+        let mut pending = t.dropped_pending();
+        pending.sort_unstable();
+        assert_eq!(pending, dropped);
+        assert!(!pending.contains(&kept.task_id()));
+        assert_eq!(t.purge_cancelled(), 3);
+        assert!(t.dropped_pending().is_empty());
+        assert_eq!(t.pending_once_count(), 1);
+    }
+
+    #[test]
+    fn test_pause_shifts_deadlines() {
+        let mut t = Timer::new();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let started = Instant::now();
+        let _guard = t.schedule_in(Duration::from_millis(100), move || {
+            tx.send(started.elapsed()).unwrap();
+        });
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(t.pause());
+        assert!(!t.pause());
+        assert!(t.is_paused());
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(t.resume());
+        assert!(!t.is_paused());
+        let elapsed = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(
+            elapsed >= Duration::from_millis(150) && elapsed < Duration::from_millis(250),
+            "{:?}",
+            elapsed
+        );
+    }
+
+    #[test]
+    fn test_pause_withholds_due_tasks() {
+        let mut t = Timer::new();
+        let count = Arc::new(AtomicU32::new(0));
+        t.pause();
+        let their_count = Arc::clone(&count);
+        t.schedule_immediately(move || {
+            their_count.fetch_add(1, Ordering::SeqCst);
+        });
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        t.resume();
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_single_timer() {
+        let count = Arc::new(AtomicU32::new(0));
+        let their_count = Arc::clone(&count);
+        let mut t = SingleTimer::new(Duration::from_millis(20), move || {
+            their_count.fetch_add(1, Ordering::SeqCst);
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        // Slow down, then make up for it with a manual trigger
+        t.set_interval(Duration::from_secs(3600));
+        assert_eq!(t.interval(), Duration::from_secs(3600));
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        t.trigger_now();
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        t.set_interval(Duration::from_millis(5));
+        t.stop();
+        assert!(t.is_stopped());
+        let stopped_at = count.load(Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(count.load(Ordering::SeqCst), stopped_at);
+        // Stopping twice is fine
+        t.stop();
+    }
+
+    #[test]
+    fn test_single_timer_stops_after_panic() {
+        let count = Arc::new(AtomicU32::new(0));
+        let their_count = Arc::clone(&count);
+        let t = SingleTimer::new(Duration::from_millis(5), move || {
+            their_count.fetch_add(1, Ordering::SeqCst);
+            panic!("heartbeat failed");
+        });
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert!(t.is_stopped());
+    }
+
+    #[test]
+    fn test_fifo_mode() {
+        let (mut t, runner) = TimerBuilder::default().fifo_mode().build_unstarted();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut guards = Vec::new();
+        for (name, delay) in [("first", 20), ("second", 5)] {
+            let their_log = Arc::clone(&log);
+            guards.push(t.schedule_in(Duration::from_millis(delay), move || {
+                their_log.lock().unwrap().push(name);
+            }));
+        }
+        std::thread::sleep(Duration::from_millis(30));
+        let executor = std::thread::spawn(|| runner.run());
+        t.flush();
+        // Both were due, so they ran in the order they were scheduled
+        assert_eq!(*log.lock().unwrap(), vec!["first", "second"]);
+        drop(t);
+        executor.join().unwrap();
+
+        // More than a batch's worth of due tasks, including some moved in from a timer whose
+        // IDs sort after this one's, still run in the order they reached this timer
+        let (mut t, runner) = TimerBuilder::default().fifo_mode().build_unstarted();
+        let (mut other, _other_runner) = Timer::new_unstarted();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut guards = Vec::new();
+        let mut schedule = |t: &mut Timer, n: usize| {
+            let their_log = Arc::clone(&log);
+            let delay = Duration::from_millis(40 - n as u64);
+            guards.push(t.schedule_in(delay, move || their_log.lock().unwrap().push(n)));
+        };
+        schedule(&mut other, 0);
+        t.put_tasks(other.take_tasks());
+        for n in 1..(Timer::MAX_PER_LOOP * 2) {
+            schedule(&mut t, n);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+        let executor = std::thread::spawn(|| runner.run());
+        t.flush();
+        let expected = (0..(Timer::MAX_PER_LOOP * 2)).collect::<Vec<_>>();
+        assert_eq!(*log.lock().unwrap(), expected);
+        drop(t);
+        executor.join().unwrap();
+    }
+
+    #[test]
+    fn test_batch_size_histogram() {
+        let (mut t, runner) = Timer::new_unstarted();
+        for _ in 0..(Timer::MAX_PER_LOOP * 2 + 1) {
+            t.schedule_immediately(|| {});
+        }
+        let executor = std::thread::spawn(|| runner.run());
+        t.flush();
+        let histogram = t.batch_size_histogram();
+        assert_eq!(histogram.len(), Timer::MAX_PER_LOOP + 1);
+        assert_eq!(histogram[0], 0);
+        assert_eq!(histogram[Timer::MAX_PER_LOOP], 2);
+        assert_eq!(histogram[1], 1);
+        drop(t);
+        executor.join().unwrap();
+    }
+
+    #[test]
+    fn test_precision_window() {
+        fn mean_lateness(mut t: Timer) -> Duration {
+            let (tx, rx) = std::sync::mpsc::channel();
+            let mut total = Duration::ZERO;
+            for _ in 0..20 {
+                let tx = tx.clone();
+                let scheduled = Instant::now();
+                t.schedule_in(Duration::from_millis(1), move || {
+                    tx.send(scheduled.elapsed()).unwrap();
+                })
+                .detach();
+                let elapsed = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+                assert!(elapsed >= Duration::from_millis(1));
+                total += elapsed - Duration::from_millis(1);
+                // Let the executor go back to waiting before the next one
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            total / 20
+        }
+        let coarse = mean_lateness(Timer::new());
+        let precise = mean_lateness(
+            Timer::builder()
+                .precision_window(Duration::from_millis(2))
+                .build(),
+        );
+        // Spinning should only ever help, though how much depends on the platform's condvar; the
+        // slack allows for scheduling noise on a busy machine
+        assert!(
+            precise <= coarse + Duration::from_micros(250),
+            "mean lateness {:?} with spinning, {:?} without",
+            precise,
+            coarse
+        );
+    }
+
+    #[test]
+    fn test_schedule_in_with_cleanup() {
+        let mut t = Timer::new();
+        let cleanups = Arc::new(AtomicU32::new(0));
+        let cleanup = |cleanups: &Arc<AtomicU32>| {
+            let cleanups = Arc::clone(cleanups);
+            move || {
+                cleanups.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+        // Runs successfully
+        t.schedule_in_with_cleanup(Duration::from_millis(1), || {}, cleanup(&cleanups))
+            .detach();
+        // Panics
+        t.schedule_in_with_cleanup(
+            Duration::from_millis(1),
+            || panic!("failed"),
+            cleanup(&cleanups),
+        )
+        .detach();
+        // Cancelled, and reaped once it comes due
+        drop(t.schedule_in_with_cleanup(Duration::from_millis(1), || {}, cleanup(&cleanups)));
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(cleanups.load(Ordering::SeqCst), 3);
+        // Never runs, so is cleaned up when the timer is dropped
+        t.schedule_in_with_cleanup(Duration::from_secs(3600), || {}, cleanup(&cleanups))
+            .detach();
+        assert_eq!(cleanups.load(Ordering::SeqCst), 3);
+        drop(t);
+        assert_eq!(cleanups.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_repeating_not_starved_by_flood() {
+        let mut t = Timer::new();
+        let runs = Arc::new(AtomicU32::new(0));
+        let their_runs = Arc::clone(&runs);
+        let _repeating = t.schedule_repeating(Duration::from_millis(5), move || {
+            their_runs.fetch_add(1, Ordering::SeqCst);
+        });
+        let flooded = Arc::new(AtomicU64::new(0));
+        let started = Instant::now();
+        // A steady flood, but not so heavy that the executor falls behind; if it did, every task
+        // would be delayed equally, since they run in order of deadline
+        while started.elapsed() < Duration::from_millis(100) {
+            for _ in 0..100 {
+                let flooded = Arc::clone(&flooded);
+                t.schedule_immediately(move || {
+                    flooded.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+            std::thread::sleep(Duration::from_micros(500));
+        }
+        let runs = runs.load(Ordering::SeqCst);
+        assert!(runs >= 10, "repeating task only ran {} times", runs);
+        assert!(flooded.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn test_overdue_task_runs_before_newer_immediate_tasks() {
+        let (mut t, runner) = Timer::new_unstarted();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let their_log = Arc::clone(&log);
+        let _repeating = t.schedule_repeating(Duration::from_millis(1), move || {
+            their_log.lock().unwrap().push(0);
+        });
+        std::thread::sleep(Duration::from_millis(5));
+        for i in 1..=Timer::MAX_PER_LOOP * 3 {
+            let their_log = Arc::clone(&log);
+            t.schedule_immediately(move || their_log.lock().unwrap().push(i));
+        }
+        let executor = std::thread::spawn(|| runner.run());
+        t.flush();
+        let log = log.lock().unwrap().clone();
+        assert_eq!(log[0], 0);
+        // The immediate tasks all ran in the order they were scheduled
+        let immediate = log.into_iter().filter(|&i| i != 0).collect::<Vec<_>>();
+        assert_eq!(immediate, (1..=Timer::MAX_PER_LOOP * 3).collect::<Vec<_>>());
+        drop(t);
+        executor.join().unwrap();
+    }
+
+    #[test]
+    fn test_spawn() {
+        let mut t = Timer::new();
+        let count = Arc::new(AtomicU32::new(0));
+        let their_count = Arc::clone(&count);
+        let repeating = t.spawn_repeating(Duration::from_millis(5), move || {
+            their_count.fetch_add(1, Ordering::SeqCst);
+        });
+        let their_count = Arc::clone(&count);
+        let cancelled = t.spawn_in(Duration::from_millis(10), move || {
+            their_count.fetch_add(100, Ordering::SeqCst);
+        });
+        let (tx, rx) = std::sync::mpsc::channel();
+        let at = t.spawn_at(SystemTime::now() + Duration::from_millis(1), move || {
+            tx.send(()).unwrap()
+        });
+        assert!(at != cancelled && at != repeating);
+        assert!(t.cancel(cancelled));
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(t.cancel(repeating));
+        let runs = count.load(Ordering::SeqCst);
+        assert!((1..100).contains(&runs), "{}", runs);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(count.load(Ordering::SeqCst), runs);
+    }
+
+    #[test]
+    fn test_schedule_in_with_ttl() {
+        let mut t = Timer::new();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        // Hold up the executor well past both deadlines
+        t.schedule_immediately(|| std::thread::sleep(Duration::from_millis(50)));
+        std::thread::sleep(Duration::from_millis(5));
+        let their_log = Arc::clone(&log);
+        let _expiring = t.schedule_in_with_ttl(
+            Duration::from_millis(1),
+            Duration::from_millis(10),
+            move || their_log.lock().unwrap().push("ttl"),
+        );
+        let their_log = Arc::clone(&log);
+        let _late = t.schedule_in(Duration::from_millis(1), move || {
+            their_log.lock().unwrap().push("late")
+        });
+        let their_log = Arc::clone(&log);
+        let _generous = t.schedule_in_with_ttl(
+            Duration::from_millis(1),
+            Duration::from_secs(10),
+            move || their_log.lock().unwrap().push("generous"),
+        );
+        t.flush();
+        assert_eq!(*log.lock().unwrap(), vec!["late", "generous"]);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_poke() {
+        let clock = Arc::new(crate::TestClock::new(SystemTime::now()));
+        let mut t = TimerBuilder::default().clock(Arc::clone(&clock)).build();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let _guard = t.schedule_in(Duration::from_secs(3600), move || tx.send(()).unwrap());
+        // Let the executor go to sleep, then move the clock past the deadline behind its back
+        std::thread::sleep(Duration::from_millis(20));
+        clock.suspend(Duration::from_secs(3600));
+        let poked = Instant::now();
+        t.poke();
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(poked.elapsed() < Timer::DEFAULT_LOOP_TIME / 2);
+    }
+
+    #[test]
+    fn test_schedule_in_tier() {
+        let (mut t, runner) = Timer::new_unstarted();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut guards = Vec::new();
+        for (tier, name) in [
+            (Tier::Background, "background"),
+            (Tier::Normal, "normal"),
+            (Tier::Critical, "critical"),
+        ] {
+            let their_log = Arc::clone(&log);
+            guards.push(t.schedule_in_tier(tier, Duration::ZERO, move || {
+                their_log.lock().unwrap().push(name)
+            }));
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        // Not due yet, so doesn't hold anything up
+        let their_log = Arc::clone(&log);
+        guards.push(
+            t.schedule_in_tier(Tier::Critical, Duration::from_millis(30), move || {
+                their_log.lock().unwrap().push("later")
+            }),
+        );
+        assert!(t.verify_invariants().is_ok());
+        let executor = std::thread::spawn(|| runner.run());
+        t.flush();
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["critical", "normal", "background", "later"]
+        );
+        drop(t);
+        executor.join().unwrap();
+    }
+
+    #[test]
+    fn test_schedule_repeating_notifying() {
+        let mut t = Timer::new();
+        let runs = Arc::new(AtomicU32::new(0));
+        let their_runs = Arc::clone(&runs);
+        let (_guard, rx) = t.schedule_repeating_notifying(Duration::from_millis(5), move || {
+            their_runs.fetch_add(1, Ordering::SeqCst);
+        });
+        let fired = (0..3)
+            .map(|_| rx.recv_timeout(Duration::from_secs(1)).unwrap())
+            .collect::<Vec<_>>();
+        assert!(fired
+            .windows(2)
+            .all(|w| w[1] - w[0] >= Duration::from_millis(4)));
+        assert!(runs.load(Ordering::SeqCst) >= 3);
+        // The task carries on without anyone listening
+        drop(rx);
+        let before = runs.load(Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(runs.load(Ordering::SeqCst) > before);
+    }
+
+    #[test]
+    fn test_on_executor_thread() {
+        let mut t = Arc::new(Timer::new());
+        let timer = Arc::new(std::sync::OnceLock::<std::sync::Weak<Timer>>::new());
+        let their_timer = std::panic::AssertUnwindSafe(Arc::clone(&timer));
+        let (tx, rx) = std::sync::mpsc::channel();
+        Arc::get_mut(&mut t)
+            .unwrap()
+            .schedule_in(Duration::from_millis(10), move || {
+                let timer = their_timer
+                    .get()
+                    .and_then(std::sync::Weak::upgrade)
+                    .unwrap();
+                tx.send(timer.on_executor_thread()).unwrap();
+            })
+            .detach();
+        timer.set(Arc::downgrade(&t)).unwrap();
+        assert!(rx.recv_timeout(Duration::from_secs(1)).unwrap());
+        assert!(!t.on_executor_thread());
+    }
+
+    #[test]
+    fn test_with_initial_tasks() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tasks = [30, 10, 20]
+            .into_iter()
+            .map(|millis| {
+                let tx = tx.clone();
+                let task: BoxedTask = Box::new(move || tx.send((millis, Instant::now())).unwrap());
+                (Duration::from_millis(millis), task)
+            })
+            .collect();
+        let started = Instant::now();
+        let (_t, guards) = Timer::with_initial_tasks(tasks);
+        assert_eq!(guards.len(), 3);
+        // They fire in order of deadline, and never early; how late they are is up to the
+        // scheduler, so isn't checked
+        for expected in [10, 20, 30] {
+            let (millis, fired) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+            assert_eq!(millis, expected);
+            let elapsed = fired - started;
+            assert!(elapsed >= Duration::from_millis(millis), "{:?}", elapsed);
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_run_task_here() {
+        let mut t = Timer::new();
+        let ran_on = Arc::new(Mutex::new(None));
+        let their_ran_on = Arc::clone(&ran_on);
+        let ok = t.schedule_in(Duration::from_secs(3600), move || {
+            *their_ran_on.lock().unwrap() = Some(std::thread::current().id());
+        });
+        let panicking = t.schedule_in(Duration::from_secs(3600), || panic!("flaky"));
+        assert_eq!(t.run_task_here(ok.task_id()), Some(Ok(())));
+        assert_eq!(*ran_on.lock().unwrap(), Some(std::thread::current().id()));
+        assert_eq!(
+            t.run_task_here(panicking.task_id()),
+            Some(Err(TaskError::Panicked))
+        );
+        // Both are gone from the queue now
+        assert_eq!(t.run_task_here(ok.task_id()), None);
+        assert_eq!(t.pending_once_count(), 0);
+    }
+
+    #[test]
+    fn test_ids_unique_across_restart() {
+        let mut t = Timer::new();
+        let other = Timer::new();
+        let old = t.schedule_in(Duration::from_secs(10), || {});
+        let old_id = old.task_id();
+        assert_ne!(old_id >> 64, other.shared_parts().0.lock().next_id() >> 64);
+        let done = t.schedule_in(Duration::ZERO, || {});
+        let finished = done.task_id();
+        while t.time_remaining(finished).is_some() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        t.begin_shutdown();
+        assert!(t.restart());
+
+        // New IDs come from a fresh generation, so never collide with old ones
+        let new = t.schedule_in(Duration::from_secs(10), || {});
+        assert_ne!(new.task_id() >> 64, old_id >> 64);
+        assert_eq!(new.task_id() as u64, 1);
+        assert!(t.time_remaining(old_id).is_some());
+
+        // Cancelling by a stale ID is a no-op
+        assert!(!t.cancel(finished));
+        assert!(t.time_remaining(new.task_id()).is_some());
+        assert!(t.cancel(old_id));
+        assert!(t.time_remaining(new.task_id()).is_some());
+    }
+
+    #[test]
+    fn test_spurious_wakeups_skip_rescan() {
+        let mut t = Timer::new();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let their_tx = tx.clone();
+        let _guard = t.schedule_in(Duration::from_millis(200), move || {
+            their_tx.send("late").unwrap();
+        });
+        std::thread::sleep(Duration::from_millis(20));
+        let rescans = t.rescan_count();
+        let (_, changed) = t.shared_parts();
+        for _ in 0..20 {
+            changed.notify_one();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(t.spurious_wakeup_count() >= 10);
+        assert_eq!(t.rescan_count(), rescans);
+
+        // Real changes still wake the executor
+        t.schedule_immediately(move || tx.send("immediate").unwrap());
+        assert_eq!(rx.recv_timeout(Duration::from_millis(100)), Ok("immediate"));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok("late"));
+    }
+
+    #[test]
+    fn test_schedule_not_before() {
+        let mut t = Timer::new();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let floor = Instant::now() + Duration::from_millis(100);
+        let guard = t.schedule_not_before(floor, move || tx.send(Instant::now()).unwrap());
+
+        // Bringing the task forward makes it due, but it still waits for its floor
+        assert!(t.reschedule_earliest(guard.task_id(), Duration::ZERO));
+        let ran_at = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(ran_at >= floor, "ran {:?} early", floor - ran_at);
+    }
+
+    #[test]
+    fn test_coalesce_duplicate_repeating_entries() {
+        use crate::task::{Task, TaskCallable, TaskOptions};
+
+        let (mut t, runner) = Timer::new_unstarted();
+        let guard = t.schedule_repeating(Duration::from_secs(3600), || {});
+        let runs = Arc::new(AtomicU32::new(0));
+        let (shared, _) = t.shared_parts();
+        let now = Instant::now();
+        for _ in 0..3 {
+            let their_runs = Arc::clone(&runs);
+            shared.lock().tasks.push(Task::new(
+                guard.task_id(),
+                now,
+                TaskCallable::new_repeating(
+                    move || {
+                        their_runs.fetch_add(1, Ordering::SeqCst);
+                    },
+                    Duration::from_secs(3600),
+                ),
+                TaskOptions::default(),
+            ));
+        }
+        let executor = std::thread::spawn(|| runner.run());
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(t.batch_size_histogram()[1], 1);
+        drop(guard);
+        drop(t);
+        executor.join().unwrap();
+    }
+
+    #[test]
+    fn test_custom_rng() {
+        let mut sequence = [0, 3_000_000, 7_000_000, 10_000_001 + 5_000_000].into_iter();
+        let mut t = Timer::builder()
+            .rng(Box::new(move || sequence.next().unwrap()))
+            .build();
+        let guards = (0..4)
+            .map(|_| {
+                t.schedule_in_jittered(Duration::from_secs(10), Duration::from_millis(10), || {})
+            })
+            .collect::<Vec<_>>();
+        let deadlines = t.upcoming(Duration::from_secs(20));
+        let first = deadlines[0].1;
+        let jitters = deadlines
+            .iter()
+            .map(|(_, deadline)| (*deadline - first).as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(jitters, vec![0, 3, 5, 7]);
+        drop(guards);
+    }
+
+    #[test]
+    fn test_schedule_repeating_result_channel() {
+        use std::sync::mpsc::RecvTimeoutError;
+
+        fn counter() -> impl FnMut() -> u32 {
+            let mut n = 0;
+            move || {
+                n += 1;
+                n
+            }
+        }
+        let interval = Duration::from_millis(5);
+        let timeout = Duration::from_secs(1);
+
+        // Values which don't fit are thrown away, and the task carries on
+        let mut t = Timer::new();
+        let (_guard, rx) =
+            t.schedule_repeating_result_channel(interval, 1, OverflowPolicy::DropResult, counter());
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(rx.recv_timeout(timeout), Ok(1));
+        assert!(rx.recv_timeout(timeout).unwrap() > 2);
+
+        // The executor waits for room, so nothing is lost
+        let mut t = Timer::new();
+        let (_guard, rx) =
+            t.schedule_repeating_result_channel(interval, 1, OverflowPolicy::Block, counter());
+        std::thread::sleep(Duration::from_millis(50));
+        let received = (0..5)
+            .map(|_| rx.recv_timeout(timeout).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(received, vec![1, 2, 3, 4, 5]);
+        drop(rx);
+
+        // A receiver which is kept but never read doesn't stop the timer from shutting down
+        let mut t = Timer::new();
+        let (_guard, rx) =
+            t.schedule_repeating_result_channel(interval, 1, OverflowPolicy::Block, counter());
+        std::thread::sleep(Duration::from_millis(30));
+        drop(t);
+        assert_eq!(rx.recv_timeout(timeout), Ok(1));
+
+        // Nor does it hold up the executor once the task is cancelled
+        let mut t = Timer::new();
+        let (guard, _rx) =
+            t.schedule_repeating_result_channel(interval, 1, OverflowPolicy::Block, counter());
+        std::thread::sleep(Duration::from_millis(30));
+        drop(guard);
+        let (tx, ran) = std::sync::mpsc::channel();
+        t.schedule_immediately(move || tx.send(()).unwrap());
+        assert_eq!(ran.recv_timeout(Duration::from_millis(500)), Ok(()));
+
+        // The task stops, and the receiver sees the channel disconnect once it's drained
+        let mut t = Timer::new();
+        let (guard, rx) =
+            t.schedule_repeating_result_channel(interval, 1, OverflowPolicy::Error, counter());
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(rx.recv_timeout(timeout), Ok(1));
+        assert_eq!(
+            rx.recv_timeout(timeout),
+            Err(RecvTimeoutError::Disconnected)
+        );
+        assert!(t.time_remaining(guard.task_id()).is_none());
+    }
+
+    #[test]
+    fn test_current_task() {
+        let mut t = Timer::new();
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let release_rx = Mutex::new(release_rx);
+        assert_eq!(t.current_task(), None);
+        let guard = t.schedule_in(Duration::ZERO, move || {
+            started_tx.send(()).unwrap();
+            release_rx.lock().unwrap().recv().unwrap();
+        });
+        started_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(t.current_task(), Some(guard.task_id()));
+        release_tx.send(()).unwrap();
+        guard.wait();
+        // The executor clears the current task just after the task finishes
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while t.current_task().is_some() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(t.current_task(), None);
+    }
+
+    #[test]
+    fn test_schedule_when_below() {
+        let mut t = Timer::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let guards = (1..=10)
+            .map(|i| {
+                let order = Arc::clone(&order);
+                t.schedule_in(Duration::from_millis(5 * i), move || {
+                    order.lock().unwrap().push(i)
+                })
+            })
+            .collect::<Vec<_>>();
+        let their_order = Arc::clone(&order);
+        let deferred = t.schedule_when_below(3, move || their_order.lock().unwrap().push(0));
+        deferred.wait();
+        for guard in guards {
+            guard.wait();
+        }
+        let order = order.lock().unwrap();
+        assert_eq!(order.len(), 11);
+        // It only ran once no more than two of the others were left
+        let position = order.iter().position(|&i| i == 0).unwrap();
+        assert!(position >= 8, "ran at {} in {:?}", position, order);
+
+        // A task which is still being held back can be cancelled by ID
+        let never = t.schedule_when_below(0, || panic!("queue can't be shorter than empty"));
+        assert!(t.cancel(never.task_id()));
+        assert!(!t.cancel(never.task_id()));
+    }
+
+    #[test]
+    fn test_deferred_tasks_are_pending() {
+        let mut t = Timer::new();
+        let never = t.schedule_when_below(0, || panic!("queue can't be shorter than empty"));
+        assert_eq!(t.time_remaining(never.task_id()), Some(Duration::ZERO));
+        let never_id = never.task_id();
+        drop(never);
+        assert_eq!(t.dropped_pending(), vec![never_id]);
+        assert_eq!(t.purge_cancelled(), 1);
+        assert!(t.dropped_pending().is_empty());
+
+        // Flushing waits for deferred tasks too
+        let ran = Arc::new(AtomicBool::new(false));
+        let their_ran = Arc::clone(&ran);
+        t.schedule_in(Duration::from_millis(20), || {}).detach();
+        t.schedule_when_below(1, move || {
+            std::thread::sleep(Duration::from_millis(10));
+            their_ran.store(true, Ordering::SeqCst);
+        })
+        .detach();
+        t.flush();
+        assert!(ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_expedite() {
+        let mut t = Timer::new();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let guard = t.schedule_in(Duration::from_secs(3600), move || tx.send(()).unwrap());
+        assert!(t.expedite(guard.task_id()));
+        assert_eq!(rx.recv_timeout(Duration::from_millis(100)), Ok(()));
+        guard.wait();
+        assert!(!t.expedite(guard.task_id()));
+
+        let guard = t.schedule_in(Duration::from_secs(3600), || {});
+        let task_id = guard.task_id();
+        drop(guard);
+        assert!(!t.expedite(task_id));
+    }
+
+    #[test]
+    fn test_task_outcomes() {
+        use super::{TaskOutcome, TimerObserver};
+
+        #[derive(Default)]
+        struct Outcomes(Mutex<Vec<(u128, TaskOutcome)>>);
+
+        impl TimerObserver for Outcomes {
+            fn task_outcome(&self, task_id: u128, outcome: TaskOutcome) {
+                self.0.lock().unwrap().push((task_id, outcome));
+            }
+        }
+
+        let outcomes = Arc::new(Outcomes::default());
+        let outcome_of = |task_id| {
+            outcomes
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|(id, _)| *id == task_id)
+                .map(|(_, outcome)| *outcome)
+        };
+        let mut t = Timer::builder().observer(Arc::clone(&outcomes)).build();
+
+        let completed = t.schedule_in(Duration::ZERO, || {});
+        let panicked = t.schedule_in(Duration::ZERO, || panic!("oh no"));
+        let cancelled = t.schedule_in(Duration::from_millis(1), || {}).task_id();
+        // Keep the executor busy so that the next task is past its time-to-live when it runs
+        t.schedule_immediately(|| std::thread::sleep(Duration::from_millis(20)));
+        let expired = t.schedule_in_with_ttl(Duration::ZERO, Duration::from_millis(1), || {});
+        let dropped = t.schedule_in(Duration::from_secs(3600), || {});
+        let dropped_id = dropped.task_id();
+        dropped.detach();
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(
+            outcome_of(completed.task_id()),
+            Some(TaskOutcome::Completed)
+        );
+        assert_eq!(outcome_of(panicked.task_id()), Some(TaskOutcome::Panicked));
+        assert_eq!(outcome_of(cancelled), Some(TaskOutcome::CancelledByGuard));
+        assert_eq!(outcome_of(expired.task_id()), Some(TaskOutcome::Expired));
+        assert_eq!(outcome_of(dropped_id), None);
+        let purged = t.schedule_in(Duration::from_secs(3600), || {}).task_id();
+        assert_eq!(t.purge_cancelled(), 1);
+        assert_eq!(outcome_of(purged), Some(TaskOutcome::CancelledByGuard));
+        #[cfg(feature = "testing")]
+        {
+            let here = t.schedule_in(Duration::from_secs(3600), || panic!("here"));
+            assert!(t.run_task_here(here.task_id()).is_some());
+            assert_eq!(outcome_of(here.task_id()), Some(TaskOutcome::Panicked));
+        }
+        drop(t);
+        assert_eq!(outcome_of(dropped_id), Some(TaskOutcome::ShutdownDropped));
+
+        // Draining runs the due tasks on this thread, and anything there's no time for is dropped
+        let (mut t, _runner) = Timer::builder()
+            .observer(Arc::clone(&outcomes))
+            .build_unstarted();
+        let drained = t.schedule_in(Duration::ZERO, || {});
+        let drained_panicked = t.schedule_in(Duration::ZERO, || panic!("oh no"));
+        t.schedule_in(Duration::ZERO, || {
+            std::thread::sleep(Duration::from_millis(150))
+        })
+        .detach();
+        let abandoned = t.schedule_in(Duration::ZERO, || {});
+        let ids = [
+            drained.task_id(),
+            drained_panicked.task_id(),
+            abandoned.task_id(),
+        ];
+        drained.detach();
+        drained_panicked.detach();
+        abandoned.detach();
+        let report = t.shutdown_draining_timeout(Duration::from_millis(75));
+        assert_eq!((report.drained, report.abandoned), (3, 1));
+        assert_eq!(outcome_of(ids[0]), Some(TaskOutcome::Completed));
+        assert_eq!(outcome_of(ids[1]), Some(TaskOutcome::Panicked));
+        assert_eq!(outcome_of(ids[2]), Some(TaskOutcome::ShutdownDropped));
+
+        let mut t = Timer::builder()
+            .observer(Arc::clone(&outcomes))
+            .execution_gate(|_| false)
+            .build();
+        let vetoed = t.schedule_in(Duration::ZERO, || {});
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(outcome_of(vetoed.task_id()), Some(TaskOutcome::GateVetoed));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_state_fingerprint() {
+        let schedule = |t: &mut Timer| {
+            let first = t.schedule_in(Duration::from_secs(10), || {});
+            let second = t.schedule_repeating(Duration::from_secs(20), || {});
+            (first, second)
+        };
+        let mut a = Timer::new();
+        let mut b = Timer::new();
+        let empty = a.state_fingerprint();
+        assert_eq!(b.state_fingerprint(), empty);
+        let _a_guards = schedule(&mut a);
+        let (_b_first, b_second) = schedule(&mut b);
+        assert_ne!(a.state_fingerprint(), empty);
+        assert_eq!(a.state_fingerprint(), b.state_fingerprint());
+
+        // Swapping the order of the tasks changes the fingerprint
+        assert!(b.expedite(b_second.task_id()));
+        assert_ne!(a.state_fingerprint(), b.state_fingerprint());
+
+        // So does running in a different tier, where a later deadline can go first
+        let tiered = |tiers: [Tier; 2]| {
+            let (mut t, _runner) = Timer::new_unstarted();
+            t.schedule_in_tier(tiers[0], Duration::ZERO, || {}).detach();
+            t.schedule_in_tier(tiers[1], Duration::from_millis(5), || {})
+                .detach();
+            std::thread::sleep(Duration::from_millis(10));
+            t.state_fingerprint()
+        };
+        let normal = tiered([Tier::Normal, Tier::Normal]);
+        assert_eq!(tiered([Tier::Normal, Tier::Normal]), normal);
+        assert_ne!(tiered([Tier::Normal, Tier::Critical]), normal);
+
+        // In FIFO mode, due tasks are fingerprinted in submission order rather than by deadline
+        let fifo = |ids: [u128; 2]| {
+            let (mut t, _runner) = Timer::builder().fifo_mode().build_unstarted();
+            for id in ids {
+                let delay = Duration::from_millis((id & 1) as u64 * 5);
+                t.schedule_in_with_id(id, delay, || {}).unwrap().detach();
+            }
+            std::thread::sleep(Duration::from_millis(10));
+            t.state_fingerprint()
+        };
+        let ids = [1 << 127, (1 << 127) + 1];
+        assert_eq!(fifo(ids), fifo(ids));
+        assert_ne!(fifo(ids), fifo([ids[1], ids[0]]));
+    }
+
+    #[test]
+    fn test_current_tasks_with_several_executors() {
+        let mut t = Timer::builder().executor_threads(2).build();
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let release_rx = Mutex::new(release_rx);
+        let stuck = t.schedule_in(Duration::ZERO, move || {
+            started_tx.send(()).unwrap();
+            release_rx.lock().unwrap().recv().unwrap();
+        });
+        started_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+
+        // The other executor runs and finishes a task, which doesn't hide the stuck one
+        let quick = t.schedule_in(Duration::ZERO, || {});
+        quick.wait();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while t.current_tasks().len() > 1 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(t.current_tasks(), vec![stuck.task_id()]);
+        assert_eq!(t.current_task(), Some(stuck.task_id()));
+        release_tx.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_replace_all_repeating_reports_scheduled() {
+        use super::TimerObserver;
+
+        #[derive(Default)]
+        struct Scheduled(Mutex<Vec<u128>>);
+
+        impl TimerObserver for Scheduled {
+            fn task_scheduled(&self, task_id: u128) {
+                self.0.lock().unwrap().push(task_id);
+            }
+        }
+
+        let scheduled = Arc::new(Scheduled::default());
+        let wrapped = Arc::new(AtomicU32::new(0));
+        let wrapped2 = Arc::clone(&wrapped);
+        let mut t = Timer::builder()
+            .observer(Arc::clone(&scheduled))
+            .task_wrapper(move |f: BoxedTask| {
+                let wrapped = Arc::clone(&wrapped2);
+                Box::new(move || {
+                    f();
+                    wrapped.fetch_add(1, Ordering::SeqCst);
+                })
+            })
+            .build();
+        let ran = Arc::new(AtomicU32::new(0));
+        let their_ran = Arc::clone(&ran);
+        let guards = t.replace_all_repeating(vec![(
+            Duration::from_millis(5),
+            Box::new(move || {
+                their_ran.fetch_add(1, Ordering::SeqCst);
+            }),
+        )]);
+        assert_eq!(*scheduled.0.lock().unwrap(), vec![guards[0].task_id()]);
+        std::thread::sleep(Duration::from_millis(30));
+        drop(guards);
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(ran.load(Ordering::SeqCst) >= 2);
+        assert_eq!(wrapped.load(Ordering::SeqCst), ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_coarse_queues_measure_from_timer_clock() {
+        // A clock which runs well behind the real monotonic clock
+        struct Behind;
+
+        impl crate::Clock for Behind {
+            fn system_now(&self) -> SystemTime {
+                SystemTime::now()
+            }
+
+            fn now(&self) -> Instant {
+                Instant::now() - Duration::from_secs(5)
+            }
+        }
+
+        for builder in [
+            Timer::builder().timer_wheel(Duration::from_millis(1), 4),
+            Timer::builder().bucketed(Duration::from_millis(1)),
+        ] {
+            let mut t = builder.clock(Behind).build();
+            let order = Arc::new(Mutex::new(Vec::new()));
+            let guards = [30, 5, 20, 10]
+                .into_iter()
+                .map(|ms| {
+                    let order = Arc::clone(&order);
+                    t.schedule_in(Duration::from_millis(ms), move || {
+                        order.lock().unwrap().push(ms)
+                    })
+                })
+                .collect::<Vec<_>>();
+            std::thread::sleep(Duration::from_millis(60));
+            assert_eq!(*order.lock().unwrap(), vec![5, 10, 20, 30]);
+            drop(guards);
+        }
+    }
+
+    #[test]
+    fn test_coarse_queues_deadlines_before_creation() {
+        for builder in [
+            Timer::builder().bucketed(Duration::from_millis(5)),
+            Timer::builder().timer_wheel(Duration::from_millis(1), 4),
+        ] {
+            let (mut t, runner) = builder.build_unstarted();
+            let now = Instant::now();
+            let order = Arc::new(Mutex::new(Vec::new()));
+            let guards = [30, 10, 20]
+                .into_iter()
+                .map(|ms| {
+                    let order = Arc::clone(&order);
+                    t.schedule_not_before(now - Duration::from_millis(ms), move || {
+                        order.lock().unwrap().push(ms)
+                    })
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(t.verify_invariants(), Ok(()));
+            let executor = std::thread::spawn(|| runner.run());
+            t.flush();
+            assert_eq!(*order.lock().unwrap(), vec![30, 20, 10]);
+            drop((guards, t));
+            executor.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_poke_after_opening_gate() {
+        let enabled = Arc::new(AtomicBool::new(false));
+        let their_enabled = Arc::clone(&enabled);
+        let mut t = Timer::builder()
+            .execution_gate(move |_| their_enabled.load(Ordering::SeqCst))
+            .build();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let _guard = t.schedule_poll(Duration::ZERO, move || {
+            let _ = tx.send(());
+            Duration::from_secs(3600)
+        });
+        // Vetoed, and so put back to be tried again after the loop time
+        std::thread::sleep(Duration::from_millis(20));
+        enabled.store(true, Ordering::SeqCst);
+        let poked = Instant::now();
+        t.poke();
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(poked.elapsed() < Timer::DEFAULT_LOOP_TIME / 2);
+    }
+
+    #[test]
+    fn test_shutdown_finalize_repeating_outcomes() {
+        use super::{TaskOutcome, TimerObserver};
+
+        #[derive(Default)]
+        struct Outcomes(Mutex<Vec<(u128, TaskOutcome)>>);
+
+        impl TimerObserver for Outcomes {
+            fn task_outcome(&self, task_id: u128, outcome: TaskOutcome) {
+                self.0.lock().unwrap().push((task_id, outcome));
+            }
+        }
+
+        let outcomes = Arc::new(Outcomes::default());
+        let vetoed_id = Arc::new(std::sync::OnceLock::new());
+        let their_vetoed_id = Arc::clone(&vetoed_id);
+        let mut t = Timer::builder()
+            .shutdown_mode(ShutdownMode::FinalizeRepeating(Duration::from_secs(1)))
+            .observer(Arc::clone(&outcomes))
+            .execution_gate(move |task| their_vetoed_id.get() != Some(&task.id()))
+            .build();
+        let flushed = t.schedule_repeating(Duration::from_secs(10), || {});
+        let vetoed = t.schedule_repeating(Duration::from_secs(10), || panic!("vetoed"));
+        vetoed_id.set(vetoed.task_id()).unwrap();
+        let one_off = t.schedule_in(Duration::from_secs(10), || panic!("not due"));
+        let completed = Arc::new(AtomicBool::new(false));
+        let their_completed = Arc::clone(&completed);
+        t.on_complete(flushed.task_id(), move || {
+            their_completed.store(true, Ordering::SeqCst)
+        });
+        let ids = [flushed.task_id(), vetoed.task_id(), one_off.task_id()];
+        flushed.detach();
+        vetoed.detach();
+        one_off.detach();
+        drop(t);
+        let mut outcomes = outcomes.0.lock().unwrap().clone();
+        // The repeating tasks are finalized in no particular order
+        outcomes.sort_by_key(|&(id, _)| id);
+        assert_eq!(
+            outcomes,
+            vec![
+                (ids[0], TaskOutcome::Completed),
+                (ids[1], TaskOutcome::GateVetoed),
+                (ids[2], TaskOutcome::ShutdownDropped),
+            ]
+        );
+        assert!(completed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_cleanup_reenters_timer() {
+        let mut t = Timer::new();
+        let (shared, _) = t.shared_parts();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let cleanup = || {
+            let shared = Arc::clone(&shared);
+            let seen = Arc::clone(&seen);
+            // Would deadlock if the task were dropped with the lock held
+            move || seen.lock().unwrap().push(shared.lock().tasks.len())
+        };
+        let guard = t.schedule_in_with_cleanup(Duration::from_secs(3600), || {}, cleanup());
+        t.schedule_in(Duration::from_secs(3600), || {}).detach();
+        drop(guard);
+        assert_eq!(t.purge_cancelled(), 1);
+        assert_eq!(*seen.lock().unwrap(), vec![1]);
+        drop(t.schedule_in_with_cleanup(Duration::from_secs(3600), || {}, cleanup()));
+        assert_eq!(t.take_tasks().len(), 1);
+        assert_eq!(*seen.lock().unwrap(), vec![1, 0]);
+    }
+
+    #[test]
+    fn test_max_batch_time_on_complete() {
+        let mut t = TimerBuilder::new()
+            .max_batch_time(Duration::from_millis(5))
+            .build();
+        // Paused, so that both tasks are taken in the same batch
+        t.pause();
+        t.schedule_in(Duration::ZERO, || {
+            std::thread::sleep(Duration::from_millis(50))
+        })
+        .detach();
+        let ran = Arc::new(AtomicBool::new(false));
+        let their_ran = Arc::clone(&ran);
+        let deferred = t.schedule_in(Duration::ZERO, move || {
+            their_ran.store(true, Ordering::SeqCst)
+        });
+        let completed_after_run = Arc::new(Mutex::new(None));
+        let their_completed = Arc::clone(&completed_after_run);
+        let their_ran = Arc::clone(&ran);
+        t.on_complete(deferred.task_id(), move || {
+            *their_completed.lock().unwrap() = Some(their_ran.load(Ordering::SeqCst));
+        });
+        deferred.detach();
+        std::thread::sleep(Duration::from_millis(10));
+        t.resume();
+        std::thread::sleep(Duration::from_millis(200));
+        // The second task is put back unrun once the batch runs out of time, and its callback
+        // must wait until it actually runs
+        assert!(ran.load(Ordering::SeqCst));
+        assert_eq!(*completed_after_run.lock().unwrap(), Some(true));
+    }
 }
@@ -0,0 +1,139 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use parking_lot::Mutex;
+
+use crate::builder::TimerBuilder;
+use crate::clock::Clock;
+use crate::executor::ExecutorRunner;
+use crate::timer::Timer;
+
+/// A clock which only moves when it is told to
+#[derive(Debug)]
+struct VirtualClock {
+    instant_origin: Instant,
+    system_origin: SystemTime,
+    elapsed: Mutex<Duration>,
+}
+
+impl Clock for VirtualClock {
+    fn system_now(&self) -> SystemTime {
+        self.system_origin + *self.elapsed.lock()
+    }
+
+    fn now(&self) -> Instant {
+        self.instant_origin + *self.elapsed.lock()
+    }
+}
+
+/// A [`Timer`] with no executor thread, running on virtual time, for deterministically testing
+/// code which schedules tasks. Time only moves when [`advance`](Self::advance) is called, and
+/// tasks only run (on the calling thread) when time is advanced or
+/// [`run_pending`](Self::run_pending) is called.
+///
+/// A `ManualTimer` dereferences to its [`Timer`], so tasks are scheduled on it as usual.
+///
+/// ```
+/// # use std::sync::atomic::{AtomicU32, Ordering};
+/// # use std::sync::Arc;
+/// # use std::time::Duration;
+/// let mut timer = synchronous_timer::ManualTimer::new();
+/// let runs = Arc::new(AtomicU32::new(0));
+/// let their_runs = Arc::clone(&runs);
+/// let _guard = timer.schedule_repeating(Duration::from_secs(60), move || {
+///     their_runs.fetch_add(1, Ordering::SeqCst);
+/// });
+/// timer.advance(Duration::from_secs(150));
+/// assert_eq!(runs.load(Ordering::SeqCst), 2);
+/// ```
+pub struct ManualTimer {
+    timer: Timer,
+    runner: ExecutorRunner,
+    clock: Arc<VirtualClock>,
+}
+
+impl ManualTimer {
+    /// Construct a new `ManualTimer` with the default settings
+    pub fn new() -> Self {
+        Self::with_builder(TimerBuilder::new())
+    }
+
+    /// Construct a new `ManualTimer` with the settings from the given builder. Its clock, number of
+    /// executor threads and executor backend are ignored.
+    pub fn with_builder(builder: TimerBuilder) -> Self {
+        let clock = Arc::new(VirtualClock {
+            instant_origin: Instant::now(),
+            system_origin: SystemTime::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+        });
+        let (timer, runner) = builder.clock(Arc::clone(&clock)).build_unstarted();
+        Self {
+            timer,
+            runner,
+            clock,
+        }
+    }
+
+    /// The current virtual time
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Move virtual time forward by the given duration, running every task which comes due along
+    /// the way at the time it is due (so a repeating task runs as many times as it would have in
+    /// real time). Returns the number of tasks run.
+    pub fn advance(&mut self, by: Duration) -> usize {
+        let target = self.now() + by;
+        let mut ran = self.run_pending();
+        loop {
+            match self.timer.next_due() {
+                Some(next) if next <= target => {
+                    self.set_now(next);
+                    ran += self.run_pending();
+                }
+                _ => break,
+            }
+        }
+        self.set_now(target);
+        ran
+    }
+
+    /// Run every task which is due at the current virtual time, returning the number of tasks
+    /// run. Cancelled tasks are discarded without being counted.
+    pub fn run_pending(&mut self) -> usize {
+        self.runner.run_due()
+    }
+
+    fn set_now(&self, now: Instant) {
+        let mut elapsed = self.clock.elapsed.lock();
+        *elapsed = (*elapsed).max(now.saturating_duration_since(self.clock.instant_origin));
+    }
+}
+
+impl Default for ManualTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::ops::Deref for ManualTimer {
+    type Target = Timer;
+
+    fn deref(&self) -> &Timer {
+        &self.timer
+    }
+}
+
+impl std::ops::DerefMut for ManualTimer {
+    fn deref_mut(&mut self) -> &mut Timer {
+        &mut self.timer
+    }
+}
+
+impl std::fmt::Debug for ManualTimer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ManualTimer")
+            .field("now", &self.now())
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,103 @@
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use crate::builder::Hook;
+
+/// What became of a task when it came due, as reported to
+/// [`TimerObserver::task_outcome`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TaskOutcome {
+    /// The task ran to completion
+    Completed,
+    /// The task panicked
+    Panicked,
+    /// The task had been cancelled, through its guard or by ID, so was discarded
+    CancelledByGuard,
+    /// The task was later than its time-to-live allowed, so was discarded; see
+    /// [`Timer::schedule_in_with_ttl`](crate::Timer::schedule_in_with_ttl)
+    Expired,
+    /// The timer was dropped while the task was still pending
+    ShutdownDropped,
+    /// The timer's execution gate (see
+    /// [`TimerBuilder::execution_gate`](crate::TimerBuilder::execution_gate)) skipped this run
+    GateVetoed,
+}
+
+/// Receives notifications of what a timer is doing, for instrumentation. Install one for a
+/// single timer with [`TimerBuilder::observer`](crate::TimerBuilder::observer), or for every
+/// timer in the process with [`set_global_observer`]. All methods do nothing by default, and are
+/// called on whichever thread the event happened on, so should be quick.
+pub trait TimerObserver: Send + Sync + 'static {
+    /// A task was scheduled
+    fn task_scheduled(&self, task_id: u128) {
+        let _ = task_id;
+    }
+
+    /// A task ran (without panicking), taking `duration`
+    fn task_executed(&self, task_id: u128, duration: Duration) {
+        let _ = (task_id, duration);
+    }
+
+    /// A task panicked
+    fn task_panicked(&self, task_id: u128) {
+        let _ = task_id;
+    }
+
+    /// A task was thrown away without running because it was already later than its time-to-live
+    /// allowed; see [`Timer::schedule_in_with_ttl`](crate::Timer::schedule_in_with_ttl)
+    fn task_expired(&self, task_id: u128) {
+        let _ = task_id;
+    }
+
+    /// A task came due and either ran or didn't, for the given reason; this is called as well as
+    /// the more specific methods above. A repeating task reports an outcome for each run. Tasks
+    /// which are discarded other than by the executor (for example, with
+    /// [`Timer::purge_cancelled`](crate::Timer::purge_cancelled)) aren't reported.
+    fn task_outcome(&self, task_id: u128, outcome: TaskOutcome) {
+        let _ = (task_id, outcome);
+    }
+}
+
+impl<O: TimerObserver + ?Sized> TimerObserver for Arc<O> {
+    fn task_scheduled(&self, task_id: u128) {
+        (**self).task_scheduled(task_id)
+    }
+
+    fn task_executed(&self, task_id: u128, duration: Duration) {
+        (**self).task_executed(task_id, duration)
+    }
+
+    fn task_panicked(&self, task_id: u128) {
+        (**self).task_panicked(task_id)
+    }
+
+    fn task_expired(&self, task_id: u128) {
+        (**self).task_expired(task_id)
+    }
+
+    fn task_outcome(&self, task_id: u128, outcome: TaskOutcome) {
+        (**self).task_outcome(task_id, outcome)
+    }
+}
+
+static GLOBAL_OBSERVER: OnceLock<Arc<dyn TimerObserver>> = OnceLock::new();
+
+/// Install an observer for every [`Timer`](crate::Timer) in the process which doesn't have its
+/// own; a timer built with [`TimerBuilder::observer`](crate::TimerBuilder::observer) only reports
+/// to that observer. Timers which already exist start reporting to the global observer straight
+/// away.
+///
+/// The global observer can only be set once, so this should be done at startup. If one has
+/// already been set, the given observer is handed back as an error.
+pub fn set_global_observer(observer: Arc<dyn TimerObserver>) -> Result<(), Arc<dyn TimerObserver>> {
+    GLOBAL_OBSERVER.set(observer)
+}
+
+/// The observer a timer should report to: its own, if it has one, or else the global one
+pub(crate) fn resolve(own: Option<&Hook<dyn TimerObserver>>) -> Option<&dyn TimerObserver> {
+    match own {
+        Some(observer) => Some(&*observer.0),
+        None => GLOBAL_OBSERVER.get().map(|observer| &**observer),
+    }
+}
@@ -0,0 +1,791 @@
+use std::collections::{BTreeMap, BinaryHeap, VecDeque};
+use std::time::{Duration, Instant};
+
+use smallvec::SmallVec;
+
+use crate::task::Task;
+
+/// Pending tasks grouped into coarse buckets by deadline; tasks within a bucket are kept in the
+/// order they were inserted
+#[derive(Debug)]
+pub(crate) struct Buckets {
+    origin: Instant,
+    granularity: Duration,
+    buckets: BTreeMap<i128, VecDeque<Task>>,
+    len: usize,
+}
+
+impl Buckets {
+    /// The bucket for a deadline, counting back from zero for deadlines before the origin
+    fn bucket(&self, deadline: Instant) -> i128 {
+        let nanos = match deadline.checked_duration_since(self.origin) {
+            Some(after) => after.as_nanos() as i128,
+            None => -((self.origin - deadline).as_nanos() as i128),
+        };
+        nanos.div_euclid(self.granularity.as_nanos() as i128)
+    }
+}
+
+/// Pending tasks in a hierarchical timing wheel. Level 0 has a slot for each tick, and each slot
+/// of a higher level spans a whole lap of the level below it. Tasks are moved down the levels as
+/// the wheel's cursor reaches them, so scheduling a task is O(1) however many are pending. Tasks
+/// due in the same tick are kept in the order they were inserted.
+#[derive(Debug)]
+pub(crate) struct Wheel {
+    origin: Instant,
+    tick_nanos: u64,
+    /// The number of slots on each level is `1 << bits`
+    bits: u32,
+    /// Indexed by level and then by slot. Only slots after the cursor's own slot on each level
+    /// are ever occupied, and the earliest task in a slot above level 0 is kept at its front.
+    levels: Vec<Vec<VecDeque<Task>>>,
+    /// Tasks due at or before the cursor, in order of deadline. Tasks due before the origin all
+    /// have tick 0, so this is the only place they are ordered.
+    due: VecDeque<Task>,
+    /// The tick the wheel has been advanced to
+    cursor: u64,
+    len: usize,
+}
+
+/// Where a task is kept in a [`Wheel`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WheelSlot {
+    Due,
+    /// A level and a slot on that level
+    At(usize, usize),
+}
+
+impl Wheel {
+    fn new(tick: Duration, slots: usize, origin: Instant) -> Self {
+        let bits = slots.trailing_zeros();
+        // Enough levels for the top one to span every possible tick
+        let levels = 64_u32.div_ceil(bits);
+        Self {
+            origin,
+            tick_nanos: u64::try_from(tick.as_nanos()).unwrap_or(u64::MAX).max(1),
+            bits,
+            levels: (0..levels)
+                .map(|_| (0..slots).map(|_| VecDeque::new()).collect())
+                .collect(),
+            due: VecDeque::new(),
+            cursor: 0,
+            len: 0,
+        }
+    }
+
+    /// Which lap of the level below the given tick is in, at the given level
+    fn block(&self, tick: u64, level: usize) -> u64 {
+        tick.checked_shr(self.bits * level as u32).unwrap_or(0)
+    }
+
+    fn index(&self, tick: u64, level: usize) -> usize {
+        let mask = (1 << self.bits) - 1;
+        self.block(tick, level) as usize & mask
+    }
+
+    fn tick(&self, deadline: Instant) -> u64 {
+        let nanos = deadline.saturating_duration_since(self.origin).as_nanos();
+        u64::try_from(nanos).unwrap_or(u64::MAX) / self.tick_nanos
+    }
+
+    /// Where a task due at the given tick belongs: the lowest level at which it is in the same
+    /// lap as the cursor
+    fn slot_for(&self, tick: u64) -> WheelSlot {
+        if tick <= self.cursor {
+            return WheelSlot::Due;
+        }
+        let highest_difference = 63 - (tick ^ self.cursor).leading_zeros();
+        let level = (highest_difference / self.bits) as usize;
+        WheelSlot::At(level, self.index(tick, level))
+    }
+
+    fn slot(&self, slot: WheelSlot) -> &VecDeque<Task> {
+        match slot {
+            WheelSlot::Due => &self.due,
+            WheelSlot::At(level, slot) => &self.levels[level][slot],
+        }
+    }
+
+    fn push(&mut self, task: Task) {
+        let tick = self.tick(task.next_execution());
+        match self.slot_for(tick) {
+            WheelSlot::Due => {
+                // After any task due at the same time, so that those stay in insertion order
+                let position = self
+                    .due
+                    .partition_point(|t| t.next_execution() <= task.next_execution());
+                self.due.insert(position, task);
+            }
+            WheelSlot::At(0, slot) => self.levels[0][slot].push_back(task),
+            WheelSlot::At(level, slot) => {
+                let front = self.levels[level][slot]
+                    .front()
+                    .map(|t| self.tick(t.next_execution()));
+                let tasks = &mut self.levels[level][slot];
+                // Only strictly earlier tasks go in front, so that tasks due in the same tick stay
+                // in the order they were inserted
+                if front.is_some_and(|front| tick < front) {
+                    tasks.push_front(task);
+                } else {
+                    tasks.push_back(task);
+                }
+            }
+        }
+        self.len += 1;
+    }
+
+    /// Find the slot holding the next task to run, which is at the front of it
+    fn front(&self) -> Option<WheelSlot> {
+        if !self.due.is_empty() {
+            return Some(WheelSlot::Due);
+        }
+        self.levels.iter().enumerate().find_map(|(level, slots)| {
+            let start = self.index(self.cursor, level) + 1;
+            let slot = (start..slots.len()).find(|&slot| !slots[slot].is_empty())?;
+            Some(WheelSlot::At(level, slot))
+        })
+    }
+
+    fn pop(&mut self) -> Option<Task> {
+        let task = match self.front()? {
+            WheelSlot::Due => self.due.pop_front(),
+            WheelSlot::At(level, slot) => {
+                let task = self.levels[level][slot].pop_front();
+                if level > 0 {
+                    // Bring the new earliest task to the front. This is O(n) in the size of the
+                    // slot, but the executor only ever takes tasks which are already due.
+                    let earliest = self.levels[level][slot]
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, t)| self.tick(t.next_execution()))
+                        .map(|(position, _)| position);
+                    if let Some(position) = earliest {
+                        let tasks = &mut self.levels[level][slot];
+                        let earliest = tasks.remove(position);
+                        tasks.extend(earliest);
+                        tasks.rotate_right(1);
+                    }
+                }
+                task
+            }
+        };
+        self.len -= 1;
+        task
+    }
+
+    /// Move the cursor forward to the given instant, moving any tasks it passes down to where
+    /// they now belong
+    fn advance(&mut self, now: Instant) {
+        let target = self.tick(now);
+        if target <= self.cursor {
+            return;
+        }
+        let mut moved = Vec::new();
+        for level in 0..self.levels.len() {
+            if self.block(target, level) == self.block(self.cursor, level) {
+                // Still in the same slot on this level, and so on every level above it
+                break;
+            }
+            let start = self.index(self.cursor, level) + 1;
+            let end = if self.block(target, level + 1) == self.block(self.cursor, level + 1) {
+                self.index(target, level) + 1
+            } else {
+                1 << self.bits
+            };
+            for slot in start..end {
+                moved.extend(self.levels[level][slot].drain(..));
+            }
+        }
+        self.cursor = target;
+        self.len -= moved.len();
+        moved.into_iter().for_each(|t| self.push(t));
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Task> + '_ {
+        self.due
+            .iter()
+            .chain(self.levels.iter().flatten().flatten())
+    }
+
+    fn take_all(&mut self) -> Vec<Task> {
+        self.len = 0;
+        let mut tasks = self.due.drain(..).collect::<Vec<_>>();
+        tasks.extend(
+            self.levels
+                .iter_mut()
+                .flatten()
+                .flat_map(|slot| slot.drain(..)),
+        );
+        tasks
+    }
+
+    fn is_ordered(&self) -> bool {
+        let mut len = 0;
+        let slots = std::iter::once(WheelSlot::Due).chain(self.levels.iter().enumerate().flat_map(
+            |(level, slots)| (0..slots.len()).map(move |slot| WheelSlot::At(level, slot)),
+        ));
+        for slot in slots {
+            let tasks = self.slot(slot);
+            len += tasks.len();
+            let ticks = tasks
+                .iter()
+                .map(|t| self.tick(t.next_execution()))
+                .collect::<Vec<_>>();
+            let earliest_first = match slot {
+                WheelSlot::Due => tasks
+                    .iter()
+                    .zip(tasks.iter().skip(1))
+                    .all(|(a, b)| a.next_execution() <= b.next_execution()),
+                WheelSlot::At(level, _) if level > 0 => ticks.iter().all(|&t| t >= ticks[0]),
+                WheelSlot::At(..) => true,
+            };
+            if !earliest_first || !ticks.iter().all(|&t| self.slot_for(t) == slot) {
+                return false;
+            }
+        }
+        len == self.len
+    }
+}
+
+/// How the pending tasks are ordered, as configured on the builder
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum QueueKind {
+    #[default]
+    Heap,
+    Bucketed(Duration),
+    Wheel {
+        tick: Duration,
+        slots: usize,
+    },
+}
+
+/// The priority class of a task. A due task is always run before any due task in a lower tier,
+/// even if that one has an earlier deadline, so critical work never waits behind a backlog of
+/// background work. Within a tier, tasks run in order of deadline as usual.
+///
+/// There is no aging between tiers: if the executor can't keep up with the higher tiers, tasks
+/// in the lower ones wait until it can.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Tier {
+    Critical,
+    #[default]
+    Normal,
+    Background,
+}
+
+/// The queue of pending tasks, ordered by tier and then by deadline
+#[derive(Debug)]
+pub(crate) struct TaskQueue {
+    normal: Lane,
+    /// Only created once a task is scheduled in these tiers, so that they cost nothing otherwise
+    critical: Option<Box<Lane>>,
+    background: Option<Box<Lane>>,
+    kind: QueueKind,
+    /// The time on the timer's clock when the queue was created, which bucketed and wheel lanes
+    /// measure deadlines from
+    origin: Instant,
+    /// Whether due tasks are run in submission order rather than deadline order
+    fifo: bool,
+    /// The FIFO sequence number to give the next task pushed which doesn't have one yet
+    next_seq: u64,
+}
+
+impl TaskQueue {
+    pub fn new(capacity: usize, kind: QueueKind, fifo: bool, origin: Instant) -> Self {
+        Self {
+            normal: Lane::new(capacity, kind, fifo, origin),
+            critical: None,
+            background: None,
+            kind,
+            origin,
+            fifo,
+            next_seq: 1,
+        }
+    }
+
+    /// The lanes which exist, highest tier first
+    fn lanes(&self) -> impl Iterator<Item = &Lane> + '_ {
+        let critical = self.critical.as_deref();
+        let background = self.background.as_deref();
+        critical
+            .into_iter()
+            .chain(std::iter::once(&self.normal))
+            .chain(background)
+    }
+
+    fn lanes_mut(&mut self) -> impl Iterator<Item = &mut Lane> + '_ {
+        let critical = self.critical.as_deref_mut();
+        let background = self.background.as_deref_mut();
+        critical
+            .into_iter()
+            .chain(std::iter::once(&mut self.normal))
+            .chain(background)
+    }
+
+    fn lane_mut(&mut self, tier: Tier) -> &mut Lane {
+        let (kind, fifo, origin) = (self.kind, self.fifo, self.origin);
+        match tier {
+            Tier::Critical => self
+                .critical
+                .get_or_insert_with(|| Box::new(Lane::new(0, kind, fifo, origin))),
+            Tier::Normal => &mut self.normal,
+            Tier::Background => self
+                .background
+                .get_or_insert_with(|| Box::new(Lane::new(0, kind, fifo, origin))),
+        }
+    }
+
+    /// In FIFO mode, give a newly-submitted task its place in submission order. Tasks which
+    /// already have one (such as repeating tasks coming back after a run) keep it.
+    fn sequence(fifo: bool, next_seq: &mut u64, task: &mut Task) {
+        if fifo && task.fifo_seq() == 0 {
+            task.set_fifo_seq(*next_seq);
+            *next_seq += 1;
+        }
+    }
+
+    fn has_tiers(&self) -> bool {
+        self.critical.is_some() || self.background.is_some()
+    }
+
+    /// Let the queue know the current time, which a timing wheel needs to move its tasks along
+    pub fn advance(&mut self, now: Instant) {
+        self.lanes_mut().for_each(|lane| lane.advance(now));
+    }
+
+    pub fn len(&self) -> usize {
+        self.lanes().map(Lane::len).sum()
+    }
+
+    pub fn push(&mut self, mut task: Task) {
+        Self::sequence(self.fifo, &mut self.next_seq, &mut task);
+        self.lane_mut(task.tier()).push(task)
+    }
+
+    /// The task with the earliest deadline, whatever its tier
+    #[cfg(feature = "test-util")]
+    pub fn peek(&self) -> Option<&Task> {
+        if !self.has_tiers() {
+            return self.normal.peek();
+        }
+        self.lanes().filter_map(Lane::peek).max()
+    }
+
+    /// The lane holding the task which should be run next at `now`: the highest tier with a task
+    /// due, or failing that, whichever holds the earliest deadline
+    fn next_lane(&self, now: Instant) -> Option<usize> {
+        let heads = self.lanes().map(Lane::peek).collect::<SmallVec<[_; 3]>>();
+        heads
+            .iter()
+            .position(|t| t.is_some_and(|t| t.next_execution() <= now))
+            .or_else(|| {
+                (0..heads.len())
+                    .filter(|&i| heads[i].is_some())
+                    .max_by(|&a, &b| heads[a].cmp(&heads[b]).then(b.cmp(&a)))
+            })
+    }
+
+    /// The task which should be run next at `now`
+    pub fn peek_at(&self, now: Instant) -> Option<&Task> {
+        if !self.has_tiers() {
+            return self.normal.peek();
+        }
+        self.lanes().nth(self.next_lane(now)?)?.peek()
+    }
+
+    /// Remove the task which should be run next at `now`
+    pub fn pop_at(&mut self, now: Instant) -> Option<Task> {
+        if !self.has_tiers() {
+            return self.normal.pop();
+        }
+        let lane = self.next_lane(now)?;
+        self.lanes_mut().nth(lane)?.pop()
+    }
+
+    /// Iterate over all pending tasks, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &Task> + '_ {
+        self.lanes().flat_map(Lane::iter)
+    }
+
+    /// All pending tasks, in the order [`pop_at`](Self::pop_at) would return them if they were
+    /// all popped at `now` (ignoring the coarser ordering of bucketed and wheel queues)
+    #[cfg(feature = "testing")]
+    pub fn pop_order(&self, now: Instant) -> Vec<&Task> {
+        let lanes = self
+            .lanes()
+            .map(|lane| lane.pop_order(now))
+            .collect::<SmallVec<[_; 3]>>();
+        let mut next = SmallVec::<[usize; 3]>::from_elem(0, lanes.len());
+        let mut order = Vec::with_capacity(self.len());
+        loop {
+            let heads = (0..lanes.len())
+                .map(|i| lanes[i].get(next[i]).copied())
+                .collect::<SmallVec<[_; 3]>>();
+            // As in `next_lane`
+            let lane = heads
+                .iter()
+                .position(|t| t.is_some_and(|t| t.next_execution() <= now))
+                .or_else(|| {
+                    (0..heads.len())
+                        .filter(|&i| heads[i].is_some())
+                        .max_by(|&a, &b| heads[a].cmp(&heads[b]).then(b.cmp(&a)))
+                });
+            let Some(lane) = lane else {
+                return order;
+            };
+            order.extend(heads[lane]);
+            next[lane] += 1;
+        }
+    }
+
+    /// Remove all of the pending tasks, in no particular order
+    pub fn take_all(&mut self) -> Vec<Task> {
+        let mut tasks = self.normal.take_all();
+        for lane in [&mut self.critical, &mut self.background] {
+            if let Some(lane) = lane.as_deref_mut() {
+                tasks.extend(lane.take_all());
+            }
+        }
+        tasks
+    }
+
+    pub fn extend<I: IntoIterator<Item = Task>>(&mut self, tasks: I) {
+        let mut others = Vec::new();
+        let (fifo, next_seq) = (self.fifo, &mut self.next_seq);
+        self.normal.extend(tasks.into_iter().filter_map(|mut t| {
+            if t.tier() == Tier::Normal {
+                Self::sequence(fifo, next_seq, &mut t);
+                Some(t)
+            } else {
+                others.push(t);
+                None
+            }
+        }));
+        others.into_iter().for_each(|t| self.push(t));
+    }
+
+    /// Check that the queue's ordering is consistent, and that every task is in its own tier
+    pub fn is_ordered(&self) -> bool {
+        let tiers = [
+            (Tier::Critical, self.critical.as_deref()),
+            (Tier::Normal, Some(&self.normal)),
+            (Tier::Background, self.background.as_deref()),
+        ];
+        tiers.into_iter().all(|(tier, lane)| {
+            lane.is_none_or(|lane| lane.is_ordered() && lane.iter().all(|t| t.tier() == tier))
+        })
+    }
+
+    /// Roughly how much memory the queue's own storage takes up, not counting anything owned by
+    /// the tasks in it
+    pub fn approx_bytes(&self) -> usize {
+        let tiers = [&self.critical, &self.background]
+            .into_iter()
+            .flatten()
+            .map(|lane| lane.approx_bytes())
+            .sum::<usize>();
+        std::mem::size_of::<Self>() - std::mem::size_of::<Lane>()
+            + self.normal.approx_bytes()
+            + tiers
+    }
+}
+
+/// The pending tasks of a single [`Tier`], ordered by deadline
+#[derive(Debug)]
+struct Lane {
+    /// A task pushed while the queue was empty, which bypasses `ordered`. In the common case of
+    /// one task at a time being scheduled on an idle timer, this avoids touching the ordered
+    /// queue at all.
+    slot: Option<Task>,
+    ordered: Ordered,
+    /// In FIFO mode, tasks which have come due, keyed by their place in submission order. Each
+    /// task moves here once, when [`advance`](Self::advance) finds it due, and is run from here
+    /// ahead of anything still in the deadline-ordered queue.
+    due: Option<BTreeMap<u64, Task>>,
+}
+
+#[derive(Debug)]
+enum Ordered {
+    /// Exact ordering by deadline (and then by ID)
+    Heap(BinaryHeap<Task>),
+    /// Ordering by deadline only to within the bucket granularity, which makes inserting and
+    /// removing tasks cheaper for very large queues
+    Bucketed(Buckets),
+    /// Ordering by deadline only to within the tick of a timing wheel, which makes inserting
+    /// tasks O(1) for very large queues
+    Wheel(Wheel),
+}
+
+impl Lane {
+    fn new(capacity: usize, kind: QueueKind, fifo: bool, origin: Instant) -> Self {
+        Self {
+            slot: None,
+            ordered: Ordered::new(capacity, kind, origin),
+            due: fifo.then(BTreeMap::new),
+        }
+    }
+
+    /// Let the queue know the current time, which a timing wheel needs to move its tasks along,
+    /// and which in FIFO mode moves the tasks which have come due into submission order
+    fn advance(&mut self, now: Instant) {
+        if let Ordered::Wheel(wheel) = &mut self.ordered {
+            wheel.advance(now);
+        }
+        if self.due.is_some() {
+            while self
+                .peek_by_deadline()
+                .is_some_and(|t| t.next_execution() <= now)
+            {
+                if let (Some(task), Some(due)) = (self.pop_by_deadline(), self.due.as_mut()) {
+                    due.insert(task.fifo_seq(), task);
+                }
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.ordered.len()
+            + usize::from(self.slot.is_some())
+            + self.due.as_ref().map_or(0, BTreeMap::len)
+    }
+
+    fn push(&mut self, task: Task) {
+        if self.slot.is_none() && self.ordered.len() == 0 {
+            self.slot = Some(task);
+        } else {
+            self.ordered.push(task);
+        }
+    }
+
+    /// Whether the slotted task (if any) should be run before anything in the ordered queue
+    fn slot_first(&self) -> bool {
+        match (&self.slot, self.ordered.peek()) {
+            (Some(slot), Some(next)) => slot >= next,
+            (slot, _) => slot.is_some(),
+        }
+    }
+
+    /// The task which should be run next
+    fn peek(&self) -> Option<&Task> {
+        match self.due.as_ref().and_then(BTreeMap::first_key_value) {
+            Some((_, task)) => Some(task),
+            None => self.peek_by_deadline(),
+        }
+    }
+
+    fn pop(&mut self) -> Option<Task> {
+        match self.due.as_mut().and_then(BTreeMap::pop_first) {
+            Some((_, task)) => Some(task),
+            None => self.pop_by_deadline(),
+        }
+    }
+
+    /// The task with the earliest deadline which hasn't yet been moved into `due`
+    fn peek_by_deadline(&self) -> Option<&Task> {
+        if self.slot_first() {
+            self.slot.as_ref()
+        } else {
+            self.ordered.peek()
+        }
+    }
+
+    fn pop_by_deadline(&mut self) -> Option<Task> {
+        if self.slot_first() {
+            self.slot.take()
+        } else {
+            self.ordered.pop()
+        }
+    }
+
+    /// Iterate over all pending tasks, in no particular order
+    fn iter(&self) -> impl Iterator<Item = &Task> + '_ {
+        let due = self.due.iter().flat_map(BTreeMap::values);
+        self.slot.iter().chain(self.ordered.iter()).chain(due)
+    }
+
+    /// All pending tasks, in the order they would be popped after advancing to `now`
+    #[cfg(feature = "testing")]
+    fn pop_order(&self, now: Instant) -> Vec<&Task> {
+        let mut by_deadline = self
+            .slot
+            .iter()
+            .chain(self.ordered.iter())
+            .collect::<Vec<_>>();
+        // Tasks compare greatest-first for the heap
+        by_deadline.sort_by(|a, b| b.cmp(a));
+        let Some(due) = &self.due else {
+            return by_deadline;
+        };
+        // In FIFO mode, everything which is due runs first, in submission order
+        let (newly_due, later): (Vec<_>, Vec<_>) = by_deadline
+            .into_iter()
+            .partition(|t| t.next_execution() <= now);
+        let mut order = due.values().chain(newly_due).collect::<Vec<_>>();
+        order.sort_by_key(|t| t.fifo_seq());
+        order.extend(later);
+        order
+    }
+
+    /// Remove all of the pending tasks, in no particular order
+    fn take_all(&mut self) -> Vec<Task> {
+        let mut tasks = self.ordered.take_all();
+        tasks.extend(self.slot.take());
+        if let Some(due) = self.due.as_mut() {
+            tasks.extend(std::mem::take(due).into_values());
+        }
+        tasks
+    }
+
+    fn extend<I: IntoIterator<Item = Task>>(&mut self, tasks: I) {
+        self.ordered.extend(tasks)
+    }
+
+    /// Check that the queue's ordering is consistent
+    fn is_ordered(&self) -> bool {
+        self.ordered.is_ordered()
+            && self
+                .due
+                .iter()
+                .flatten()
+                .all(|(&seq, task)| task.fifo_seq() == seq)
+    }
+
+    /// Roughly how much memory the queue's own storage takes up, not counting anything owned by
+    /// the tasks in it
+    fn approx_bytes(&self) -> usize {
+        let task = std::mem::size_of::<Task>();
+        let slots = match &self.ordered {
+            Ordered::Heap(heap) => heap.capacity() * task,
+            Ordered::Bucketed(buckets) => buckets
+                .buckets
+                .values()
+                .map(|b| std::mem::size_of::<(i128, VecDeque<Task>)>() + b.capacity() * task)
+                .sum(),
+            Ordered::Wheel(wheel) => {
+                let slots = wheel.levels.iter().flatten();
+                wheel.due.capacity() * task
+                    + slots
+                        .map(|s| std::mem::size_of::<VecDeque<Task>>() + s.capacity() * task)
+                        .sum::<usize>()
+            }
+        };
+        let due = self
+            .due
+            .as_ref()
+            .map_or(0, |due| due.len() * std::mem::size_of::<(u64, Task)>());
+        std::mem::size_of::<Self>() + slots + due
+    }
+}
+
+impl Ordered {
+    fn new(capacity: usize, kind: QueueKind, origin: Instant) -> Self {
+        match kind {
+            QueueKind::Bucketed(granularity) => Self::Bucketed(Buckets {
+                origin,
+                granularity: granularity.max(Duration::from_nanos(1)),
+                buckets: BTreeMap::new(),
+                len: 0,
+            }),
+            QueueKind::Wheel { tick, slots } => Self::Wheel(Wheel::new(tick, slots, origin)),
+            // Avoid allocating if there's no capacity requested
+            QueueKind::Heap if capacity == 0 => Self::Heap(BinaryHeap::new()),
+            QueueKind::Heap => Self::Heap(BinaryHeap::with_capacity(capacity)),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Self::Heap(heap) => heap.len(),
+            Self::Bucketed(buckets) => buckets.len,
+            Self::Wheel(wheel) => wheel.len,
+        }
+    }
+
+    fn push(&mut self, task: Task) {
+        match self {
+            Self::Heap(heap) => heap.push(task),
+            Self::Bucketed(buckets) => {
+                let bucket = buckets.bucket(task.next_execution());
+                buckets.buckets.entry(bucket).or_default().push_back(task);
+                buckets.len += 1;
+            }
+            Self::Wheel(wheel) => wheel.push(task),
+        }
+    }
+
+    fn peek(&self) -> Option<&Task> {
+        match self {
+            Self::Heap(heap) => heap.peek(),
+            Self::Bucketed(buckets) => buckets.buckets.values().next().and_then(|b| b.front()),
+            Self::Wheel(wheel) => wheel.slot(wheel.front()?).front(),
+        }
+    }
+
+    fn pop(&mut self) -> Option<Task> {
+        match self {
+            Self::Heap(heap) => heap.pop(),
+            Self::Bucketed(buckets) => {
+                let mut first = buckets.buckets.first_entry()?;
+                let task = first.get_mut().pop_front();
+                if first.get().is_empty() {
+                    first.remove();
+                }
+                buckets.len -= 1;
+                task
+            }
+            Self::Wheel(wheel) => wheel.pop(),
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Task> + '_ {
+        let (heap, buckets, wheel) = match self {
+            Self::Heap(heap) => (Some(heap.iter()), None, None),
+            Self::Bucketed(buckets) => (None, Some(buckets.buckets.values().flatten()), None),
+            Self::Wheel(wheel) => (None, None, Some(wheel.iter())),
+        };
+        heap.into_iter()
+            .flatten()
+            .chain(buckets.into_iter().flatten())
+            .chain(wheel.into_iter().flatten())
+    }
+
+    fn take_all(&mut self) -> Vec<Task> {
+        match self {
+            Self::Heap(heap) => std::mem::take(heap).into_vec(),
+            Self::Bucketed(buckets) => {
+                buckets.len = 0;
+                std::mem::take(&mut buckets.buckets)
+                    .into_values()
+                    .flatten()
+                    .collect()
+            }
+            Self::Wheel(wheel) => wheel.take_all(),
+        }
+    }
+
+    fn extend<I: IntoIterator<Item = Task>>(&mut self, tasks: I) {
+        match self {
+            Self::Heap(heap) => heap.extend(tasks),
+            Self::Bucketed(_) | Self::Wheel(_) => tasks.into_iter().for_each(|t| self.push(t)),
+        }
+    }
+
+    fn is_ordered(&self) -> bool {
+        match self {
+            Self::Heap(heap) => {
+                // The heap is a max-heap, so every task should sort no lower than its children
+                let tasks = heap.as_slice();
+                (1..tasks.len()).all(|i| tasks[(i - 1) / 2] >= tasks[i])
+            }
+            Self::Bucketed(buckets) => {
+                buckets.buckets.iter().all(|(bucket, tasks)| {
+                    !tasks.is_empty()
+                        && tasks
+                            .iter()
+                            .all(|t| buckets.bucket(t.next_execution()) == *bucket)
+                }) && buckets.buckets.values().map(VecDeque::len).sum::<usize>() == buckets.len
+            }
+            Self::Wheel(wheel) => wheel.is_ordered(),
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::panic::{AssertUnwindSafe, RefUnwindSafe};
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::error::TaskError;
+
+#[derive(Debug)]
+struct ResultSlot<T> {
+    value: Mutex<Option<Result<T, TaskError>>>,
+    ready: Condvar,
+}
+
+impl<T> ResultSlot<T> {
+    /// Fill the slot, unless it has already been filled
+    fn fill(&self, value: Result<T, TaskError>) {
+        let mut slot = self.value.lock();
+        if slot.is_none() {
+            *slot = Some(value);
+            self.ready.notify_all();
+        }
+    }
+}
+
+/// What a task scheduled with
+/// [`Timer::schedule_repeating_result_channel`](crate::Timer::schedule_repeating_result_channel) does with a
+/// value when the channel is full or the receiver has gone away
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Throw the value away and carry on running the task
+    #[default]
+    DropResult,
+    /// Wait for the receiver to make room, holding up the executor (and so every other task)
+    /// until it does. If the receiver has gone away, or the timer shuts down while waiting, the
+    /// value is thrown away.
+    Block,
+    /// Stop running the task. The receiver gets the values already in the channel, then sees it
+    /// disconnect.
+    Error,
+}
+
+/// The sending half of a [`TaskResult`], which is moved into the task. If it is dropped without
+/// sending (because the task was discarded or panicked), the receiver gets an error.
+pub(crate) struct ResultSender<T> {
+    // Nothing is observed after a panic except through `fill`, which takes the lock afresh
+    slot: AssertUnwindSafe<Arc<ResultSlot<T>>>,
+    sent: bool,
+}
+
+impl<T> ResultSender<T> {
+    pub fn send(mut self, value: T) {
+        self.slot.fill(Ok(value));
+        self.sent = true;
+    }
+
+    /// Report that the task was cancelled, for use when the task's guard is dropped
+    pub fn canceller(&self) -> impl Fn() + RefUnwindSafe + Send + Sync + 'static
+    where
+        T: Send + 'static,
+    {
+        let slot = AssertUnwindSafe(Arc::clone(&self.slot));
+        move || slot.fill(Err(TaskError::Cancelled))
+    }
+}
+
+impl<T> Drop for ResultSender<T> {
+    fn drop(&mut self) {
+        if !self.sent {
+            let err = if std::thread::panicking() {
+                TaskError::Panicked
+            } else {
+                TaskError::Cancelled
+            };
+            self.slot.fill(Err(err));
+        }
+    }
+}
+
+/// The eventual result of a task scheduled with
+/// [`Timer::schedule_in_returning`](crate::Timer::schedule_in_returning)
+#[derive(Debug)]
+pub struct TaskResult<T> {
+    slot: Arc<ResultSlot<T>>,
+}
+
+impl<T> TaskResult<T> {
+    pub(crate) fn channel() -> (ResultSender<T>, Self) {
+        let slot = Arc::new(ResultSlot {
+            value: Mutex::new(None),
+            ready: Condvar::new(),
+        });
+        let sender = ResultSender {
+            slot: AssertUnwindSafe(Arc::clone(&slot)),
+            sent: false,
+        };
+        (sender, Self { slot })
+    }
+
+    /// Block until the task has run (or been cancelled), and return its result
+    pub fn recv(self) -> Result<T, TaskError> {
+        let mut value = self.slot.value.lock();
+        loop {
+            if let Some(value) = value.take() {
+                return value;
+            }
+            self.slot.ready.wait(&mut value);
+        }
+    }
+
+    /// Like [`recv`](Self::recv), but give up after `timeout`, returning `None`
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Result<T, TaskError>> {
+        let mut value = self.slot.value.lock();
+        if value.is_none() {
+            self.slot
+                .ready
+                .wait_while_for(&mut value, |v| v.is_none(), timeout);
+        }
+        value.take()
+    }
+}
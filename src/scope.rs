@@ -0,0 +1,58 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Default)]
+pub(crate) struct ScopeState {
+    cancelled: AtomicBool,
+    parent: Option<Arc<ScopeState>>,
+}
+
+impl ScopeState {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+            || self.parent.as_ref().is_some_and(|p| p.is_cancelled())
+    }
+}
+
+/// A `Scope` groups together tasks for cancellation; when it is dropped, all tasks scheduled
+/// within it (with [`Timer::schedule_in_scope`](crate::Timer::schedule_in_scope) or
+/// [`Timer::schedule_repeating_in_scope`](crate::Timer::schedule_repeating_in_scope)) are
+/// cancelled, as are all tasks in any child scopes created with [`child`](Self::child).
+#[derive(Debug)]
+pub struct Scope {
+    state: Arc<ScopeState>,
+}
+
+impl Scope {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: Arc::default(),
+        }
+    }
+
+    /// Create a new scope nested within this one. Dropping this scope will cancel all tasks in the
+    /// child scope, but the child scope can also be dropped independently.
+    pub fn child(&self) -> Scope {
+        Self {
+            state: Arc::new(ScopeState {
+                cancelled: AtomicBool::new(false),
+                parent: Some(Arc::clone(&self.state)),
+            }),
+        }
+    }
+
+    /// Returns whether this scope (or any of its parents) has been cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.state.is_cancelled()
+    }
+
+    pub(crate) fn state(&self) -> Arc<ScopeState> {
+        Arc::clone(&self.state)
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        self.state.cancelled.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,138 @@
+use std::panic::UnwindSafe;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex};
+
+/// A stripped-down timer which runs a single repeating task on its own background thread. This
+/// is a cheaper alternative to a [`Timer`](crate::Timer) for the common case of a single
+/// periodic job, such as a heartbeat: there is no queue, no guards, and nothing to configure.
+///
+/// As with a `Timer`, a panic in the task is caught and logged, and the task is not run again.
+/// The background thread is shut down (and joined) by [`stop`](Self::stop) or on drop, neither
+/// of which interrupts a run which is already in progress.
+pub struct SingleTimer {
+    shared: Arc<SingleShared>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+struct SingleShared {
+    state: Mutex<SingleState>,
+    changed: Condvar,
+}
+
+struct SingleState {
+    interval: Duration,
+    next: Instant,
+    /// Set by [`SingleTimer::trigger_now`] to run the task as soon as possible
+    triggered: bool,
+    stopped: bool,
+}
+
+impl SingleTimer {
+    /// Start running `f` every `interval`, starting one interval from now
+    pub fn new<F: FnMut() + UnwindSafe + Send + 'static>(interval: Duration, f: F) -> Self {
+        let shared = Arc::new(SingleShared {
+            state: Mutex::new(SingleState {
+                interval,
+                next: Instant::now() + interval,
+                triggered: false,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let their_shared = Arc::clone(&shared);
+        let thread = std::thread::Builder::new()
+            .name("single-timer".into())
+            .spawn(move || their_shared.run(f))
+            .unwrap();
+        Self {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Stop running the task, waiting for a run which is in progress to finish. Does nothing if
+    /// the timer has already been stopped.
+    pub fn stop(&mut self) {
+        self.shared.state.lock().stopped = true;
+        self.shared.changed.notify_one();
+        if let Some(thread) = self.thread.take() {
+            if let Err(e) = thread.join() {
+                log::error!("Error joining timer thread: {:?}", e);
+            }
+        }
+    }
+
+    /// Returns whether the timer has been stopped, either with [`stop`](Self::stop) or because
+    /// the task panicked
+    pub fn is_stopped(&self) -> bool {
+        self.shared.state.lock().stopped
+    }
+
+    /// Change the interval between runs. The next run is rescheduled for one new interval from
+    /// now.
+    pub fn set_interval(&self, interval: Duration) {
+        let mut state = self.shared.state.lock();
+        state.interval = interval;
+        state.next = Instant::now() + interval;
+        drop(state);
+        self.shared.changed.notify_one();
+    }
+
+    /// Get the current interval between runs
+    pub fn interval(&self) -> Duration {
+        self.shared.state.lock().interval
+    }
+
+    /// Run the task as soon as possible (or as soon as the current run has finished), rather than
+    /// waiting for the rest of the interval. The following run is one interval after this one.
+    pub fn trigger_now(&self) {
+        self.shared.state.lock().triggered = true;
+        self.shared.changed.notify_one();
+    }
+}
+
+impl SingleShared {
+    fn run<F: FnMut() + UnwindSafe>(&self, mut f: F) {
+        let mut state = self.state.lock();
+        loop {
+            if state.stopped {
+                return;
+            }
+            let now = Instant::now();
+            if !state.triggered && now < state.next {
+                let next = state.next;
+                self.changed.wait_until(&mut state, next);
+                continue;
+            }
+            state.triggered = false;
+            state.next = now + state.interval;
+            drop(state);
+            // The closure is never run again if it panics, so it's fine to assert unwind safety
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(&mut f));
+            state = self.state.lock();
+            if let Err(e) = result {
+                log::error!("uncaught panic when running task: {:?}", e);
+                state.stopped = true;
+            }
+        }
+    }
+}
+
+impl Drop for SingleTimer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl std::fmt::Debug for SingleTimer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.shared.state.lock();
+        f.debug_struct("SingleTimer")
+            .field("interval", &state.interval)
+            .field("next", &state.next)
+            .field("stopped", &state.stopped)
+            .finish()
+    }
+}
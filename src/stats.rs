@@ -0,0 +1,33 @@
+use std::time::Duration;
+
+/// Execution statistics for a single repeating task, as returned by
+/// [`Timer::task_stats`](crate::Timer::task_stats)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TaskStats {
+    /// The number of times this task has been run to completion
+    pub runs: u64,
+    /// The mean time taken by a single run of this task
+    pub avg_duration: Duration,
+    /// The longest time taken by a single run of this task
+    pub max_duration: Duration,
+}
+
+impl TaskStats {
+    pub(crate) fn record(&mut self, duration: Duration) {
+        self.runs += 1;
+        // Incremental mean, so that we never have to keep a (potentially overflowing) total around
+        let avg = self.avg_duration.as_secs_f64();
+        let avg = avg + (duration.as_secs_f64() - avg) / self.runs as f64;
+        self.avg_duration = Duration::from_secs_f64(avg.max(0.0));
+        self.max_duration = self.max_duration.max(duration);
+    }
+}
+
+/// The outcome of [`Timer::shutdown_draining_timeout`](crate::Timer::shutdown_draining_timeout)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DrainReport {
+    /// The number of due tasks which were run before the timer shut down
+    pub drained: usize,
+    /// The number of due tasks which were dropped without running because the budget ran out
+    pub abandoned: usize,
+}
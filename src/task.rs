@@ -1,17 +1,112 @@
-use std::panic::UnwindSafe;
+use std::any::Any;
+use std::collections::HashMap;
+use std::panic::{AssertUnwindSafe, RefUnwindSafe, UnwindSafe};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock, Weak};
+use std::task::Waker;
 use std::time::{Duration, Instant};
 
+use parking_lot::{Condvar, Mutex};
+
+use crate::builder::{Hook, TaskWrapper};
+use crate::clock::Clock;
+use crate::queue::Tier;
+use crate::scope::ScopeState;
+use crate::timer::Timer;
+
+/// The state shared between a task and its [`TaskGuard`]
+#[derive(Debug, Default)]
+struct GuardState {
+    dropped: AtomicBool,
+    /// Set by [`TaskGuard::cancel_with_grace`]; the task is cancelled if it isn't due by then
+    cancel_unless_due_by: OnceLock<Instant>,
+    finished: Mutex<Finished>,
+    finished_changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Finished {
+    finished: bool,
+    waker: Option<Waker>,
+}
+
+impl GuardState {
+    fn is_dropped(&self) -> bool {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Whether a task which is next due at `next_execution` has been cancelled
+    fn is_cancelled_at(&self, next_execution: Instant) -> bool {
+        self.is_dropped()
+            || self
+                .cancel_unless_due_by
+                .get()
+                .is_some_and(|&due_by| next_execution > due_by)
+    }
+
+    fn cancel(&self) {
+        self.dropped.store(true, Ordering::Relaxed);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.finished.lock().finished
+    }
+}
+
+// The state is only ever accessed under its lock, which leaves it consistent even if a task
+// panics
+impl std::panic::RefUnwindSafe for GuardState {}
+
+/// The task's end of its [`GuardState`], which marks the task as finished when the task is
+/// discarded (whether because it ran, was cancelled, or panicked)
+#[derive(Debug, Default)]
+struct GuardLink(Arc<GuardState>);
+
+impl GuardLink {
+    fn state(&self) -> Arc<GuardState> {
+        Arc::clone(&self.0)
+    }
+}
+
+impl Drop for GuardLink {
+    fn drop(&mut self) {
+        let waker = {
+            let mut finished = self.0.finished.lock();
+            finished.finished = true;
+            self.0.finished_changed.notify_all();
+            finished.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 struct TaskState {
-    running: Arc<AtomicBool>,
-    dropped: Arc<AtomicBool>,
+    running: AtomicBool,
+    /// Shared with the task's guard; `None` for lightweight tasks, which can't be cancelled
+    guard: Option<GuardLink>,
+    /// Set for a repeating task which should not be rescheduled after its next run
+    last_run: AtomicBool,
 }
 
+/// The boxed form in which one-off tasks are stored
+pub type BoxedTask = Box<dyn FnOnce() + UnwindSafe + Send + 'static>;
+
+/// The boxed form in which repeating tasks are stored
+pub type BoxedRepeatingTask = Box<dyn FnMut() + UnwindSafe + Send + 'static>;
+
+/// Called when a task is cancelled through its guard or by ID
+pub(crate) type CancelHook = dyn Fn() + RefUnwindSafe + Send + Sync;
+
+pub(crate) type BoxedPollTask = Box<dyn FnMut() -> Duration + UnwindSafe + Send + 'static>;
+
 pub(crate) enum TaskCallable {
-    Once(Box<dyn FnOnce() + UnwindSafe + Send + 'static>),
-    Repeating(Box<dyn FnMut() + UnwindSafe + Send + 'static>, Duration),
+    Once(BoxedTask),
+    Repeating(BoxedRepeatingTask, Duration),
+    /// A repeating task which returns the delay until it should next be run
+    Poll(BoxedPollTask),
 }
 
 impl TaskCallable {
@@ -25,6 +120,47 @@ impl TaskCallable {
     ) -> Self {
         Self::Repeating(Box::new(f), interval)
     }
+
+    pub fn new_poll<F: FnMut() -> Duration + UnwindSafe + Send + 'static>(f: F) -> Self {
+        Self::Poll(Box::new(f))
+    }
+
+    /// Apply a user-provided wrapper to this callable. A repeating callable can't be passed to the
+    /// wrapper directly, so instead every run is passed through the wrapper.
+    pub fn wrap(self, wrapper: &Arc<TaskWrapper>) -> Self {
+        match self {
+            Self::Once(f) => Self::Once(wrapper(f)),
+            Self::Repeating(f, interval) => {
+                // The task is discarded if it panics, so it's fine to assert unwind safety here
+                let state = AssertUnwindSafe((Arc::clone(wrapper), Arc::new(Mutex::new(f))));
+                Self::new_repeating(
+                    move || {
+                        let (wrapper, f) = &*state;
+                        let f = Arc::clone(f);
+                        wrapper(Box::new(AssertUnwindSafe(move || (f.lock())())))()
+                    },
+                    interval,
+                )
+            }
+            Self::Poll(f) => {
+                // If the wrapper doesn't actually run the task, keep the previous delay
+                let state = AssertUnwindSafe((
+                    Arc::clone(wrapper),
+                    Arc::new(Mutex::new((f, Duration::ZERO))),
+                ));
+                Self::new_poll(move || {
+                    let (wrapper, f) = &*state;
+                    let inner = Arc::clone(f);
+                    wrapper(Box::new(AssertUnwindSafe(move || {
+                        let mut inner = inner.lock();
+                        inner.1 = (inner.0)();
+                    })))();
+                    let delay = f.lock().1;
+                    delay
+                })
+            }
+        }
+    }
 }
 
 impl std::fmt::Debug for TaskCallable {
@@ -32,10 +168,177 @@ impl std::fmt::Debug for TaskCallable {
         match self {
             Self::Once(_) => write!(f, "TaskCallable::Once(<unformattable>)"),
             Self::Repeating(_, i) => write!(f, "TaskCallable::Repeating(<unformattable>, {:?})", i),
+            Self::Poll(_) => write!(f, "TaskCallable::Poll(<unformattable>)"),
         }
     }
 }
 
+/// A callable which is run on the executor right after every `every`th run of the task it is
+/// attached to
+pub(crate) struct Follower {
+    task_id: u128,
+    every: u64,
+    count: u64,
+    guard: GuardLink,
+    f: BoxedRepeatingTask,
+}
+
+impl Follower {
+    pub fn new<F: FnMut() + UnwindSafe + Send + 'static>(task_id: u128, every: u64, f: F) -> Self {
+        Self {
+            task_id,
+            every,
+            count: 0,
+            guard: GuardLink::default(),
+            f: Box::new(f),
+        }
+    }
+
+    pub fn guard(&self) -> TaskGuard {
+        TaskGuard::new(self.task_id, self.guard.state(), None)
+    }
+
+    pub fn task_id(&self) -> u128 {
+        self.task_id
+    }
+
+    /// Note that the task this is attached to has run. Returns `false` if this follower is
+    /// finished and should be removed.
+    fn tick(&mut self) -> bool {
+        if self.guard.0.is_dropped() {
+            return false;
+        }
+        self.count += 1;
+        if self.count < self.every {
+            return true;
+        }
+        self.count = 0;
+        // The closure is thrown away if it panics, so it's fine to assert unwind safety here
+        match std::panic::catch_unwind(AssertUnwindSafe(|| (self.f)())) {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("uncaught panic when running follower task: {:?}", e);
+                false
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Follower {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Follower")
+            .field("task_id", &self.task_id)
+            .field("every", &self.every)
+            .field("count", &self.count)
+            .finish()
+    }
+}
+
+/// A slot counted against a per-key limit on pending tasks, which is released when the task
+/// holding it is discarded
+#[derive(Debug)]
+pub(crate) struct LimitPermit {
+    key: String,
+    // The counts are only changed under their lock, and never while running a task
+    limits: AssertUnwindSafe<Weak<Limits>>,
+}
+
+/// The number of pending tasks for each key passed to
+/// [`Timer::schedule_in_limited`](crate::Timer::schedule_in_limited); a key is removed once it
+/// has none
+pub(crate) type Limits = Mutex<HashMap<String, usize>>;
+
+impl LimitPermit {
+    /// Take a slot for `key`, unless it has already reached `limit`
+    pub fn acquire(limits: &Arc<Limits>, key: &str, limit: usize) -> Option<Self> {
+        let mut counts = limits.lock();
+        let count = counts.get(key).copied().unwrap_or(0);
+        if count >= limit {
+            return None;
+        }
+        counts.insert(key.to_owned(), count + 1);
+        Some(Self {
+            key: key.to_owned(),
+            limits: AssertUnwindSafe(Arc::downgrade(limits)),
+        })
+    }
+}
+
+impl Drop for LimitPermit {
+    fn drop(&mut self) {
+        let Some(limits) = self.limits.upgrade() else {
+            return;
+        };
+        let mut counts = limits.lock();
+        if let Some(count) = counts.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// A closure which is run when the task holding it is discarded, for whatever reason
+pub(crate) struct Cleanup(Option<AssertUnwindSafe<Box<dyn FnOnce() + Send>>>);
+
+impl Cleanup {
+    /// The closure is only ever called once, from `drop`, so it doesn't matter if it is left in a
+    /// broken state by a panic
+    pub fn new<F: FnOnce() + Send + 'static>(f: F) -> Self {
+        Self(Some(AssertUnwindSafe(Box::new(f))))
+    }
+}
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        let Some(f) = self.0.take() else {
+            return;
+        };
+        // This may be running while a panicking task unwinds, when a second panic would abort
+        if let Err(e) = std::panic::catch_unwind(f) {
+            log::error!("uncaught panic in task cleanup: {:?}", e);
+        }
+    }
+}
+
+impl std::fmt::Debug for Cleanup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Cleanup").finish_non_exhaustive()
+    }
+}
+
+/// Optional per-task settings
+#[derive(Debug, Default)]
+pub(crate) struct TaskOptions {
+    pub scope: Option<Arc<ScopeState>>,
+    /// Only held so that it's released when the task is discarded
+    #[allow(dead_code)]
+    pub limit: Option<LimitPermit>,
+    /// Only held so that it runs when the task is discarded
+    #[allow(dead_code)]
+    pub cleanup: Option<Cleanup>,
+    /// How late the task can run before it is thrown away instead
+    pub ttl: Option<Duration>,
+    /// The task must never run before this instant on the real monotonic clock, whatever its
+    /// deadline says
+    pub not_before: Option<Instant>,
+    /// Hold the task out of the queue until the queue has fewer tasks than this
+    pub when_below: Option<usize>,
+    pub tier: Tier,
+    /// Skip allocating the state shared with the guard, so that the task can't be cancelled
+    pub lightweight: bool,
+    /// The key this task was scheduled under, for [`Timer::boost_key`]
+    pub key: Option<String>,
+    /// User-provided data attached to the task. The task itself has its own handle to this, so
+    /// it is only ever read through here.
+    pub context: Option<AssertUnwindSafe<Arc<dyn Any + Send + Sync>>>,
+    pub on_cancel: Option<Hook<CancelHook>>,
+    /// Breaks ties between tasks with the same deadline before the task ID does, so that tasks
+    /// from different fairness keys are interleaved
+    pub fair_rank: u64,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub(crate) enum Ready {
     Now,
@@ -44,62 +347,273 @@ pub(crate) enum Ready {
 
 #[derive(Debug)]
 pub(crate) struct Task {
-    task_id: u64,
+    task_id: u128,
     next_execution: Instant,
     task: TaskState,
     callable: TaskCallable,
+    followers: Vec<Follower>,
+    options: TaskOptions,
+    /// Position in submission order, assigned by the queue in FIFO mode; zero until then
+    fifo_seq: u64,
 }
 
 impl Task {
-    pub fn new(task_id: u64, next_execution: Instant, callable: TaskCallable) -> Self {
+    pub fn new(
+        task_id: u128,
+        next_execution: Instant,
+        callable: TaskCallable,
+        options: TaskOptions,
+    ) -> Self {
+        let task = TaskState {
+            guard: (!options.lightweight).then(GuardLink::default),
+            ..TaskState::default()
+        };
         Self {
             task_id,
             next_execution,
-            task: TaskState::default(),
+            task,
             callable,
+            followers: Vec::new(),
+            options,
+            fifo_seq: 0,
         }
     }
 
     /// Run this task. If there is a "next_execution", return a new TaskHandle with the fields
-    /// updated
-    pub fn run(self) -> Option<Task> {
-        let task_id = self.task_id;
-        let task = self.task;
+    /// updated according to `clock`
+    pub fn run(self, clock: &dyn Clock) -> Option<Task> {
+        let Task {
+            task_id,
+            task,
+            callable,
+            mut followers,
+            options,
+            fifo_seq,
+            ..
+        } = self;
         let was_running = task.running.swap(true, Ordering::Acquire);
         if was_running {
             log::error!("encountered a running task (a.k.a. a panic); not running again");
             return None;
         }
-        match self.callable {
+        match callable {
             TaskCallable::Repeating(mut f, interval) => {
-                let next_execution = Instant::now() + interval;
+                let next_execution = clock.now() + interval;
                 f();
+                followers.retain_mut(Follower::tick);
                 task.running.store(false, Ordering::Release);
+                if task.last_run.load(Ordering::Relaxed) {
+                    return None;
+                }
                 Some(Task {
                     task_id,
                     next_execution,
                     task,
                     callable: TaskCallable::Repeating(f, interval),
+                    followers,
+                    options,
+                    fifo_seq,
+                })
+            }
+            TaskCallable::Poll(mut f) => {
+                let delay = f();
+                followers.retain_mut(Follower::tick);
+                task.running.store(false, Ordering::Release);
+                if task.last_run.load(Ordering::Relaxed) {
+                    return None;
+                }
+                Some(Task {
+                    task_id,
+                    next_execution: clock.now() + delay,
+                    task,
+                    callable: TaskCallable::Poll(f),
+                    followers,
+                    options,
+                    fifo_seq,
                 })
             }
             TaskCallable::Once(f) => {
                 f();
+                followers.retain_mut(Follower::tick);
                 task.running.store(false, Ordering::Release);
                 None
             }
         }
     }
 
-    pub fn id(&self) -> u64 {
+    pub fn id(&self) -> u128 {
         self.task_id
     }
 
+    /// Which kind of task this is (one-off, repeating or polled), for telling tasks apart
+    #[cfg(feature = "testing")]
+    pub fn kind(&self) -> std::mem::Discriminant<TaskCallable> {
+        std::mem::discriminant(&self.callable)
+    }
+
+    /// Skip this run of the task without running it. A repeating task is returned to be put back
+    /// in the queue, due one interval after `now` (or after `retry`, if it has no fixed interval).
+    pub fn skip(mut self, now: Instant, retry: Duration) -> Option<Task> {
+        self.next_execution = match self.callable {
+            TaskCallable::Once(_) => return None,
+            TaskCallable::Repeating(_, interval) => now + interval,
+            TaskCallable::Poll(_) => now + retry,
+        };
+        Some(self)
+    }
+
+    /// If this is a repeating task which was due before `now`, skip the missed run and make it
+    /// next due one interval after `now`. Returns whether the task was rescheduled.
+    pub fn skip_missed(&mut self, now: Instant) -> bool {
+        match self.callable {
+            TaskCallable::Repeating(_, interval) if self.next_execution < now => {
+                self.next_execution = now + interval;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Change the interval of a repeating task; returns `false` if this isn't a repeating task
+    pub fn set_interval(&mut self, new_interval: Duration) -> bool {
+        match &mut self.callable {
+            TaskCallable::Repeating(_, interval) => {
+                *interval = new_interval;
+                true
+            }
+            TaskCallable::Once(_) | TaskCallable::Poll(_) => false,
+        }
+    }
+
+    /// The IDs of the followers attached to this task
+    pub fn follower_ids(&self) -> impl Iterator<Item = u128> + '_ {
+        self.followers.iter().map(Follower::task_id)
+    }
+
+    pub fn add_followers<I: IntoIterator<Item = Follower>>(&mut self, followers: I) {
+        self.followers.extend(followers);
+    }
+
+    pub fn next_execution(&self) -> Instant {
+        self.next_execution
+    }
+
+    pub fn fifo_seq(&self) -> u64 {
+        self.fifo_seq
+    }
+
+    pub fn set_fifo_seq(&mut self, fifo_seq: u64) {
+        self.fifo_seq = fifo_seq;
+    }
+
+    pub fn key(&self) -> Option<&str> {
+        self.options.key.as_deref()
+    }
+
+    pub fn context(&self) -> Option<&Arc<dyn Any + Send + Sync>> {
+        self.options.context.as_ref().map(|c| &c.0)
+    }
+
+    pub fn set_id(&mut self, task_id: u128) {
+        self.task_id = task_id;
+    }
+
+    pub fn set_next_execution(&mut self, next_execution: Instant) {
+        self.next_execution = next_execution;
+    }
+
+    /// Roughly how much heap memory this task owns beyond the `Task` itself: its boxed closure
+    /// and the state shared with its guard and those of its followers. Anything owned by the
+    /// closures can't be seen, so this is a lower bound.
+    pub fn approx_heap_bytes(&self) -> usize {
+        // An `Arc` allocation holds the two reference counts as well as the value
+        let guard_bytes = std::mem::size_of::<GuardState>() + 2 * std::mem::size_of::<usize>();
+        let callable = match &self.callable {
+            TaskCallable::Once(f) => std::mem::size_of_val(&**f),
+            TaskCallable::Repeating(f, _) => std::mem::size_of_val(&**f),
+            TaskCallable::Poll(f) => std::mem::size_of_val(&**f),
+        };
+        let followers = self.followers.capacity() * std::mem::size_of::<Follower>()
+            + self
+                .followers
+                .iter()
+                .map(|f| std::mem::size_of_val(&*f.f) + guard_bytes)
+                .sum::<usize>();
+        let guard = if self.task.guard.is_some() {
+            guard_bytes
+        } else {
+            0
+        };
+        callable + followers + guard
+    }
+
+    pub fn is_repeating(&self) -> bool {
+        matches!(
+            self.callable,
+            TaskCallable::Repeating(..) | TaskCallable::Poll(_)
+        )
+    }
+
+    pub fn tier(&self) -> Tier {
+        self.options.tier
+    }
+
+    /// Whether this task is now too late to be worth running; see
+    /// [`Timer::schedule_in_with_ttl`](crate::Timer::schedule_in_with_ttl)
+    pub fn expired(&self, now: Instant) -> bool {
+        self.options
+            .ttl
+            .is_some_and(|ttl| now.saturating_duration_since(self.next_execution) > ttl)
+    }
+
     pub fn dropped(&self) -> bool {
-        self.task.dropped.load(Ordering::Relaxed)
+        self.task
+            .guard
+            .as_ref()
+            .is_some_and(|g| g.0.is_cancelled_at(self.next_execution))
+            || self
+                .options
+                .scope
+                .as_ref()
+                .is_some_and(|s| s.is_cancelled())
+    }
+
+    /// Stop rescheduling this repeating task after its next run; returns `false` if this isn't a
+    /// repeating task
+    pub fn stop_after_next(&self) -> bool {
+        if self.is_repeating() {
+            self.task.last_run.store(true, Ordering::Relaxed);
+        }
+        self.is_repeating()
+    }
+
+    /// Mark this task as cancelled; returns `false` if this is a lightweight task, which can't be
+    /// cancelled
+    pub fn cancel(&self) -> bool {
+        let Some(guard) = &self.task.guard else {
+            return false;
+        };
+        guard.0.cancel();
+        if let Some(on_cancel) = &self.options.on_cancel {
+            (on_cancel.0)();
+        }
+        true
+    }
+
+    pub fn when_below(&self) -> Option<usize> {
+        self.options.when_below
+    }
+
+    /// If this task has a floor which hasn't been reached yet, how much longer it must wait
+    pub fn held_for(&self) -> Option<Duration> {
+        self.options
+            .not_before
+            .and_then(|floor| floor.checked_duration_since(Instant::now()))
+            .filter(|d| !d.is_zero())
     }
 
     pub fn ready(&self, now: Instant) -> Ready {
-        if now > self.next_execution {
+        if now >= self.next_execution {
             Ready::Now
         } else {
             Ready::In(self.next_execution - now)
@@ -107,7 +621,17 @@ impl Task {
     }
 
     pub fn guard(&self) -> TaskGuard {
-        TaskGuard::new(self.task_id, Arc::clone(&self.task.dropped))
+        match &self.task.guard {
+            Some(guard) => {
+                TaskGuard::new(self.task_id, guard.state(), self.options.on_cancel.clone())
+            }
+            None => TaskGuard {
+                task_id: self.task_id,
+                state: None,
+                on_cancel: None,
+                armed: false,
+            },
+        }
     }
 }
 
@@ -125,10 +649,14 @@ impl PartialOrd<Task> for Task {
 
 impl Eq for Task {}
 
+/// Tasks are ordered by deadline, then by fairness rank, then by ID, with the "greatest" task
+/// being the one to run first. Overdue tasks always sort first, so none can be starved.
 impl Ord for Task {
     fn cmp(&self, other: &Task) -> std::cmp::Ordering {
         match self.next_execution.cmp(&other.next_execution).reverse() {
-            std::cmp::Ordering::Equal => self.task_id.cmp(&other.task_id).reverse(),
+            std::cmp::Ordering::Equal => (self.options.fair_rank, self.task_id)
+                .cmp(&(other.options.fair_rank, other.task_id))
+                .reverse(),
             other => other,
         }
     }
@@ -137,34 +665,328 @@ impl Ord for Task {
 #[derive(Debug)]
 /// A `TaskGuard` represents a handle to a future task. When it is dropped, we will attempt to cancel that task. If you would like the task to continue running in the background, use the `.detach()` method
 pub struct TaskGuard {
-    task_id: u64,
-    dropped: Option<Arc<AtomicBool>>,
+    task_id: u128,
+    state: Option<Arc<GuardState>>,
+    on_cancel: Option<Hook<CancelHook>>,
+    /// Whether dropping this guard cancels the task
+    armed: bool,
 }
 
 impl TaskGuard {
-    fn new(task_id: u64, dropped: Arc<AtomicBool>) -> Self {
+    fn new(task_id: u128, state: Arc<GuardState>, on_cancel: Option<Hook<CancelHook>>) -> Self {
         Self {
             task_id,
-            dropped: Some(dropped),
+            state: Some(state),
+            on_cancel,
+            armed: true,
+        }
+    }
+
+    /// A guard which isn't attached to any task, for when scheduling failed
+    pub(crate) fn inert() -> Self {
+        Self {
+            task_id: 0,
+            state: None,
+            on_cancel: None,
+            armed: false,
         }
     }
 
-    /// Get the ID of the underlying task, for debugging
-    pub fn task_id(&self) -> u64 {
+    /// Get the ID of the underlying task, for debugging. This is zero if the task could not be
+    /// scheduled.
+    pub fn task_id(&self) -> u128 {
         self.task_id
     }
 
+    /// Push back the firing of this (not yet fired) task so that it happens `new_delay` from
+    /// now instead of at its original time, as if it had been cancelled and scheduled afresh.
+    /// This is the classic "kick the watchdog" operation. The task is given a new ID, which will be
+    /// reflected in [`task_id`](Self::task_id). Returns `false` if the task has already run or
+    /// been cancelled.
+    ///
+    /// This has to rebuild the timer's queue, so is O(n) in the number of pending tasks.
+    pub fn re_arm(&mut self, timer: &Timer, new_delay: Duration) -> bool {
+        if self.state.is_none() {
+            return false;
+        }
+        match timer.re_arm_task(self.task_id, new_delay) {
+            Some(task_id) => {
+                self.task_id = task_id;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Block until the task has finished: that is, until a one-off task has run or a repeating
+    /// task has stopped. A cancelled task only counts as finished once the executor discards it,
+    /// which happens when it comes due (or when the timer is dropped). Returns immediately if the
+    /// task could not be scheduled or was scheduled as a lightweight task.
+    pub fn wait(&self) {
+        if let Some(state) = &self.state {
+            let mut finished = state.finished.lock();
+            while !finished.finished {
+                state.finished_changed.wait(&mut finished);
+            }
+        }
+    }
+
+    /// Returns whether the task has finished (see [`wait`](Self::wait))
+    pub fn is_finished(&self) -> bool {
+        self.state.as_ref().is_none_or(|s| s.is_finished())
+    }
+
+    /// Make dropping this guard cancel the task. This is only needed for guards returned by a
+    /// timer built with [`TimerBuilder::detach_by_default`](crate::TimerBuilder::detach_by_default),
+    /// since otherwise guards are armed from the start.
+    pub fn arm(mut self) -> Self {
+        self.armed = self.state.is_some();
+        self
+    }
+
+    pub(crate) fn disarm(&mut self) {
+        self.armed = false;
+    }
+
     /// Detach this `TaskGuard` from the underlying `Task` so that dropping this guard will no
     /// longer cancel the task.
     pub fn detach(mut self) {
-        self.dropped.take();
+        self.state.take();
+        self.on_cancel.take();
+    }
+
+    /// Get a function which cancels the task without needing this guard, for tasks which stop
+    /// themselves
+    pub(crate) fn canceller(&self) -> impl Fn() + RefUnwindSafe + Send + Sync + 'static {
+        let state = self.state.clone();
+        move || {
+            if let Some(state) = &state {
+                state.cancel();
+            }
+        }
+    }
+
+    /// Get a function which tells whether the task has been cancelled, which the task itself can
+    /// use to stop waiting for something
+    pub(crate) fn cancelled_check(
+        &self,
+    ) -> impl Fn() -> bool + RefUnwindSafe + Send + Sync + 'static {
+        let state = self.state.clone();
+        move || state.as_ref().is_some_and(|state| state.is_dropped())
+    }
+
+    /// Cancel the task unless it is due to run within `grace` from now on `timer`'s clock, in
+    /// which case it is allowed to run anyway (a repeating task is allowed to run until it is next
+    /// due after that). This avoids cancelling work which is about to happen regardless.
+    pub fn cancel_with_grace(mut self, timer: &Timer, grace: Duration) {
+        if let Some(state) = self.state.take() {
+            let _ = state.cancel_unless_due_by.set(timer.now() + grace);
+        }
+        self.on_cancel.take();
     }
 }
 
 impl Drop for TaskGuard {
     fn drop(&mut self) {
-        if let Some(dropped) = self.dropped.take() {
-            dropped.store(true, Ordering::Relaxed);
+        if !self.armed {
+            return;
+        }
+        if let Some(state) = self.state.take() {
+            state.cancel();
+            if let Some(on_cancel) = self.on_cancel.take() {
+                (on_cancel.0)();
+            }
+        }
+    }
+}
+
+/// With the `async` feature, a `TaskGuard` can be awaited, which resolves once the task has
+/// finished (see [`TaskGuard::wait`]). Dropping the guard before then still cancels the task.
+#[cfg(feature = "async")]
+impl std::future::Future for TaskGuard {
+    type Output = ();
+
+    fn poll(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        let Some(state) = &self.state else {
+            return std::task::Poll::Ready(());
+        };
+        let mut finished = state.finished.lock();
+        if finished.finished {
+            std::task::Poll::Ready(())
+        } else {
+            finished.waker = Some(cx.waker().clone());
+            std::task::Poll::Pending
         }
     }
 }
+
+/// An opaque bundle of tasks which have been taken out of a [`Timer`] with
+/// [`Timer::take_tasks`], for putting back (into the same or another timer) with
+/// [`Timer::put_tasks`]
+#[derive(Debug, Default)]
+pub struct TaskBundle {
+    tasks: Vec<Task>,
+}
+
+impl TaskBundle {
+    pub(crate) fn new(tasks: Vec<Task>) -> Self {
+        Self { tasks }
+    }
+
+    pub(crate) fn into_tasks(self) -> Vec<Task> {
+        self.tasks
+    }
+
+    /// The number of tasks in this bundle
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Whether this bundle contains no tasks
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+}
+
+/// The state shared between the instances of a task scheduled with [`Timer::schedule_race`] and
+/// its [`RaceHandle`]
+#[derive(Debug, Default)]
+pub(crate) struct RaceState {
+    /// The index of the timer whose instance started running first
+    winner: OnceLock<usize>,
+    /// The guards for every instance, until there is a winner or the race is cancelled
+    guards: Mutex<Vec<TaskGuard>>,
+}
+
+// The guards are only taken out of their lock, never left half-modified, so they stay
+// consistent even if a task panics
+impl RefUnwindSafe for RaceState {}
+
+impl RaceState {
+    /// Called by each instance as it starts running, returning whether it won the race. The
+    /// winner cancels every other instance.
+    pub fn claim(&self, index: usize) -> bool {
+        if self.winner.set(index).is_err() {
+            return false;
+        }
+        self.finish(std::mem::take(&mut *self.guards.lock()));
+        true
+    }
+
+    pub fn set_guards(&self, guards: Vec<TaskGuard>) {
+        let mut slot = self.guards.lock();
+        if self.winner.get().is_some() {
+            // An instance started running before all of them had been scheduled
+            drop(slot);
+            self.finish(guards);
+        } else {
+            *slot = guards;
+        }
+    }
+
+    /// Cancel every instance other than the winner
+    fn finish(&self, guards: Vec<TaskGuard>) {
+        let winner = self.winner.get().copied();
+        for (index, guard) in guards.into_iter().enumerate() {
+            if Some(index) == winner {
+                guard.detach();
+            }
+        }
+    }
+}
+
+/// A handle to a task scheduled on several timers with [`Timer::schedule_race`]. When it is
+/// dropped, every instance of the task which hasn't started running yet is cancelled, unless
+/// [`detach`](Self::detach) has been called.
+#[derive(Debug)]
+pub struct RaceHandle {
+    state: Option<Arc<RaceState>>,
+}
+
+impl RaceHandle {
+    pub(crate) fn new(state: Arc<RaceState>) -> Self {
+        Self { state: Some(state) }
+    }
+
+    /// The index (into the timers passed to [`Timer::schedule_race`]) of the timer whose
+    /// instance of the task ran, if one has started running yet
+    pub fn winner(&self) -> Option<usize> {
+        self.state.as_ref()?.winner.get().copied()
+    }
+
+    /// Let the race run to completion in the background, even if this handle is dropped
+    pub fn detach(mut self) {
+        self.state.take();
+    }
+}
+
+impl Drop for RaceHandle {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take() {
+            std::mem::take(&mut *state.guards.lock());
+        }
+    }
+}
+
+/// Passed to a task scheduled with
+/// [`Timer::schedule_repeating_yielding`](crate::Timer::schedule_repeating_yielding), for it to
+/// yield the executor thread between chunks of work
+#[derive(Debug, Default)]
+pub struct Yielder {
+    yielded: bool,
+}
+
+impl Yielder {
+    /// Let any other tasks which are due run, and then run this task again straight afterwards to
+    /// continue its work. The task should return promptly after calling this.
+    pub fn yield_and_reschedule(&mut self) {
+        self.yielded = true;
+    }
+
+    pub(crate) fn yielded(&self) -> bool {
+        self.yielded
+    }
+}
+
+/// A view of a task which is about to run, as passed to the
+/// [`TimerBuilder::execution_gate`](crate::TimerBuilder::execution_gate)
+#[derive(Debug)]
+pub struct PendingTask<'a> {
+    task: &'a Task,
+}
+
+impl<'a> PendingTask<'a> {
+    pub(crate) fn new(task: &'a Task) -> Self {
+        Self { task }
+    }
+
+    /// The task's ID
+    pub fn id(&self) -> u128 {
+        self.task.id()
+    }
+
+    /// The key the task was scheduled with, if any (see [`Timer::schedule_in_limited`] and
+    /// [`Timer::schedule_fair`])
+    pub fn key(&self) -> Option<&'a str> {
+        self.task.key()
+    }
+
+    /// The context the task was scheduled with, if any (see
+    /// [`Timer::schedule_in_with_context`])
+    pub fn context(&self) -> Option<&'a Arc<dyn Any + Send + Sync>> {
+        self.task.context()
+    }
+
+    /// Whether this is a repeating task
+    pub fn is_repeating(&self) -> bool {
+        self.task.is_repeating()
+    }
+
+    /// When the task was due to run
+    pub fn due(&self) -> Instant {
+        self.task.next_execution()
+    }
+}
@@ -1,40 +1,427 @@
-use std::collections::BinaryHeap;
-use std::panic::UnwindSafe;
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::panic::{AssertUnwindSafe, UnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant, SystemTime};
 
-use parking_lot::{Condvar, Mutex};
+use parking_lot::{Condvar, Mutex, MutexGuard};
 
-use crate::executor::Executor;
-use crate::task::{Task, TaskCallable, TaskGuard};
+use crate::builder::{Hook, PanicPolicy, PastPolicy, TimerBuilder, TimerConfig};
+use crate::clock::{Clock, PausableClock};
+use crate::error::{ClockError, InvariantViolation, ScheduleError};
+use crate::executor::{self, BatchSizes, Executor, ExecutorRunner, TaskSlot, WakeReason};
+use crate::instrument;
+use crate::observer::{self, TaskOutcome};
+use crate::queue::{TaskQueue, Tier};
+use crate::result::{OverflowPolicy, TaskResult};
+use crate::scope::Scope;
+use crate::stats::{DrainReport, TaskStats};
+use crate::task::{
+    BoxedRepeatingTask, BoxedTask, Cleanup, Follower, LimitPermit, Limits, RaceHandle, RaceState,
+    Ready, Task, TaskBundle, TaskCallable, TaskGuard, TaskOptions, Yielder,
+};
 
 /// The main structure of this library, a `Timer` handles scheduling one-off and repeating tasks,
 /// which are executed on a background thread. Tasks should be short-lived (as they block the
 /// thread) synchronous functions.
 pub struct Timer {
-    executor_thread: Option<std::thread::JoinHandle<()>>,
+    executor_threads: Mutex<Vec<ExecutorThread>>,
     shared: Arc<Mutex<TimerShared>>,
     changed: Arc<Condvar>,
+    completed: Arc<Condvar>,
+    #[cfg(feature = "async")]
+    async_changed: Option<Arc<tokio::sync::Notify>>,
+    config: TimerConfig,
+    /// The same clock as `config.clock`, kept here so that it can be paused
+    clock: Arc<PausableClock>,
+    /// Whether the timer started its own executor threads, rather than handing out an
+    /// [`ExecutorRunner`] or running on a Tokio runtime
+    owns_executor: bool,
 }
 
-pub(crate) struct TimerShared {
-    pub tasks: BinaryHeap<Task>,
-    pub done: bool,
-    pub next_id: u64,
+pub(crate) type CompletionCallback = Box<dyn FnOnce() + Send>;
+
+// The longest a task blocked on a full result channel with `OverflowPolicy::Block` waits before
+// trying again, which also bounds how long it takes to notice that it has been cancelled
+const MAX_BLOCK_BACKOFF: Duration = Duration::from_millis(50);
+
+/// The generation to give the next timer (or restarted timer) in this process
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+fn next_generation() -> u64 {
+    NEXT_GENERATION.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Build a task ID from a timer's generation and its per-generation counter
+fn compose_id(generation: u64, counter: u64) -> u128 {
+    (u128::from(generation) << 64) | u128::from(counter)
+}
+
+/// Counts down as the tasks scheduled by [`Timer::run_n_blocking`] finish
+#[derive(Default)]
+struct Countdown {
+    remaining: Mutex<usize>,
+    finished: Condvar,
+}
+
+// The count is only ever accessed under its lock, which leaves it consistent even if a task
+// panics
+impl std::panic::RefUnwindSafe for Countdown {}
+
+/// Held by each task scheduled by [`Timer::run_n_blocking`], and counts down when the task is
+/// dropped, whether because it ran, panicked, or was discarded
+struct CountdownTick(Arc<Countdown>);
+
+impl Drop for CountdownTick {
+    fn drop(&mut self) {
+        let mut remaining = self.0.remaining.lock();
+        *remaining -= 1;
+        if *remaining == 0 {
+            self.0.finished.notify_all();
+        }
+    }
+}
+
+/// An executor thread spawned by the timer
+struct ExecutorThread {
+    handle: std::thread::JoinHandle<()>,
+    /// Set to make the thread exit without shutting down the timer
+    retired: Arc<AtomicBool>,
+}
+
+impl ExecutorThread {
+    fn spawn(runner: ExecutorRunner) -> Self {
+        let retired = runner.retired();
+        let handle = std::thread::Builder::new()
+            .name("timer-executor".into())
+            .spawn(|| runner.run())
+            .unwrap();
+        Self { handle, retired }
+    }
+
+    fn join(self) {
+        if let Err(e) = self.handle.join() {
+            log::error!("Error joining timer thread: {:?}", e);
+        }
+    }
+}
+
+/// The state shared between a [`Timer`] and its executor. This can be obtained with
+/// [`Timer::shared_parts`] to observe the state of the timer from elsewhere.
+pub struct TimerShared {
+    pub(crate) tasks: TaskQueue,
+    pub(crate) done: bool,
+    pub(crate) accepting: bool,
+    /// Distinguishes IDs handed out by this timer (since it was last restarted) from those of
+    /// every other timer in the process; it forms the high 64 bits of each task ID
+    pub(crate) generation: u64,
+    /// The counter which forms the low 64 bits of the next task ID
+    pub(crate) next_id: u64,
+    /// Incremented whenever the queue is changed, so that the executor can tell whether it needs
+    /// to rescan
+    pub(crate) epoch: u64,
+    pub(crate) stats: HashMap<u128, TaskStats>,
+    pub(crate) watermark: Option<Watermark>,
+    /// IDs of tasks which have been taken out of the queue by the executor and not yet returned
+    pub(crate) in_flight: Vec<u128>,
+    /// The subset of `in_flight` which are repeating tasks
+    pub(crate) in_flight_repeating: HashSet<u128>,
+    /// Followers which were attached to a task while it was in flight, to be attached when it is
+    /// returned to the queue
+    pub(crate) pending_followers: HashMap<u128, Vec<Follower>>,
+    /// Repeating tasks which were in flight when they were cancelled by
+    /// [`Timer::replace_all_repeating`], to be cancelled when they are returned to the queue
+    pub(crate) cancel_on_return: HashSet<u128>,
+    /// Tasks whose last run was vetoed by the execution gate and which were put back in the
+    /// queue, to be brought forward by [`Timer::poke`]
+    pub(crate) gated: HashSet<u128>,
+    /// Callbacks registered with [`Timer::on_complete`], to be invoked when the task with the
+    /// given ID next finishes
+    pub(crate) completion_callbacks: HashMap<u128, Vec<CompletionCallback>>,
+    /// How many times the executor has had to rescan because the queue changed before it slept
+    pub(crate) rescans: u64,
+    /// How many times the executor was woken while waiting even though nothing had changed
+    pub(crate) spurious_wakeups: u64,
+    /// The number of pending tasks for each key passed to [`Timer::schedule_in_limited`]. This
+    /// has its own lock so that a task can release its slot when it is discarded, which may
+    /// happen while the main lock is held.
+    pub(crate) limits: Arc<Limits>,
+    /// The state of each fairness key with pending tasks from [`Timer::schedule_fair`]. This has
+    /// its own lock so that a task can remove its key's entry when it is discarded, which may
+    /// happen while the main lock is held.
+    pub(crate) fairness: Arc<Mutex<HashMap<String, FairnessCount>>>,
+    /// Why the executor last woke up from waiting
+    pub(crate) last_wake: Option<WakeReason>,
+    /// Set by [`Timer::pause`]; the executor runs nothing while this is set
+    pub(crate) paused: bool,
+    /// Shared with every executor, which records the size of each batch it runs without taking
+    /// the lock
+    pub(crate) batch_sizes: Arc<BatchSizes>,
+    /// One slot for each executor, in which it records the task it is running without taking the
+    /// lock
+    pub(crate) current_tasks: Vec<Arc<TaskSlot>>,
+    /// The threads currently running [`ExecutorRunner::run`] for this timer
+    pub(crate) executor_thread_ids: Vec<std::thread::ThreadId>,
+    /// Tasks from [`Timer::schedule_when_below`], held out of the queue until it has fewer tasks
+    /// than their threshold
+    pub(crate) deferred: Vec<Task>,
+    /// How many calls to [`Timer::flush`] are waiting
+    pub(crate) flushing: usize,
+    /// IDs of tasks which have left the timer for good (having run or been discarded) since the
+    /// oldest waiting flush began, so that each flush can tell when its tasks are done without
+    /// rescanning the queue. Only recorded while a flush is waiting.
+    pub(crate) finished: Vec<u128>,
+}
+
+/// For one fairness key, the most recent deadline it scheduled a task for, how many tasks it has
+/// scheduled for that deadline, and how many of its tasks are still pending
+#[derive(Debug)]
+pub(crate) struct FairnessCount {
+    deadline: Instant,
+    scheduled: u64,
+    pending: usize,
+}
+
+pub(crate) struct Watermark {
+    high: usize,
+    low: usize,
+    crossed: bool,
+    callback: Arc<dyn Fn(usize) + Send + Sync>,
+}
+
+impl Watermark {
+    /// Check whether the queue length has gone over the high watermark, returning the callback to
+    /// invoke if this is a new crossing
+    fn check_high(&mut self, len: usize) -> Option<Arc<dyn Fn(usize) + Send + Sync>> {
+        if !self.crossed && len > self.high {
+            self.crossed = true;
+            Some(Arc::clone(&self.callback))
+        } else {
+            None
+        }
+    }
+
+    pub fn check_low(&mut self, len: usize) {
+        if self.crossed && len < self.low {
+            self.crossed = false;
+        }
+    }
 }
 
 impl TimerShared {
+    /// The number of tasks in the queue, including cancelled tasks which haven't been cleaned up
+    /// yet, but not including tasks which are currently being executed
+    pub fn task_count(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Whether the timer has been shut down
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Whether the timer is accepting new tasks (see [`Timer::stop_accepting`])
+    pub fn is_accepting(&self) -> bool {
+        self.accepting
+    }
+
+    /// The ID which will be assigned to the next scheduled task
+    pub fn next_id(&self) -> u128 {
+        compose_id(self.generation, self.next_id)
+    }
+
+    /// Apply `f` to the pending task with the given ID, re-establishing the heap ordering
+    /// afterwards. Returns whatever `f` returned, or `false` if no such task is pending.
+    pub(crate) fn modify_task<F: FnOnce(&mut Task) -> bool>(
+        &mut self,
+        task_id: u128,
+        f: F,
+    ) -> bool {
+        let mut tasks = self.tasks.take_all();
+        let modified = tasks
+            .iter_mut()
+            .find(|t| t.id() == task_id && !t.dropped())
+            .map(f)
+            .unwrap_or(false);
+        self.tasks.extend(tasks);
+        self.epoch += 1;
+        modified
+    }
+
+    /// Check whether a pending or running task has the given ID
+    pub(crate) fn is_id_in_use(&self, task_id: u128) -> bool {
+        self.in_flight.contains(&task_id)
+            || self
+                .pending_tasks()
+                .any(|t| t.id() == task_id || t.follower_ids().any(|id| id == task_id))
+            || self
+                .pending_followers
+                .values()
+                .flatten()
+                .any(|f| f.task_id() == task_id)
+    }
+
+    /// Push back every repeating task which was due before `now` to one interval after `now`,
+    /// returning how many were pushed back
+    pub(crate) fn skip_missed_repeating(&mut self, now: Instant) -> usize {
+        let mut tasks = self.tasks.take_all();
+        let skipped = tasks
+            .iter_mut()
+            .map(|t| t.skip_missed(now))
+            .filter(|&s| s)
+            .count();
+        self.tasks.extend(tasks);
+        self.epoch += 1;
+        skipped
+    }
+
+    /// Make every task which was put back after being vetoed by the execution gate due at `now`,
+    /// so that the gate is asked about it again
+    pub(crate) fn release_gated(&mut self, now: Instant) {
+        if self.gated.is_empty() {
+            return;
+        }
+        let gated = std::mem::take(&mut self.gated);
+        let mut tasks = self.tasks.take_all();
+        for task in tasks.iter_mut().filter(|t| gated.contains(&t.id())) {
+            task.set_next_execution(now.min(task.next_execution()));
+        }
+        self.tasks.extend(tasks);
+    }
+
+    /// Take all cancelled tasks out of the queue, rather than waiting for them to come due.
+    /// Dropping a task can run user code (such as a cleanup closure), which may call back into
+    /// the timer, so the caller must only drop the returned tasks once the lock is released.
+    #[must_use]
+    pub(crate) fn purge_cancelled(&mut self) -> Vec<Task> {
+        let (mut cancelled, live): (Vec<_>, Vec<_>) =
+            self.tasks.take_all().into_iter().partition(Task::dropped);
+        self.tasks.extend(live);
+        let (deferred_cancelled, deferred): (Vec<_>, Vec<_>) = std::mem::take(&mut self.deferred)
+            .into_iter()
+            .partition(Task::dropped);
+        self.deferred = deferred;
+        cancelled.extend(deferred_cancelled);
+        self.epoch += 1;
+        self.record_finished(cancelled.iter().map(Task::id));
+        cancelled
+    }
+
+    /// Note that these tasks have left the timer for good, for any waiting [`Timer::flush`]. The
+    /// caller must notify the `completed` condition variable once it has released the lock.
+    pub(crate) fn record_finished<I: IntoIterator<Item = u128>>(&mut self, task_ids: I) {
+        if self.flushing > 0 {
+            self.finished.extend(task_ids);
+        }
+    }
+
+    fn verify_invariants(&self) -> Result<(), InvariantViolation> {
+        if !self.tasks.is_ordered() {
+            return Err(InvariantViolation::HeapOrder);
+        }
+        let mut seen = HashSet::with_capacity(self.tasks.len() + self.in_flight.len());
+        for task_id in self
+            .pending_tasks()
+            .map(Task::id)
+            .chain(self.in_flight.iter().copied())
+        {
+            if task_id == 0 {
+                return Err(InvariantViolation::ZeroId);
+            }
+            if !seen.insert(task_id) {
+                return Err(InvariantViolation::DuplicateId(task_id));
+            }
+        }
+        if let Some(task_id) = self
+            .pending_followers
+            .keys()
+            .find(|id| !self.in_flight.contains(id))
+        {
+            return Err(InvariantViolation::OrphanedFollowers(*task_id));
+        }
+        Ok(())
+    }
+
+    /// Check whether new tasks may currently be added
+    pub(crate) fn check_accepting(&self) -> Result<(), ScheduleError> {
+        if self.done {
+            Err(ScheduleError::ShuttingDown)
+        } else if !self.accepting {
+            Err(ScheduleError::NotAccepting)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Move each deferred task whose threshold is above the length of the queue into the queue,
+    /// due at `now`. Cancelled tasks are returned, to be dropped once the lock is released (see
+    /// [`purge_cancelled`](Self::purge_cancelled)).
+    #[must_use]
+    pub(crate) fn release_deferred(&mut self, now: Instant) -> Vec<Task> {
+        let mut cancelled = Vec::new();
+        if self.deferred.is_empty() {
+            return cancelled;
+        }
+        for mut task in std::mem::take(&mut self.deferred) {
+            if task.dropped() {
+                self.record_finished([task.id()]);
+                cancelled.push(task);
+                continue;
+            }
+            if task
+                .when_below()
+                .is_none_or(|threshold| self.tasks.len() < threshold)
+            {
+                task.set_next_execution(now);
+                self.tasks.push(task);
+            } else {
+                self.deferred.push(task);
+            }
+        }
+        cancelled
+    }
+
+    /// Every pending task: those in the queue, and those held back by
+    /// [`Timer::schedule_when_below`]
+    pub(crate) fn pending_tasks(&self) -> impl Iterator<Item = &Task> {
+        self.tasks.iter().chain(&self.deferred)
+    }
+
     #[inline(always)]
-    fn with_capacity(cap: usize) -> Self {
+    fn new(config: &TimerConfig) -> Self {
         Self {
-            tasks: if cap == 0 {
-                // Avoid allocating in this case
-                BinaryHeap::new()
-            } else {
-                BinaryHeap::with_capacity(cap)
-            },
+            tasks: TaskQueue::new(
+                config.capacity,
+                config.queue_kind,
+                config.fifo,
+                config.clock.0.now(),
+            ),
             done: false,
+            accepting: true,
+            generation: next_generation(),
             next_id: 1,
+            epoch: 0,
+            stats: HashMap::new(),
+            watermark: None,
+            in_flight: Vec::new(),
+            in_flight_repeating: HashSet::new(),
+            pending_followers: HashMap::new(),
+            cancel_on_return: HashSet::new(),
+            gated: HashSet::new(),
+            completion_callbacks: HashMap::new(),
+            rescans: 0,
+            spurious_wakeups: 0,
+            limits: Arc::default(),
+            fairness: Arc::default(),
+            last_wake: None,
+            paused: false,
+            batch_sizes: Arc::new(std::array::from_fn(|_| AtomicU64::new(0))),
+            current_tasks: Vec::new(),
+            deferred: Vec::new(),
+            flushing: 0,
+            finished: Vec::new(),
+            executor_thread_ids: Vec::new(),
         }
     }
 }
@@ -51,32 +438,275 @@ impl Timer {
     /// as a microoptimization. This will immediately start a background thread for
     /// executing tasks, which will be shut down on drop.
     pub fn with_capacity(cap: usize) -> Self {
-        let shared = Arc::new(Mutex::new(TimerShared::with_capacity(cap)));
+        TimerBuilder::new().capacity(cap).build()
+    }
+
+    /// The longest the executor will sleep without checking the queue, even if no task is due
+    /// sooner. This bounds how late a task can run if a wakeup is ever missed.
+    pub const DEFAULT_LOOP_TIME: Duration = crate::executor::DEFAULT_LOOP_TIME;
+
+    /// The largest number of ready tasks the executor takes from the queue at once. Tasks are
+    /// taken in batches to amortize locking.
+    pub const MAX_PER_LOOP: usize = crate::executor::MAX_PER_LOOP;
+
+    /// Construct a new Timer with the given tasks already scheduled, each to run once after its
+    /// duration; see [`TimerBuilder::build_with_initial_tasks`]
+    pub fn with_initial_tasks(tasks: Vec<(Duration, BoxedTask)>) -> (Self, Vec<TaskGuard>) {
+        TimerBuilder::new().build_with_initial_tasks(tasks)
+    }
+
+    /// Construct a [`TimerBuilder`] for configuring a Timer with non-default settings
+    pub fn builder() -> TimerBuilder {
+        TimerBuilder::new()
+    }
+
+    /// Returns whether panics in tasks will be caught and logged. This is the case unless the
+    /// crate was compiled with `panic = "abort"`, in which case a panicking task will abort the
+    /// whole process. Running the task on a helper thread would not help here, since under
+    /// `panic = "abort"` a panic on *any* thread aborts the process.
+    pub const fn catches_panics() -> bool {
+        !cfg!(panic = "abort")
+    }
+
+    pub(crate) fn from_config(config: TimerConfig) -> Self {
+        let (mut timer, runner) = Self::from_config_unstarted(config);
+        timer.spawn_executor_threads(runner);
+        timer
+    }
+
+    pub(crate) fn from_config_with_tasks(
+        config: TimerConfig,
+        tasks: Vec<(Duration, BoxedTask)>,
+    ) -> (Self, Vec<TaskGuard>) {
+        let (mut timer, runner) = Self::from_config_unstarted(config);
+        let now = timer.now();
+        let guards = tasks
+            .into_iter()
+            .map(|(duration, f)| timer.push(TaskCallable::Once(f), now + duration))
+            .collect();
+        timer.spawn_executor_threads(runner);
+        (timer, guards)
+    }
+
+    /// Start the configured number of executor threads, running `runner` and copies of it
+    fn spawn_executor_threads(&mut self, runner: ExecutorRunner) {
+        self.owns_executor = true;
+        // A repeating task is never run concurrently with itself because it is popped from the
+        // queue by exactly one executor and only put back once that run has finished
+        let mut runners = (1..self.config.executor_threads)
+            .map(|_| runner.duplicate())
+            .collect::<Vec<_>>();
+        runners.push(runner);
+        *self.executor_threads.lock() = runners.into_iter().map(ExecutorThread::spawn).collect();
+    }
+
+    /// Make a new runner for this timer's executor
+    fn new_runner(&self) -> ExecutorRunner {
+        ExecutorRunner::new(Executor::new(
+            Arc::clone(&self.shared),
+            Arc::clone(&self.changed),
+            Arc::clone(&self.completed),
+            self.config.clone(),
+        ))
+    }
+
+    /// Construct a new Timer without starting a background thread. Tasks will not be executed
+    /// until [`ExecutorRunner::run`] is called on the returned runner, which may be done on a
+    /// thread of your choosing. The runner will return once the `Timer` is dropped (or
+    /// [`begin_shutdown`](Self::begin_shutdown) is called).
+    pub fn new_unstarted() -> (Self, ExecutorRunner) {
+        TimerBuilder::new().build_unstarted()
+    }
+
+    pub(crate) fn from_config_unstarted(config: TimerConfig) -> (Self, ExecutorRunner) {
+        if !Self::catches_panics() {
+            static WARN_ONCE: std::sync::Once = std::sync::Once::new();
+            WARN_ONCE.call_once(|| {
+                log::warn!(
+                    "compiled with panic=abort; a panic in any scheduled task will abort the process"
+                )
+            });
+        }
+        let mut config = config;
+        let clock = Arc::new(PausableClock::new(Arc::clone(&config.clock.0)));
+        config.clock = Hook(Arc::clone(&clock) as Arc<dyn Clock>);
+        let shared = Arc::new(Mutex::new(TimerShared::new(&config)));
         let changed = Arc::new(Condvar::new());
-        let executor = Executor::new(Arc::clone(&shared), Arc::clone(&changed));
-        let executor_thread = Some(
-            std::thread::Builder::new()
-                .name("timer-executor".into())
-                .spawn(|| executor.run_until_done())
-                .unwrap(),
+        let completed = Arc::new(Condvar::new());
+        let executor = Executor::new(
+            Arc::clone(&shared),
+            Arc::clone(&changed),
+            Arc::clone(&completed),
+            config.clone(),
         );
-        Self {
+        let timer = Self {
             shared,
             changed,
-            executor_thread,
+            completed,
+            executor_threads: Mutex::new(Vec::new()),
+            #[cfg(feature = "async")]
+            async_changed: None,
+            config,
+            clock,
+            owns_executor: false,
+        };
+        (timer, ExecutorRunner::new(executor))
+    }
+
+    /// Construct a new Timer whose executor runs as a task on the given Tokio runtime rather than
+    /// on a dedicated thread. Scheduled tasks are run on the runtime's blocking thread pool, so
+    /// they will not block the runtime. Scheduling works exactly the same as for any other Timer.
+    #[cfg(feature = "async")]
+    pub fn spawn_on(handle: &tokio::runtime::Handle) -> Self {
+        TimerBuilder::new().build_on(handle)
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn from_config_on(config: TimerConfig, handle: &tokio::runtime::Handle) -> Self {
+        let (mut timer, runner) = Self::from_config_unstarted(config);
+        let changed = Arc::new(tokio::sync::Notify::new());
+        timer.async_changed = Some(Arc::clone(&changed));
+        handle.spawn(runner.run_async(changed));
+        timer
+    }
+
+    /// The current monotonic time, according to the timer's clock
+    pub(crate) fn now(&self) -> Instant {
+        self.config.clock.0.now()
+    }
+
+    /// Wake up the executor so that it notices changes to the queue
+    fn notify(&self) {
+        self.changed.notify_one();
+        #[cfg(feature = "async")]
+        if let Some(changed) = &self.async_changed {
+            changed.notify_one();
         }
     }
 
-    fn push(&mut self, callable: TaskCallable, next: Instant) -> TaskGuard {
+    fn try_push(&self, callable: TaskCallable, next: Instant) -> Result<TaskGuard, ScheduleError> {
+        self.try_push_with(callable, next, TaskOptions::default())
+    }
+
+    fn try_push_with(
+        &self,
+        callable: TaskCallable,
+        next: Instant,
+        options: TaskOptions,
+    ) -> Result<TaskGuard, ScheduleError> {
+        self.try_push_with_id(callable, next, options, None)
+    }
+
+    fn try_push_with_id(
+        &self,
+        callable: TaskCallable,
+        next: Instant,
+        options: TaskOptions,
+        id: Option<u128>,
+    ) -> Result<TaskGuard, ScheduleError> {
+        let callable = self.wrap_callable(callable);
         let mut shared = self.shared.lock();
-        let id = shared.next_id;
-        shared.next_id += 1;
-        let handle = Task::new(id, next, callable);
-        let guard = handle.guard();
-        shared.tasks.push(handle);
+        let guard = self.push_locked(&mut shared, callable, next, options, id)?;
+        self.finish_push(shared, &[guard.task_id()]);
+        Ok(guard)
+    }
+
+    /// Apply the configured task wrapper, if any
+    fn wrap_callable(&self, callable: TaskCallable) -> TaskCallable {
+        match &self.config.task_wrapper {
+            Some(wrapper) => callable.wrap(&wrapper.0),
+            None => callable,
+        }
+    }
+
+    /// Release the lock after pushing the tasks with the given IDs, then wake the executor and
+    /// report the new tasks to the instrument, observer and high watermark callback.
+    fn finish_push(&self, mut shared: MutexGuard<'_, TimerShared>, ids: &[u128]) {
+        let len = shared.tasks.len();
+        let crossed = shared.watermark.as_mut().and_then(|w| w.check_high(len));
         drop(shared);
-        self.changed.notify_one();
-        guard
+        self.notify();
+        let observer = observer::resolve(self.config.observer.as_ref());
+        for &id in ids {
+            instrument::task_scheduled(len);
+            if let Some(observer) = &observer {
+                observer.task_scheduled(id);
+            }
+        }
+        if let Some(callback) = crossed {
+            callback(len);
+        }
+    }
+
+    /// Add a task to the queue while already holding the lock. The caller is responsible for
+    /// notifying the executor afterwards.
+    fn push_locked(
+        &self,
+        shared: &mut TimerShared,
+        callable: TaskCallable,
+        next: Instant,
+        options: TaskOptions,
+        id: Option<u128>,
+    ) -> Result<TaskGuard, ScheduleError> {
+        shared.check_accepting()?;
+        let id = match id {
+            Some(id) => {
+                if id == 0 || shared.is_id_in_use(id) {
+                    return Err(ScheduleError::IdInUse(id));
+                }
+                id
+            }
+            None => {
+                let id = shared.next_id();
+                shared.next_id += 1;
+                id
+            }
+        };
+        shared.epoch += 1;
+        let handle = Task::new(id, next, callable, options);
+        let mut guard = handle.guard();
+        if self.config.detach_by_default {
+            guard.disarm();
+        }
+        if handle.when_below().is_some() {
+            shared.deferred.push(handle);
+        } else {
+            shared.tasks.push(handle);
+        }
+        Ok(guard)
+    }
+
+    fn push(&self, callable: TaskCallable, next: Instant) -> TaskGuard {
+        Self::guard_or_log(self.try_push(callable, next))
+    }
+
+    fn guard_or_log(res: Result<TaskGuard, ScheduleError>) -> TaskGuard {
+        match res {
+            Ok(guard) => guard,
+            Err(e) => {
+                log::warn!("not scheduling task: {}", e);
+                TaskGuard::inert()
+            }
+        }
+    }
+
+    fn try_push_at(
+        &mut self,
+        callable: TaskCallable,
+        system_time: SystemTime,
+    ) -> Result<TaskGuard, ScheduleError> {
+        let now = self.config.clock.0.system_now();
+        match system_time.duration_since(now) {
+            Ok(d) => self.try_push(callable, self.now() + d),
+            Err(e) => match self.config.past_policy {
+                PastPolicy::RunImmediately => self.try_push(callable, self.now()),
+                PastPolicy::Skip => {
+                    log::info!("skipping task scheduled {:?} in the past", e.duration());
+                    Ok(TaskGuard::inert())
+                }
+                PastPolicy::Error => Err(ScheduleError::InThePast(e.duration())),
+            },
+        }
     }
 
     /// Schedule a task to run once, after the given duration
@@ -86,24 +716,218 @@ impl Timer {
         f: F,
     ) -> TaskGuard {
         let callable = TaskCallable::new_once(f);
-        self.push(callable, Instant::now() + duration)
+        self.push(callable, self.now() + duration)
+    }
+
+    /// Schedule a task to run once, after the given duration, in the given priority [`Tier`].
+    /// Once it is due, it runs before any due task in a lower tier, however long that one has
+    /// been waiting.
+    pub fn schedule_in_tier<F: FnOnce() + UnwindSafe + Send + 'static>(
+        &mut self,
+        tier: Tier,
+        duration: Duration,
+        f: F,
+    ) -> TaskGuard {
+        let callable = TaskCallable::new_once(f);
+        let options = TaskOptions {
+            tier,
+            ..TaskOptions::default()
+        };
+        Self::guard_or_log(self.try_push_with(callable, self.now() + duration, options))
+    }
+
+    /// Schedule a task to run once, after the given duration, unless the executor is so far
+    /// behind that it would start more than `ttl` after that, in which case it is thrown away
+    /// instead. This suits work which is pointless if it's too late, such as refreshing a
+    /// display. Expired tasks are logged at debug level and reported to the timer's
+    /// [`TimerObserver`](crate::TimerObserver).
+    pub fn schedule_in_with_ttl<F: FnOnce() + UnwindSafe + Send + 'static>(
+        &mut self,
+        duration: Duration,
+        ttl: Duration,
+        f: F,
+    ) -> TaskGuard {
+        let callable = TaskCallable::new_once(f);
+        let options = TaskOptions {
+            ttl: Some(ttl),
+            ..TaskOptions::default()
+        };
+        Self::guard_or_log(self.try_push_with(callable, self.now() + duration, options))
+    }
+
+    /// Schedule a task to run once, at `min_instant`, which is also a hard floor: the task never
+    /// starts before `min_instant` has passed on the real monotonic clock ([`Instant::now`]),
+    /// even if it is brought forward (as by [`boost_key`](Self::boost_key) or
+    /// [`reschedule_earliest`](Self::reschedule_earliest)) or the timer's clock runs ahead. If the
+    /// executor finds it due before then, it is put back until the floor has passed. This suits
+    /// security-sensitive work such as rate limiting.
+    pub fn schedule_not_before<F: FnOnce() + UnwindSafe + Send + 'static>(
+        &mut self,
+        min_instant: Instant,
+        f: F,
+    ) -> TaskGuard {
+        let callable = TaskCallable::new_once(f);
+        let options = TaskOptions {
+            not_before: Some(min_instant),
+            ..TaskOptions::default()
+        };
+        Self::guard_or_log(self.try_push_with(callable, min_instant, options))
+    }
+
+    /// Schedule a task to run once, after the given duration plus a random extra delay of up to
+    /// `jitter`. Spreading out tasks like this avoids many of them (for example, retries across
+    /// a fleet) firing in lockstep. The jitter is drawn from the timer's random number generator
+    /// (see [`TimerBuilder::rng`]).
+    pub fn schedule_in_jittered<F: FnOnce() + UnwindSafe + Send + 'static>(
+        &mut self,
+        duration: Duration,
+        jitter: Duration,
+        f: F,
+    ) -> TaskGuard {
+        let callable = TaskCallable::new_once(f);
+        let next = self.now() + duration + self.random_delay(jitter);
+        Self::guard_or_log(self.try_push(callable, next))
+    }
+
+    /// A random delay of up to `max`, drawn from the configured random number generator
+    fn random_delay(&self, max: Duration) -> Duration {
+        let max = u64::try_from(max.as_nanos()).unwrap_or(u64::MAX);
+        if max == 0 {
+            return Duration::ZERO;
+        }
+        let random = (self.config.rng.0.lock())();
+        Duration::from_nanos(random % max.saturating_add(1))
+    }
+
+    /// Schedule a task to run once, after the given duration, and `cleanup` to run exactly once
+    /// when the task is finished with for any reason: after it has run (even if it panicked),
+    /// once it has been cancelled and discarded, or if it is dropped without running because
+    /// the timer was shut down or refused it. Cleanup runs on whichever thread discards the task,
+    /// which is usually the executor thread.
+    pub fn schedule_in_with_cleanup<F, C>(
+        &mut self,
+        duration: Duration,
+        f: F,
+        cleanup: C,
+    ) -> TaskGuard
+    where
+        F: FnOnce() + UnwindSafe + Send + 'static,
+        C: FnOnce() + Send + 'static,
+    {
+        let callable = TaskCallable::new_once(f);
+        let options = TaskOptions {
+            cleanup: Some(Cleanup::new(cleanup)),
+            ..TaskOptions::default()
+        };
+        Self::guard_or_log(self.try_push_with(callable, self.now() + duration, options))
+    }
+
+    /// Schedule a task to run once, after the shortest of the given durations. This is useful
+    /// when combining timeouts from several sources; see also
+    /// [`reschedule_earliest`](Self::reschedule_earliest).
+    ///
+    /// # Panics
+    /// Panics if `durations` is empty
+    pub fn schedule_in_earliest<F: FnOnce() + UnwindSafe + Send + 'static>(
+        &mut self,
+        durations: &[Duration],
+        f: F,
+    ) -> TaskGuard {
+        let duration = durations
+            .iter()
+            .min()
+            .expect("must have at least one candidate duration");
+        self.schedule_in(*duration, f)
+    }
+
+    /// Like [`schedule_in`](Self::schedule_in), but returns an error if the task could not be
+    /// scheduled instead of silently never running it
+    pub fn try_schedule_in<F: FnOnce() + UnwindSafe + Send + 'static>(
+        &mut self,
+        duration: Duration,
+        f: F,
+    ) -> Result<TaskGuard, ScheduleError> {
+        let callable = TaskCallable::new_once(f);
+        self.try_push(callable, self.now() + duration)
     }
 
     /// Schedule a task to run at a given wall-clock time. This will be converted
     /// to an Instant and run according to the monotonic clock, so may have... somewhat
-    /// unpredictable behavior around leap seconds.
+    /// unpredictable behavior around leap seconds. Times in the past are handled according to the
+    /// configured [`PastPolicy`] (by default, the task is run immediately).
     pub fn schedule_at<F: FnOnce() + UnwindSafe + Send + 'static>(
         &mut self,
         system_time: SystemTime,
         f: F,
     ) -> TaskGuard {
         let callable = TaskCallable::new_once(f);
-        let now = SystemTime::now();
-        let when = match system_time.duration_since(now) {
-            Ok(d) => Instant::now() + d,
-            Err(_) => Instant::now(),
-        };
-        self.push(callable, when)
+        Self::guard_or_log(self.try_push_at(callable, system_time))
+    }
+
+    /// Like [`schedule_at`](Self::schedule_at), but returns an error if the task could not be
+    /// scheduled instead of silently never running it
+    pub fn try_schedule_at<F: FnOnce() + UnwindSafe + Send + 'static>(
+        &mut self,
+        system_time: SystemTime,
+        f: F,
+    ) -> Result<TaskGuard, ScheduleError> {
+        let callable = TaskCallable::new_once(f);
+        self.try_push_at(callable, system_time)
+    }
+
+    /// Like [`try_schedule_at`](Self::try_schedule_at), but a time which has already passed is
+    /// always refused with [`ScheduleError::InThePast`] (saying how far in the past it was),
+    /// whatever the timer's [`PastPolicy`]. This catches bugs where a stale time is used, which
+    /// would otherwise silently run the task straight away.
+    pub fn try_schedule_at_strict<F: FnOnce() + UnwindSafe + Send + 'static>(
+        &mut self,
+        system_time: SystemTime,
+        f: F,
+    ) -> Result<TaskGuard, ScheduleError> {
+        let now = self.config.clock.0.system_now();
+        let delay = system_time
+            .duration_since(now)
+            .map_err(|e| ScheduleError::InThePast(e.duration()))?;
+        self.try_push(TaskCallable::new_once(f), self.now() + delay)
+    }
+
+    /// Like [`try_schedule_at`](Self::try_schedule_at), but first checks that `system_time` is
+    /// plausible, in case either it or the system clock is wrong (for example, a deadline which
+    /// was accidentally left at the epoch). Times more than
+    /// [`TimerBuilder::max_wall_clock_offset`] away from the current time, in either direction,
+    /// are rejected.
+    pub fn schedule_at_checked<F: FnOnce() + UnwindSafe + Send + 'static>(
+        &mut self,
+        system_time: SystemTime,
+        f: F,
+    ) -> Result<TaskGuard, ClockError> {
+        let bound = self.config.max_wall_clock_offset;
+        match system_time.duration_since(self.config.clock.0.system_now()) {
+            Ok(d) if d > bound => return Err(ClockError::TooFarInFuture(d)),
+            Err(e) if e.duration() > bound => return Err(ClockError::TooFarInPast(e.duration())),
+            _ => {}
+        }
+        Ok(self.try_schedule_at(system_time, f)?)
+    }
+
+    /// Schedule a group of tasks to run at the given wall-clock time as a single unit: they are
+    /// run back-to-back, in order, on the same executor thread, without any other task running in
+    /// between. A panic in one of them is caught and logged, and the rest still run. The returned
+    /// guard cancels the whole group.
+    pub fn schedule_batch_at(
+        &mut self,
+        system_time: SystemTime,
+        closures: Vec<BoxedTask>,
+    ) -> TaskGuard {
+        let callable = TaskCallable::new_once(move || {
+            for f in closures {
+                if let Err(e) = std::panic::catch_unwind(f) {
+                    log::error!("uncaught panic when running batched task: {:?}", e);
+                    instrument::task_panicked();
+                }
+            }
+        });
+        Self::guard_or_log(self.try_push_at(callable, system_time))
     }
 
     /// Schedule a task to run periodically, after every interval
@@ -113,32 +937,1652 @@ impl Timer {
         f: F,
     ) -> TaskGuard {
         let callable = TaskCallable::new_repeating(f, interval);
-        self.push(callable, Instant::now() + interval)
+        self.push(callable, self.now() + interval)
+    }
+
+    /// Like [`schedule_repeating`](Self::schedule_repeating), but returns an error if the task
+    /// could not be scheduled instead of silently never running it
+    pub fn try_schedule_repeating<F: FnMut() + UnwindSafe + Send + 'static>(
+        &mut self,
+        interval: Duration,
+        f: F,
+    ) -> Result<TaskGuard, ScheduleError> {
+        let callable = TaskCallable::new_repeating(f, interval);
+        self.try_push(callable, self.now() + interval)
+    }
+
+    /// Like [`schedule_repeating`](Self::schedule_repeating), but with a choice of what happens
+    /// if the task panics. By default, a repeating task is never run again after it panics; with
+    /// [`PanicPolicy::ContinueOnPanic`], the panic is logged and the task keeps running.
+    pub fn schedule_repeating_with_panic_policy<F: FnMut() + UnwindSafe + Send + 'static>(
+        &mut self,
+        interval: Duration,
+        policy: PanicPolicy,
+        mut f: F,
+    ) -> TaskGuard {
+        match policy {
+            PanicPolicy::StopOnPanic => self.schedule_repeating(interval, f),
+            PanicPolicy::ContinueOnPanic => self.schedule_repeating(interval, move || {
+                // `f` is itself UnwindSafe, so it's fine to call it through a mutable reference
+                if let Err(e) = std::panic::catch_unwind(AssertUnwindSafe(&mut f)) {
+                    log::error!("uncaught panic when running task (continuing): {:?}", e);
+                    instrument::task_panicked();
+                }
+            }),
+        }
+    }
+
+    /// Like [`schedule_repeating`](Self::schedule_repeating), for a fallible task. Whenever the
+    /// task returns an error, it is logged and the task keeps running at its usual interval. This
+    /// suits pollers which expect occasional transient failures.
+    pub fn schedule_repeating_logged<E, F>(&mut self, interval: Duration, mut f: F) -> TaskGuard
+    where
+        E: std::fmt::Display,
+        F: FnMut() -> Result<(), E> + UnwindSafe + Send + 'static,
+    {
+        self.schedule_repeating(interval, move || {
+            if let Err(e) = f() {
+                log::warn!("repeating task returned an error: {}", e);
+            }
+        })
+    }
+
+    /// Like [`schedule_repeating`](Self::schedule_repeating), but runs the task once on the
+    /// calling thread before returning, then every interval after that on the executor. This is
+    /// useful when the first run must have happened before continuing (for example, to populate a
+    /// cache before serving traffic). A panic in the first run is caught and logged, and the task
+    /// is still scheduled.
+    pub fn schedule_repeating_prime<F: FnMut() + UnwindSafe + Send + 'static>(
+        &mut self,
+        interval: Duration,
+        mut f: F,
+    ) -> TaskGuard {
+        // `f` is itself UnwindSafe, so it's fine to call it through a mutable reference
+        if let Err(e) = std::panic::catch_unwind(AssertUnwindSafe(&mut f)) {
+            log::error!("uncaught panic when running task: {:?}", e);
+            instrument::task_panicked();
+        }
+        self.schedule_repeating(interval, f)
+    }
+
+    /// Schedule `n` tasks to run as soon as possible, each made by calling `factory` with its
+    /// index, then block until all of them have finished and return how long that took since
+    /// they started being scheduled. This is meant for benchmarking the timer's throughput. A
+    /// task which panics or can't be scheduled counts as finished.
+    pub fn run_n_blocking<F: Fn(usize) -> BoxedTask>(&mut self, n: usize, factory: F) -> Duration {
+        let started = Instant::now();
+        let countdown = Arc::new(Countdown {
+            remaining: Mutex::new(n),
+            finished: Condvar::new(),
+        });
+        for i in 0..n {
+            let tick = CountdownTick(Arc::clone(&countdown));
+            let f = factory(i);
+            let callable = TaskCallable::new_once(move || {
+                let _tick = tick;
+                f()
+            });
+            self.push(callable, self.now()).detach();
+        }
+        let mut remaining = countdown.remaining.lock();
+        while *remaining > 0 {
+            countdown.finished.wait(&mut remaining);
+        }
+        started.elapsed()
     }
 
     /// Schedule a task to run as soon as possible
     pub fn schedule_immediately<F: FnOnce() + UnwindSafe + Send + 'static>(&mut self, f: F) {
         let callable = TaskCallable::new_once(f);
-        self.push(callable, Instant::now()).detach()
+        self.push(callable, self.now()).detach()
     }
-}
 
-impl Default for Timer {
-    fn default() -> Self {
-        Self::new()
+    /// Like [`schedule_immediately`](Self::schedule_immediately), but skips allocating the state
+    /// which is shared with a task's guard, so the task can never be cancelled. This saves a
+    /// little per task when scheduling very large numbers of fire-and-forget tasks.
+    pub fn schedule_immediately_lightweight<F: FnOnce() + UnwindSafe + Send + 'static>(
+        &mut self,
+        f: F,
+    ) {
+        let callable = TaskCallable::new_once(f);
+        let options = TaskOptions {
+            lightweight: true,
+            ..TaskOptions::default()
+        };
+        Self::guard_or_log(self.try_push_with(callable, self.now(), options)).detach()
     }
-}
 
-impl Drop for Timer {
+    /// Like [`schedule_immediately`](Self::schedule_immediately), but returns an error if the
+    /// task could not be scheduled instead of silently never running it
+    pub fn try_schedule_immediately<F: FnOnce() + UnwindSafe + Send + 'static>(
+        &mut self,
+        f: F,
+    ) -> Result<(), ScheduleError> {
+        let callable = TaskCallable::new_once(f);
+        self.try_push(callable, self.now()).map(TaskGuard::detach)
+    }
+
+    /// Schedule a task to run repeatedly, first after `initial` and subsequently after whatever
+    /// delay the task itself returns, measured from when it finished running. This is useful for
+    /// polling something with a cadence that depends on the result.
+    pub fn schedule_poll<F: FnMut() -> Duration + UnwindSafe + Send + 'static>(
+        &mut self,
+        initial: Duration,
+        f: F,
+    ) -> TaskGuard {
+        let callable = TaskCallable::new_poll(f);
+        self.push(callable, self.now() + initial)
+    }
+
+    /// Schedule a task to run every `interval`, like
+    /// [`schedule_repeating`](Self::schedule_repeating), except that if it falls behind (for
+    /// example, because the executor was stalled by a slow task), the runs it missed are made up
+    /// by running it several times in a row, before it resumes its original cadence. The backlog
+    /// includes the run which was due, so at most `max_backlog` runs (and always at least one) are
+    /// made in a row; any more are dropped, and a warning is logged.
+    pub fn schedule_repeating_catchup_bounded<F: FnMut() + UnwindSafe + Send + 'static>(
+        &mut self,
+        interval: Duration,
+        max_backlog: u64,
+        mut f: F,
+    ) -> TaskGuard {
+        // The clock is only read, so a panicking task can't leave it inconsistent
+        let clock = AssertUnwindSafe(Arc::clone(&self.config.clock.0));
+        let period = interval.as_nanos().max(1);
+        let mut due = self.now() + interval;
+        let callable = TaskCallable::new_poll(move || {
+            let now = clock.now();
+            let behind = now.saturating_duration_since(due).as_nanos();
+            let missed = u64::try_from(behind / period).unwrap_or(u64::MAX);
+            let backlog = missed.saturating_add(1);
+            let runs = backlog.min(max_backlog.max(1));
+            for _ in 0..runs {
+                f();
+            }
+            if backlog > runs {
+                log::warn!(
+                    "repeating task fell {} runs behind; dropped {} of them",
+                    missed,
+                    backlog - runs
+                );
+            }
+            // Stay on the original cadence, at the first deadline which hasn't passed yet
+            let into_period = Duration::from_nanos((behind % period) as u64);
+            due = if now > due {
+                now + interval - into_period
+            } else {
+                due + interval
+            };
+            due.saturating_duration_since(clock.now())
+        });
+        self.push(callable, due)
+    }
+
+    /// Schedule a long-running task to run every `interval`, which can split its work into chunks
+    /// so that it doesn't hold up other tasks. The task is passed a [`Yielder`]; if it calls
+    /// [`Yielder::yield_and_reschedule`] and returns, it is run again as soon as any other tasks
+    /// which are already due have run, to carry on with its remaining work (which it keeps in its
+    /// own state). The interval is measured from the start of the first chunk of each run.
+    pub fn schedule_repeating_yielding<F: FnMut(&mut Yielder) + UnwindSafe + Send + 'static>(
+        &mut self,
+        interval: Duration,
+        mut f: F,
+    ) -> TaskGuard {
+        // The clock is only read, so a panicking task can't leave it inconsistent
+        let clock = AssertUnwindSafe(Arc::clone(&self.config.clock.0));
+        let mut run_started = None;
+        let callable = TaskCallable::new_poll(move || {
+            let started = *run_started.get_or_insert_with(|| clock.now());
+            let mut yielder = Yielder::default();
+            f(&mut yielder);
+            if yielder.yielded() {
+                // Scheduling the continuation for now puts it behind anything already due
+                Duration::ZERO
+            } else {
+                run_started = None;
+                (started + interval).saturating_duration_since(clock.now())
+            }
+        });
+        self.push(callable, self.now() + interval)
+    }
+
+    /// Schedule a task to run periodically, after every interval, like
+    /// [`schedule_repeating`](Self::schedule_repeating), and get a channel which receives the
+    /// instant each run started, once that run has finished. This lets another thread react to
+    /// every run without the task itself having to know about it. Dropping the receiver doesn't
+    /// affect the task; the notifications are just discarded.
+    ///
+    /// The channel is unbounded, so the task is never held up by a slow receiver, but a receiver
+    /// which is kept around and never read from will grow by one `Instant` per run.
+    pub fn schedule_repeating_notifying<F: FnMut() + UnwindSafe + Send + 'static>(
+        &mut self,
+        interval: Duration,
+        mut f: F,
+    ) -> (TaskGuard, std::sync::mpsc::Receiver<Instant>) {
+        let (tx, rx) = std::sync::mpsc::channel();
+        // The clock is only read, and a send can't leave the channel inconsistent
+        let clock = AssertUnwindSafe(Arc::clone(&self.config.clock.0));
+        let tx = AssertUnwindSafe(tx);
+        let guard = self.schedule_repeating(interval, move || {
+            let started = clock.now();
+            f();
+            // The receiver having gone away is fine
+            let _ = tx.send(started);
+        });
+        (guard, rx)
+    }
+
+    /// Schedule an already-boxed task to run once, after the given duration. This avoids boxing
+    /// the task a second time, as [`schedule_in`](Self::schedule_in) would.
+    ///
+    /// If you have a boxed closure which isn't `UnwindSafe` (such as a `Box<dyn FnOnce() + Send>`),
+    /// you can wrap it with [`std::panic::AssertUnwindSafe`], as long as you're sure that a panic
+    /// won't leave anything it captures in an inconsistent state:
+    ///
+    /// ```
+    /// use std::panic::AssertUnwindSafe;
+    /// use std::time::Duration;
+    /// use synchronous_timer::Timer;
+    ///
+    /// let mut timer = Timer::new();
+    /// let plugin_task: Box<dyn FnOnce() + Send> = Box::new(|| println!("hello from a plugin"));
+    /// timer
+    ///     .schedule_in_boxed(Duration::from_millis(10), Box::new(AssertUnwindSafe(plugin_task)))
+    ///     .detach();
+    /// ```
+    pub fn schedule_in_boxed(&mut self, duration: Duration, f: BoxedTask) -> TaskGuard {
+        self.push(TaskCallable::Once(f), self.now() + duration)
+    }
+
+    /// Schedule an already-boxed task to run periodically, after every interval. See
+    /// [`schedule_in_boxed`](Self::schedule_in_boxed) for more details.
+    pub fn schedule_repeating_boxed(
+        &mut self,
+        interval: Duration,
+        f: BoxedRepeatingTask,
+    ) -> TaskGuard {
+        self.push(TaskCallable::Repeating(f, interval), self.now() + interval)
+    }
+
+    /// Atomically replace every repeating task with the given set of `(interval, task)` pairs, as
+    /// when reloading configuration. Under a single lock, every existing repeating task is
+    /// cancelled and the new ones are scheduled, so there is no window in which neither set is
+    /// scheduled. One-off tasks are left alone. A repeating task which is running at the time
+    /// finishes its current run and is then not rescheduled.
+    ///
+    /// Returns a guard for each new task, in the same order as `specs`. If the timer is not
+    /// accepting new tasks, the existing repeating tasks are still cancelled, and the returned
+    /// guards are inert.
+    pub fn replace_all_repeating(
+        &mut self,
+        specs: Vec<(Duration, BoxedRepeatingTask)>,
+    ) -> Vec<TaskGuard> {
+        let now = self.now();
+        let callables = specs
+            .into_iter()
+            .map(|(interval, f)| {
+                let callable = self.wrap_callable(TaskCallable::Repeating(f, interval));
+                (callable, now + interval)
+            })
+            .collect::<Vec<_>>();
+        let mut shared = self.shared.lock();
+        let cancelled = shared
+            .tasks
+            .iter()
+            .filter(|t| t.is_repeating() && !t.dropped())
+            .map(Task::cancel)
+            .filter(|&c| c)
+            .count();
+        let in_flight = shared.in_flight_repeating.clone();
+        shared.cancel_on_return.extend(in_flight);
+        let guards = callables
+            .into_iter()
+            .map(|(callable, next)| {
+                Self::guard_or_log(self.push_locked(
+                    &mut shared,
+                    callable,
+                    next,
+                    TaskOptions::default(),
+                    None,
+                ))
+            })
+            .collect::<Vec<_>>();
+        shared.epoch += 1;
+        let ids = guards
+            .iter()
+            .map(TaskGuard::task_id)
+            .filter(|&id| id != 0)
+            .collect::<Vec<_>>();
+        self.finish_push(shared, &ids);
+        log::debug!(
+            "replaced {} repeating tasks with {}",
+            cancelled,
+            guards.len()
+        );
+        guards
+    }
+
+    /// Reset the counter used to assign task IDs, so that the next task scheduled gets the ID `to`
+    /// (and later tasks count up from there within the generation in its high 64 bits). This is
+    /// intended for tests which want to assert on specific task IDs.
+    ///
+    /// Task IDs are used to identify tasks for cancellation and introspection, so reusing an ID
+    /// that was handed out earlier can make a stale [`TaskGuard`] or ID refer to a different task.
+    /// This returns an error (and does nothing) if any pending or running task has an ID at or
+    /// after `to` in the same generation, but cannot protect against IDs that are held elsewhere.
+    #[cfg(feature = "testing")]
+    pub fn reset_ids(&self, to: u128) -> Result<(), ScheduleError> {
+        let mut shared = self.shared.lock();
+        if to as u64 == 0 {
+            return Err(ScheduleError::IdInUse(to));
+        }
+        let collision = shared
+            .pending_tasks()
+            .map(Task::id)
+            .chain(shared.in_flight.iter().copied())
+            .filter(|&id| id >= to && id >> 64 == to >> 64)
+            .min();
+        if let Some(id) = collision {
+            return Err(ScheduleError::IdInUse(id));
+        }
+        shared.generation = (to >> 64) as u64;
+        shared.next_id = to as u64;
+        Ok(())
+    }
+
+    /// Get a hash of the order and shape of the queue, for tests which want to check that an
+    /// operation left the timer in the expected state, or that it didn't reorder anything. This
+    /// covers, for each pending task in the order they would run if they were all taken now
+    /// (taking tiers and FIFO mode into account), the low 64 bits of its ID (the part which
+    /// doesn't depend on the timer's generation), its deadline to the millisecond relative to the
+    /// first task's, its kind (one-off, repeating or polled), its tier and its place in submission
+    /// order; and then the same for tasks held back by
+    /// [`schedule_when_below`](Self::schedule_when_below), in the order they were scheduled.
+    /// Cancelled tasks are left out. Two timers which had the same tasks scheduled in the same way
+    /// have the same fingerprint, as long as the scheduling didn't straddle a millisecond
+    /// boundary.
+    ///
+    /// The fingerprint is only comparable within one run of a program.
+    #[cfg(feature = "testing")]
+    pub fn state_fingerprint(&self) -> u64 {
+        use std::hash::{Hash, Hasher};
+
+        let now = self.now();
+        let shared = self.shared.lock();
+        let queued = shared.tasks.pop_order(now).into_iter().map(|t| (t, false));
+        let held = shared.deferred.iter().map(|t| (t, true));
+        let tasks = queued
+            .chain(held)
+            .filter(|(t, _)| !t.dropped())
+            .collect::<Vec<_>>();
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        let first = tasks.first().map(|(t, _)| t.next_execution());
+        for (task, held) in tasks {
+            // A higher tier can put a later deadline first, so the offset may be negative
+            let next = task.next_execution();
+            let offset = first.map_or(0, |first| {
+                let after = next.saturating_duration_since(first).as_millis() as i128;
+                after - first.saturating_duration_since(next).as_millis() as i128
+            });
+            let shape = (task.kind(), task.tier(), task.fifo_seq(), held);
+            (task.id() as u64, offset, shape).hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// Take the pending task with the given ID out of the queue and run it straight away on the
+    /// calling thread, rather than on the executor, so that it can be stepped through in a
+    /// debugger and any panic happens where it can be inspected. A panic is still caught, and
+    /// reported as [`TaskError::Panicked`](crate::TaskError::Panicked); a repeating task which
+    /// doesn't panic is put back in the queue for its next run as usual.
+    ///
+    /// Returns `None` if there is no such pending task (including if it is currently running), or
+    /// `Some(Err(TaskError::Cancelled))` if it had been cancelled but not yet discarded.
+    #[cfg(feature = "testing")]
+    pub fn run_task_here(&self, task_id: u128) -> Option<Result<(), crate::TaskError>> {
+        let task = {
+            let mut shared = self.shared.lock();
+            let (mut found, rest): (Vec<_>, Vec<_>) = shared
+                .tasks
+                .take_all()
+                .into_iter()
+                .partition(|t| t.id() == task_id);
+            shared.tasks.extend(rest);
+            shared.epoch += 1;
+            let task = found.pop()?;
+            if !task.is_repeating() {
+                shared.record_finished([task_id]);
+            }
+            task
+        };
+        self.completed.notify_all();
+        let observer = observer::resolve(self.config.observer.as_ref());
+        if task.dropped() {
+            executor::discard_cancelled(observer, vec![task]);
+            return Some(Err(crate::TaskError::Cancelled));
+        }
+        let clock = AssertUnwindSafe(&*self.config.clock.0);
+        let started = Instant::now();
+        match std::panic::catch_unwind(|| task.run(*clock)) {
+            Ok(remainder) => {
+                executor::record_executed(observer, task_id, started.elapsed());
+                if let Some(remainder) = remainder {
+                    let mut shared = self.shared.lock();
+                    shared.tasks.push(remainder);
+                    shared.epoch += 1;
+                    drop(shared);
+                    self.notify();
+                }
+                Some(Ok(()))
+            }
+            Err(e) => {
+                log::error!("uncaught panic when running task {} here: {:?}", task_id, e);
+                executor::record_panicked(observer, task_id);
+                Some(Err(crate::TaskError::Panicked))
+            }
+        }
+    }
+
+    /// Schedule a task to run once, after the given duration, with a caller-supplied ID rather than
+    /// one assigned by the timer. This is useful for correlating tasks with external systems.
+    /// Returns [`ScheduleError::IdInUse`] if a pending task already has that ID (or if it is zero,
+    /// which is reserved).
+    ///
+    /// IDs assigned by the timer hold its generation in their high 64 bits and count up from 1 in
+    /// their low 64 bits, so to avoid collisions with them it is best to use IDs from a distinct
+    /// range (for example, with the highest bit set).
+    pub fn schedule_in_with_id<F: FnOnce() + UnwindSafe + Send + 'static>(
+        &mut self,
+        id: u128,
+        duration: Duration,
+        f: F,
+    ) -> Result<TaskGuard, ScheduleError> {
+        let callable = TaskCallable::new_once(f);
+        self.try_push_with_id(
+            callable,
+            self.now() + duration,
+            TaskOptions::default(),
+            Some(id),
+        )
+    }
+
+    /// Schedule a task to run once, after the given duration, and get a [`TaskResult`] for
+    /// retrieving the value it returns. If the task is cancelled (by dropping the guard, or with
+    /// [`cancel`](Self::cancel)) before it runs, the result is
+    /// [`TaskError::Cancelled`](crate::TaskError::Cancelled); if it panics, it is
+    /// [`TaskError::Panicked`](crate::TaskError::Panicked).
+    pub fn schedule_in_returning<T, F>(
+        &mut self,
+        duration: Duration,
+        f: F,
+    ) -> (TaskGuard, TaskResult<T>)
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + UnwindSafe + Send + 'static,
+    {
+        let (sender, result) = TaskResult::channel();
+        let options = TaskOptions {
+            on_cancel: Some(Hook(Arc::new(sender.canceller()))),
+            ..TaskOptions::default()
+        };
+        let callable = TaskCallable::new_once(move || sender.send(f()));
+        let guard =
+            Self::guard_or_log(self.try_push_with(callable, self.now() + duration, options));
+        (guard, result)
+    }
+
+    /// Schedule a task to run periodically, after every interval, and get a channel which
+    /// receives the value returned by each run. The channel holds at most `capacity` values
+    /// which haven't been received yet; `policy` says what happens to a value when the channel is
+    /// full or the receiver has been dropped. If the task panics, the channel disconnects.
+    ///
+    /// With [`OverflowPolicy::Block`], a receiver which is kept but never read holds up the
+    /// executor, and every other task on this timer, until the timer shuts down or the task is
+    /// cancelled; the value being sent is then dropped so that dropping the timer doesn't hang.
+    pub fn schedule_repeating_result_channel<T, F>(
+        &mut self,
+        interval: Duration,
+        capacity: usize,
+        policy: OverflowPolicy,
+        mut f: F,
+    ) -> (TaskGuard, std::sync::mpsc::Receiver<T>)
+    where
+        T: Send + 'static,
+        F: FnMut() -> T + UnwindSafe + Send + 'static,
+    {
+        use std::sync::mpsc::TrySendError;
+
+        let (tx, rx) = std::sync::mpsc::sync_channel(capacity);
+        // A send can't leave the channel inconsistent, and the canceller is only set once
+        let mut tx = AssertUnwindSafe(Some(tx));
+        // Set as soon as the task is scheduled, which is at least an interval before it first runs
+        let canceller = Arc::new(std::sync::OnceLock::<Box<dyn Fn() + Send + Sync>>::new());
+        let their_canceller = AssertUnwindSafe(Arc::clone(&canceller));
+        let cancelled = Arc::new(std::sync::OnceLock::<Box<dyn Fn() -> bool + Send + Sync>>::new());
+        let their_cancelled = AssertUnwindSafe(Arc::clone(&cancelled));
+        // Only read, to notice the timer shutting down while blocked on a full channel; shutting
+        // down notifies `completed`
+        let shared = AssertUnwindSafe(Arc::clone(&self.shared));
+        let completed = Arc::clone(&self.completed);
+        let guard = self.schedule_repeating(interval, move || {
+            let value = f();
+            let Some(sender) = tx.as_ref() else {
+                return;
+            };
+            let overflowed = match policy {
+                OverflowPolicy::DropResult => match sender.try_send(value) {
+                    Ok(()) | Err(TrySendError::Disconnected(_)) => false,
+                    Err(TrySendError::Full(_)) => {
+                        log::debug!("result channel full; dropping value");
+                        false
+                    }
+                },
+                OverflowPolicy::Block => {
+                    let mut value = value;
+                    let mut backoff = Duration::from_millis(1);
+                    loop {
+                        match sender.try_send(value) {
+                            // The receiver having gone away is fine
+                            Ok(()) | Err(TrySendError::Disconnected(_)) => break,
+                            Err(TrySendError::Full(_))
+                                if their_cancelled.get().is_some_and(|c| c()) =>
+                            {
+                                log::debug!("task cancelled; dropping value");
+                                break;
+                            }
+                            Err(TrySendError::Full(v)) => {
+                                value = v;
+                                let mut shared = shared.lock();
+                                if shared.done {
+                                    log::debug!("timer shutting down; dropping value");
+                                    break;
+                                }
+                                // Nothing says when the receiver makes room, so try again after
+                                // a while, unless the timer starts shutting down first
+                                completed.wait_for(&mut shared, backoff);
+                                backoff = (backoff * 2).min(MAX_BLOCK_BACKOFF);
+                            }
+                        }
+                    }
+                    false
+                }
+                OverflowPolicy::Error => sender.try_send(value).is_err(),
+            };
+            if overflowed {
+                log::debug!("result channel full or disconnected; stopping task");
+                tx.take();
+                if let Some(cancel) = their_canceller.get() {
+                    cancel();
+                }
+            }
+        });
+        let _ = canceller.set(Box::new(guard.canceller()));
+        let _ = cancelled.set(Box::new(guard.cancelled_check()));
+        (guard, rx)
+    }
+
+    /// Schedule a task to run once, after the given duration, with some data attached to it
+    /// (such as a request ID). The task is passed a reference to the data when it runs, and the
+    /// data can be retrieved while the task is pending with
+    /// [`task_context`](Self::task_context).
+    pub fn schedule_in_with_context<C, F>(&mut self, ctx: C, duration: Duration, f: F) -> TaskGuard
+    where
+        C: Send + Sync + 'static,
+        F: FnOnce(&C) + UnwindSafe + Send + 'static,
+    {
+        let ctx = Arc::new(ctx);
+        let options = TaskOptions {
+            context: Some(AssertUnwindSafe(Arc::clone(&ctx) as _)),
+            ..TaskOptions::default()
+        };
+        // The task is discarded if it panics, so it's fine to assert unwind safety here
+        let ctx = AssertUnwindSafe(ctx);
+        let callable = TaskCallable::new_once(move || {
+            let ctx = &*ctx;
+            f(ctx)
+        });
+        Self::guard_or_log(self.try_push_with(callable, self.now() + duration, options))
+    }
+
+    /// Get the data attached to the pending task with the given ID by
+    /// [`schedule_in_with_context`](Self::schedule_in_with_context), which can be downcast with
+    /// [`Arc::downcast`]. Returns `None` if there is no such pending task, or it has no data
+    /// attached.
+    ///
+    /// This scans the queue, so is O(n) in the number of pending tasks.
+    pub fn task_context(&self, task_id: u128) -> Option<Arc<dyn Any + Send + Sync>> {
+        self.shared
+            .lock()
+            .tasks
+            .iter()
+            .find(|t| t.id() == task_id && !t.dropped())
+            .and_then(|t| t.context().cloned())
+    }
+
+    /// Schedule a task to run once, after the given duration, on every one of the given timers.
+    /// Closures can't be cloned, so `factory` is called once per timer to make the task scheduled
+    /// on it. This can be used for redundancy, so that the task still runs if one of the
+    /// executors dies; the tasks are entirely independent, so the application is responsible for
+    /// deduplicating their effects.
+    ///
+    /// Returns a guard for each timer's task, in the same order as `timers`.
+    pub fn schedule_mirrored<F: Fn() -> BoxedTask>(
+        timers: &[&Timer],
+        duration: Duration,
+        factory: F,
+    ) -> Vec<TaskGuard> {
+        timers
+            .iter()
+            .map(|timer| timer.push(TaskCallable::Once(factory()), timer.now() + duration))
+            .collect()
+    }
+
+    /// Schedule a task to run once, after the given duration, on every one of the given timers,
+    /// like [`schedule_mirrored`](Self::schedule_mirrored), except that only the first instance
+    /// to start running does any work: it cancels the others, and any which start anyway skip
+    /// running. This suits redundant work where whoever gets there first wins. Closures can't be
+    /// cloned, so `factory` is called once per timer to make the task scheduled on it.
+    ///
+    /// The returned handle reports which timer won.
+    pub fn schedule_race<F: Fn() -> BoxedTask>(
+        timers: &[&Timer],
+        duration: Duration,
+        factory: F,
+    ) -> RaceHandle {
+        let state = Arc::new(RaceState::default());
+        let guards = timers
+            .iter()
+            .enumerate()
+            .map(|(index, timer)| {
+                let f = factory();
+                let state = Arc::clone(&state);
+                let callable = TaskCallable::new_once(move || {
+                    if state.claim(index) {
+                        f()
+                    }
+                });
+                timer.push(callable, timer.now() + duration)
+            })
+            .collect();
+        state.set_guards(guards);
+        RaceHandle::new(state)
+    }
+
+    /// Schedule a task to run once, at the given instant, on behalf of the given fairness key
+    /// (such as a tenant). Tasks with the same deadline would normally run in the order they were
+    /// scheduled; instead, a burst of tasks for one key is interleaved in weighted round-robin
+    /// with any other keys' tasks for that same deadline, so that no one key monopolizes the
+    /// executor. Weights are set with [`TimerBuilder::fairness_weight`].
+    pub fn schedule_fair<F: FnOnce() + UnwindSafe + Send + 'static>(
+        &mut self,
+        key: &str,
+        at: Instant,
+        f: F,
+    ) -> TaskGuard {
+        let weight = self.config.fairness_weights.get(key).copied().unwrap_or(1);
+        let fairness = Arc::clone(&self.shared.lock().fairness);
+        let fair_rank = {
+            let mut fairness = fairness.lock();
+            let count = fairness.entry(key.to_owned()).or_insert(FairnessCount {
+                deadline: at,
+                scheduled: 0,
+                pending: 0,
+            });
+            if count.deadline != at {
+                count.deadline = at;
+                count.scheduled = 0;
+            }
+            count.scheduled += 1;
+            count.pending += 1;
+            (count.scheduled - 1) / weight
+        };
+        // Forget the key once it has no pending tasks left
+        let fairness = Arc::downgrade(&fairness);
+        let their_key = key.to_owned();
+        let cleanup = Cleanup::new(move || {
+            let Some(fairness) = fairness.upgrade() else {
+                return;
+            };
+            let mut fairness = fairness.lock();
+            if let Some(count) = fairness.get_mut(&their_key) {
+                count.pending -= 1;
+                if count.pending == 0 {
+                    fairness.remove(&their_key);
+                }
+            }
+        });
+        let callable = TaskCallable::new_once(f);
+        let options = TaskOptions {
+            fair_rank,
+            key: Some(key.to_owned()),
+            cleanup: Some(cleanup),
+            ..TaskOptions::default()
+        };
+        Self::guard_or_log(self.try_push_with(callable, at, options))
+    }
+
+    /// Schedule a task to run once, after the given duration, unless there are already `limit`
+    /// pending tasks scheduled with the same `key`, in which case
+    /// [`ScheduleError::LimitExceeded`] is returned. This can be used to stop any one category
+    /// of work (such as a single tenant) from flooding the timer. A task stops counting against
+    /// the limit once it has run or been cancelled.
+    ///
+    /// When the limit has been reached, the queue is cleared of cancelled tasks before giving up,
+    /// which is O(n) in the number of pending tasks.
+    pub fn schedule_in_limited<F: FnOnce() + UnwindSafe + Send + 'static>(
+        &mut self,
+        key: &str,
+        limit: usize,
+        duration: Duration,
+        f: F,
+    ) -> Result<TaskGuard, ScheduleError> {
+        let permit = {
+            let mut shared = self.shared.lock();
+            let limits = Arc::clone(&shared.limits);
+            match LimitPermit::acquire(&limits, key, limit) {
+                Some(permit) => permit,
+                None => {
+                    // Cancelled tasks only release their slot when they're discarded
+                    let cancelled = shared.purge_cancelled();
+                    drop(shared);
+                    self.completed.notify_all();
+                    executor::discard_cancelled(
+                        observer::resolve(self.config.observer.as_ref()),
+                        cancelled,
+                    );
+                    LimitPermit::acquire(&limits, key, limit)
+                        .ok_or(ScheduleError::LimitExceeded(limit))?
+                }
+            }
+        };
+        let callable = TaskCallable::new_once(f);
+        let options = TaskOptions {
+            limit: Some(permit),
+            key: Some(key.to_owned()),
+            ..TaskOptions::default()
+        };
+        self.try_push_with(callable, self.now() + duration, options)
+    }
+
+    /// Bring forward every pending task with the given key (from
+    /// [`schedule_in_limited`](Self::schedule_in_limited) or
+    /// [`schedule_fair`](Self::schedule_fair)) by `by`, though not to before now. This can be
+    /// used to expedite one tenant's work. Returns the number of tasks which were brought forward.
+    ///
+    /// This has to rebuild the timer's queue, so is O(n) in the number of pending tasks.
+    pub fn boost_key(&self, key: &str, by: Duration) -> usize {
+        let now = self.now();
+        let mut shared = self.shared.lock();
+        let mut tasks = shared.tasks.take_all();
+        let mut boosted = 0;
+        for task in tasks
+            .iter_mut()
+            .filter(|t| t.key() == Some(key) && !t.dropped())
+        {
+            let next = task
+                .next_execution()
+                .checked_sub(by)
+                .map_or(now, |next| next.max(now));
+            task.set_next_execution(next.min(task.next_execution()));
+            boosted += 1;
+        }
+        shared.tasks.extend(tasks);
+        shared.epoch += 1;
+        drop(shared);
+        self.notify();
+        boosted
+    }
+
+    /// Schedule a task to run once, after the given duration, without a guard; returns the task's
+    /// ID, which can be passed to [`cancel`](Self::cancel). This is the same as calling
+    /// [`schedule_in`](Self::schedule_in) and detaching the guard.
+    pub fn spawn_in<F: FnOnce() + UnwindSafe + Send + 'static>(
+        &mut self,
+        duration: Duration,
+        f: F,
+    ) -> u128 {
+        Self::detach_for_id(self.schedule_in(duration, f))
+    }
+
+    /// Schedule a task to run once, at the given wall-clock time, without a guard; returns the
+    /// task's ID, which can be passed to [`cancel`](Self::cancel)
+    pub fn spawn_at<F: FnOnce() + UnwindSafe + Send + 'static>(
+        &mut self,
+        system_time: SystemTime,
+        f: F,
+    ) -> u128 {
+        Self::detach_for_id(self.schedule_at(system_time, f))
+    }
+
+    /// Schedule a task to run periodically, after every interval, without a guard; returns the
+    /// task's ID, which can be passed to [`cancel`](Self::cancel). Otherwise the task runs until
+    /// the timer is dropped.
+    pub fn spawn_repeating<F: FnMut() + UnwindSafe + Send + 'static>(
+        &mut self,
+        interval: Duration,
+        f: F,
+    ) -> u128 {
+        Self::detach_for_id(self.schedule_repeating(interval, f))
+    }
+
+    fn detach_for_id(guard: TaskGuard) -> u128 {
+        let task_id = guard.task_id();
+        guard.detach();
+        task_id
+    }
+
+    /// Cancel the pending task with the given ID, as if its [`TaskGuard`] had been dropped.
+    /// Returns `false` if there is no such pending task, or if it is a lightweight task (see
+    /// [`schedule_immediately_lightweight`](Self::schedule_immediately_lightweight)).
+    pub fn cancel(&self, task_id: u128) -> bool {
+        self.shared
+            .lock()
+            .pending_tasks()
+            .find(|t| t.id() == task_id && !t.dropped())
+            .is_some_and(Task::cancel)
+    }
+
+    /// Register a callback to be invoked on the executor thread once the pending task with the
+    /// given ID has finished; for a repeating task, that is after its next run. A task which is
+    /// cancelled counts as finished when it is discarded. If there is no such pending task (for
+    /// example, because it has already finished), the callback is invoked straight away on the
+    /// calling thread. Callbacks for tasks which are still pending when the timer shuts down are
+    /// never invoked.
+    pub fn on_complete<F: FnOnce() + Send + 'static>(&self, task_id: u128, f: F) {
+        {
+            let mut shared = self.shared.lock();
+            if shared.is_id_in_use(task_id) {
+                shared
+                    .completion_callbacks
+                    .entry(task_id)
+                    .or_default()
+                    .push(Box::new(f));
+                return;
+            }
+        }
+        f()
+    }
+
+    /// Let the pending repeating task with the given ID run one more time, at its usual time, and
+    /// then stop rescheduling it. This is useful for winding down a poller gracefully. Returns
+    /// `false` if there is no such pending repeating task.
+    pub fn stop_after_next(&self, task_id: u128) -> bool {
+        self.shared
+            .lock()
+            .tasks
+            .iter()
+            .find(|t| t.id() == task_id && !t.dropped())
+            .is_some_and(Task::stop_after_next)
+    }
+
+    /// Create a new [`Scope`], which can be used to cancel a group of tasks together
+    pub fn scope(&self) -> Scope {
+        Scope::new()
+    }
+
+    /// Schedule a task to run once, after the given duration, which will be cancelled if `scope`
+    /// (or any of its parents) is dropped first
+    pub fn schedule_in_scope<F: FnOnce() + UnwindSafe + Send + 'static>(
+        &mut self,
+        scope: &Scope,
+        duration: Duration,
+        f: F,
+    ) -> TaskGuard {
+        let callable = TaskCallable::new_once(f);
+        let options = TaskOptions {
+            scope: Some(scope.state()),
+            ..TaskOptions::default()
+        };
+        Self::guard_or_log(self.try_push_with(callable, self.now() + duration, options))
+    }
+
+    /// Schedule a task to run periodically, after every interval, until `scope` (or any of its
+    /// parents) is dropped
+    pub fn schedule_repeating_in_scope<F: FnMut() + UnwindSafe + Send + 'static>(
+        &mut self,
+        scope: &Scope,
+        interval: Duration,
+        f: F,
+    ) -> TaskGuard {
+        let callable = TaskCallable::new_repeating(f, interval);
+        let options = TaskOptions {
+            scope: Some(scope.state()),
+            ..TaskOptions::default()
+        };
+        Self::guard_or_log(self.try_push_with(callable, self.now() + interval, options))
+    }
+
+    /// Stop accepting new tasks. Tasks which are already scheduled will continue to run as normal
+    /// (including repeating tasks), but any further attempt to schedule a task will fail; the
+    /// `try_schedule_*` methods will return [`ScheduleError::NotAccepting`] and the infallible
+    /// methods will log a warning and return a guard for a task which will never run.
+    pub fn stop_accepting(&self) {
+        self.shared.lock().accepting = false;
+    }
+
+    /// Wake the executor so that it re-checks the queue straight away, without scheduling
+    /// anything. This is a low-level tool which should rarely be needed, since the timer already
+    /// does this whenever its own state changes; it is for when something the timer can't see
+    /// has changed, such as a custom [`Clock`] jumping forward or an
+    /// [execution gate](TimerBuilder::execution_gate) being opened. Repeating tasks which the gate
+    /// vetoed and rescheduled are made due straight away so that the gate is asked again; vetoed
+    /// one-off tasks have already been discarded.
+    pub fn poke(&self) {
+        let now = self.now();
+        let mut shared = self.shared.lock();
+        shared.release_gated(now);
+        // Make sure an executor which is just about to wait notices, too
+        shared.epoch += 1;
+        drop(shared);
+        self.changed.notify_all();
+        #[cfg(feature = "async")]
+        if let Some(changed) = &self.async_changed {
+            changed.notify_one();
+        }
+    }
+
+    /// Pause the timer: once the current batch has finished, no more tasks run until
+    /// [`resume`](Self::resume) is called. The timer's clock stands still while it is paused, so
+    /// that every deadline is pushed back by the length of the pause, as though no time had passed
+    /// in the meantime. For example, a task scheduled to run in 100ms which is paused for 50ms
+    /// along the way runs 150ms after it was scheduled. Tasks can still be scheduled while the
+    /// timer is paused, relative to the time at which it was paused. Deadlines given as a
+    /// [`SystemTime`] are converted when they are scheduled, so are shifted too if they are pending
+    /// during a pause.
+    ///
+    /// Returns `false` (and does nothing) if the timer was already paused.
+    pub fn pause(&self) -> bool {
+        let mut shared = self.shared.lock();
+        if !self.clock.pause() {
+            return false;
+        }
+        shared.paused = true;
+        true
+    }
+
+    /// Resume a timer which was paused with [`pause`](Self::pause). Returns `false` (and does
+    /// nothing) if the timer wasn't paused.
+    pub fn resume(&self) -> bool {
+        {
+            let mut shared = self.shared.lock();
+            if !self.clock.resume() {
+                return false;
+            }
+            shared.paused = false;
+            shared.epoch += 1;
+        }
+        self.changed.notify_all();
+        #[cfg(feature = "async")]
+        if let Some(changed) = &self.async_changed {
+            changed.notify_one();
+        }
+        true
+    }
+
+    /// Returns whether the timer is currently paused
+    pub fn is_paused(&self) -> bool {
+        self.clock.is_paused()
+    }
+
+    /// Signal the executor to shut down once it has finished running its current batch of tasks,
+    /// without waiting for it to do so. Any pending tasks will not be run, and any attempt to
+    /// schedule further tasks will fail with [`ScheduleError::ShuttingDown`]. The executor thread
+    /// is joined when the `Timer` is dropped.
+    pub fn begin_shutdown(&self) {
+        self.shared.lock().done = true;
+        self.changed.notify_all();
+        self.completed.notify_all();
+        #[cfg(feature = "async")]
+        if let Some(changed) = &self.async_changed {
+            changed.notify_one();
+        }
+    }
+
+    /// Shut down the timer, first running every task which is already due on the calling thread.
+    /// Tasks are run in order until `budget` (which includes the time spent waiting for the
+    /// executor threads to finish their current batch) is used up; a task which has started is
+    /// always allowed to finish, and any due tasks left over are dropped without running, as are
+    /// all tasks which are not yet due. Repeating tasks which are due run once.
+    pub fn shutdown_draining_timeout(mut self, budget: Duration) -> DrainReport {
+        let started = Instant::now();
+        let (due, cancelled) = {
+            let mut shared = self.shared.lock();
+            shared.accepting = false;
+            let now = self.now();
+            let (cancelled, live): (Vec<_>, Vec<_>) =
+                shared.tasks.take_all().into_iter().partition(Task::dropped);
+            let (mut due, pending): (Vec<_>, Vec<_>) = live
+                .into_iter()
+                .partition(|t| t.next_execution() <= now && t.held_for().is_none());
+            shared.tasks.extend(pending);
+            due.sort_by_key(|t| t.next_execution());
+            (due, cancelled)
+        };
+        let observer = observer::resolve(self.config.observer.as_ref());
+        executor::discard_cancelled(observer, cancelled);
+        self.begin_shutdown();
+        for thread in self.executor_threads.get_mut().drain(..) {
+            thread.join();
+        }
+        let total = due.len();
+        let mut drained = 0;
+        let clock = AssertUnwindSafe(&*self.config.clock.0);
+        let mut due = due.into_iter();
+        for item in due.by_ref() {
+            if started.elapsed() > budget {
+                // Put it back, to be reported as dropped along with anything not yet due
+                self.shared.lock().tasks.push(item);
+                break;
+            }
+            let task_id = item.id();
+            let run_started = Instant::now();
+            match std::panic::catch_unwind(|| item.run(*clock)) {
+                Ok(_) => executor::record_executed(observer, task_id, run_started.elapsed()),
+                Err(e) => {
+                    log::error!("uncaught panic when draining task: {:?}", e);
+                    executor::record_panicked(observer, task_id);
+                }
+            }
+            drained += 1;
+        }
+        self.shared.lock().tasks.extend(due);
+        if drained < total {
+            log::warn!(
+                "ran out of time draining due tasks; abandoned {} of {}",
+                total - drained,
+                total
+            );
+        }
+        DrainReport {
+            drained,
+            abandoned: total - drained,
+        }
+    }
+
+    /// Start another executor thread, so that more tasks can run concurrently. Together with
+    /// [`remove_executor_thread`](Self::remove_executor_thread), this can be used to scale the
+    /// number of executor threads with the load, for example based on
+    /// [`ready_count`](Self::ready_count). Does nothing if the timer is shutting down.
+    pub fn add_executor_thread(&self) {
+        if self.shared.lock().done {
+            return;
+        }
+        let runner = self.new_runner();
+        let mut threads = self.executor_threads.lock();
+        Self::reap_retired(&mut threads);
+        threads.push(ExecutorThread::spawn(runner));
+    }
+
+    /// Signal one of the timer's executor threads to exit once it has finished running its
+    /// current batch of tasks; the rest carry on as usual. Returns `false` (and does nothing) if
+    /// the timer has only one executor thread left. Executors run with
+    /// [`ExecutorRunner::run`] or on a Tokio runtime are never removed.
+    pub fn remove_executor_thread(&self) -> bool {
+        let mut threads = self.executor_threads.lock();
+        Self::reap_retired(&mut threads);
+        let mut active = threads
+            .iter()
+            .rev()
+            .filter(|t| !t.retired.load(Ordering::Relaxed));
+        match (active.next(), active.next()) {
+            (Some(last), Some(_)) => {
+                last.retired.store(true, Ordering::Relaxed);
+                drop(threads);
+                // Wake the retired thread if it's waiting, wherever it is. Taking the lock first
+                // means it can't miss this between checking the flag and starting to wait.
+                let _shared = self.shared.lock();
+                self.changed.notify_all();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The number of executor threads the timer has started which have not been removed with
+    /// [`remove_executor_thread`](Self::remove_executor_thread)
+    pub fn executor_thread_count(&self) -> usize {
+        self.executor_threads
+            .lock()
+            .iter()
+            .filter(|t| !t.retired.load(Ordering::Relaxed))
+            .count()
+    }
+
+    /// Join any retired executor threads which have exited
+    fn reap_retired(threads: &mut Vec<ExecutorThread>) {
+        let (exited, running) = std::mem::take(threads)
+            .into_iter()
+            .partition::<Vec<_>, _>(|t| {
+                t.retired.load(Ordering::Relaxed) && t.handle.is_finished()
+            });
+        *threads = running;
+        exited.into_iter().for_each(ExecutorThread::join);
+    }
+
+    /// Restart a timer which has been shut down with [`begin_shutdown`](Self::begin_shutdown),
+    /// so that it can be reused (for example, by an object pool). This waits for the old
+    /// executor threads to exit, then starts the configured number of fresh executor threads on
+    /// the same queue. Any tasks which were still pending when the timer was shut down will be run
+    /// by the new executor, and keep their IDs. Returns `false` (and does nothing) if the timer
+    /// hasn't been shut down.
+    ///
+    /// Only a timer which started its own executor threads can be restarted. The executor of a
+    /// timer from [`new_unstarted`](Self::new_unstarted) or `spawn_on` is out of the timer's
+    /// hands, so can't be waited for; for those, this always returns `false`.
+    ///
+    /// The restarted timer hands out IDs from a new generation, so they never collide with IDs
+    /// from before the restart, and a stale ID (say, one held by a caller which didn't see the
+    /// restart) can't refer to a new task.
+    pub fn restart(&mut self) -> bool {
+        if !self.owns_executor || !self.shared.lock().done {
+            return false;
+        }
+        for thread in self.executor_threads.get_mut().drain(..) {
+            thread.join();
+        }
+        let mut shared = self.shared.lock();
+        shared.done = false;
+        shared.generation = next_generation();
+        shared.next_id = 1;
+        drop(shared);
+        self.spawn_executor_threads(self.new_runner());
+        true
+    }
+
+    /// Block until every one-off task which is currently pending has run (or been cancelled and
+    /// discarded). Repeating tasks and tasks scheduled after this is called are not waited for.
+    /// Returns early if the timer is shut down.
+    ///
+    /// This waits for all pending one-off tasks, including those which aren't due for a long time
+    /// or are held back by [`schedule_when_below`](Self::schedule_when_below), and will block
+    /// forever if the executor of an unstarted timer is never run.
+    pub fn flush(&self) {
+        let mut shared = self.shared.lock();
+        let mut waiting = shared
+            .pending_tasks()
+            .filter(|t| !t.is_repeating() && !t.dropped())
+            .map(Task::id)
+            .collect::<HashSet<_>>();
+        // Only look at the IDs which have finished since this started
+        let mut seen = shared.finished.len();
+        shared.flushing += 1;
+        while !waiting.is_empty() && !shared.done {
+            self.completed.wait(&mut shared);
+            for task_id in &shared.finished[seen..] {
+                waiting.remove(task_id);
+            }
+            seen = shared.finished.len();
+        }
+        shared.flushing -= 1;
+        if shared.flushing == 0 {
+            shared.finished.clear();
+        }
+    }
+
+    /// Schedule a task to run once, as soon as there are fewer than `threshold` tasks in the
+    /// queue. This is the counterpart of [`on_high_watermark`](Self::on_high_watermark), for
+    /// deferred low-priority work such as cleanup or compaction which should wait for a lull.
+    /// Until then, the task is held out of the queue (so it doesn't count towards the length of
+    /// any queue, including its own). The executor checks the length whenever it takes tasks from
+    /// the queue.
+    pub fn schedule_when_below<F: FnOnce() + UnwindSafe + Send + 'static>(
+        &mut self,
+        threshold: usize,
+        f: F,
+    ) -> TaskGuard {
+        let callable = TaskCallable::new_once(f);
+        let options = TaskOptions {
+            when_below: Some(threshold),
+            ..TaskOptions::default()
+        };
+        Self::guard_or_log(self.try_push_with(callable, self.now(), options))
+    }
+
+    /// Register a callback to be invoked when the number of pending tasks exceeds `threshold`. The
+    /// callback is invoked with the current number of pending tasks on the thread which scheduled
+    /// the task that crossed the watermark, and will only be invoked once per crossing; it will
+    /// not be invoked again until the number of pending tasks has dropped below half of
+    /// `threshold`. This replaces any previously-registered callback.
+    pub fn on_high_watermark<F: Fn(usize) + Send + Sync + 'static>(&self, threshold: usize, f: F) {
+        self.shared.lock().watermark = Some(Watermark {
+            high: threshold,
+            low: threshold / 2,
+            crossed: false,
+            callback: Arc::new(f),
+        });
+    }
+
+    /// Change the interval of a pending repeating task and the instant at which it will next
+    /// fire. Subsequent firings will happen every `new_interval` after that. Returns `false` if
+    /// there is no such pending repeating task (including if it is currently being executed).
+    ///
+    /// This has to rebuild the queue, so is O(n) in the number of pending tasks.
+    pub fn reschedule_repeating(
+        &self,
+        task_id: u128,
+        new_interval: Duration,
+        next_at: Instant,
+    ) -> bool {
+        let mut shared = self.shared.lock();
+        let modified = shared.modify_task(task_id, |task| {
+            if task.set_interval(new_interval) {
+                task.set_next_execution(next_at);
+                true
+            } else {
+                false
+            }
+        });
+        drop(shared);
+        if modified {
+            self.notify();
+        }
+        modified
+    }
+
+    /// Schedule a task to run on the executor right after every `n`th run of the task
+    /// represented by `master`, so that the two stay phase-locked rather than drifting apart as
+    /// two independent repeating tasks would. The returned guard can be used to cancel this task
+    /// independently of `master`; cancelling `master` stops both. Returns
+    /// [`ScheduleError::NoSuchTask`] unless `master` is a pending (or running) repeating task.
+    ///
+    /// # Panics
+    /// Panics if `n` is zero
+    pub fn schedule_every_nth<F: FnMut() + UnwindSafe + Send + 'static>(
+        &mut self,
+        master: &TaskGuard,
+        n: u64,
+        f: F,
+    ) -> Result<TaskGuard, ScheduleError> {
+        assert!(n > 0, "n must be non-zero");
+        let master_id = master.task_id();
+        let mut shared = self.shared.lock();
+        shared.check_accepting()?;
+        let id = shared.next_id();
+        let follower = Follower::new(id, n, f);
+        let guard = follower.guard();
+        let mut follower = Some(follower);
+        shared.modify_task(master_id, |task| {
+            // A one-off task has no runs to count
+            task.is_repeating() && {
+                task.add_followers(follower.take());
+                true
+            }
+        });
+        if let Some(follower) = follower {
+            if !shared.in_flight_repeating.contains(&master_id) {
+                return Err(ScheduleError::NoSuchTask);
+            }
+            shared
+                .pending_followers
+                .entry(master_id)
+                .or_default()
+                .push(follower);
+        }
+        shared.next_id += 1;
+        Ok(guard)
+    }
+
+    /// Schedule a task to run on the executor right after every run of the task represented by
+    /// `primary`, on the same tick; this is [`schedule_every_nth`](Self::schedule_every_nth)
+    /// with `n` of 1. This suits coordinated sampling, where one task must always see the effects
+    /// of another's latest run.
+    pub fn schedule_coupled<F: FnMut() + UnwindSafe + Send + 'static>(
+        &mut self,
+        primary: &TaskGuard,
+        f: F,
+    ) -> Result<TaskGuard, ScheduleError> {
+        self.schedule_every_nth(primary, 1, f)
+    }
+
+    /// Get how long until the pending task with the given ID will next fire. Returns `None` if
+    /// the task has already run (or is currently running) or has been cancelled. A task which is
+    /// overdue will return a zero duration.
+    ///
+    /// This scans the queue, so is O(n) in the number of pending tasks.
+    pub fn time_remaining(&self, task_id: u128) -> Option<Duration> {
+        let now = self.now();
+        self.shared
+            .lock()
+            .pending_tasks()
+            .find(|t| t.id() == task_id && !t.dropped())
+            .map(|t| t.next_execution().saturating_duration_since(now))
+    }
+
+    /// Bring the pending task with the given ID forward to run after `duration`, if that is
+    /// sooner than it would otherwise run; a task is never pushed back. This gives "whichever
+    /// timeout fires first" semantics when adding candidate deadlines to a task scheduled with
+    /// [`schedule_in_earliest`](Self::schedule_in_earliest). Returns `true` if the task was
+    /// brought forward, and `false` if it was already due sooner or there is no such pending task.
+    ///
+    /// This has to rebuild the queue, so is O(n) in the number of pending tasks.
+    pub fn reschedule_earliest(&self, task_id: u128, duration: Duration) -> bool {
+        let next = self.now() + duration;
+        let mut shared = self.shared.lock();
+        let moved = shared.modify_task(task_id, |task| {
+            if next < task.next_execution() {
+                task.set_next_execution(next);
+                true
+            } else {
+                false
+            }
+        });
+        drop(shared);
+        if moved {
+            self.notify();
+        }
+        moved
+    }
+
+    /// Make the pending task with the given ID due now, so that it runs as soon as the executor
+    /// gets to it, for work which has suddenly become urgent. Unlike the tie-breaking of
+    /// [`schedule_in_tier`](Self::schedule_in_tier), this changes the task's deadline. A
+    /// repeating task carries on at its usual interval from this run. Returns `false` if there is
+    /// no such pending task (because it has already run or been cancelled).
+    ///
+    /// This has to rebuild the queue, so is O(n) in the number of pending tasks.
+    pub fn expedite(&self, task_id: u128) -> bool {
+        let now = self.now();
+        let mut shared = self.shared.lock();
+        let found = shared.modify_task(task_id, |task| {
+            task.set_next_execution(now.min(task.next_execution()));
+            true
+        });
+        drop(shared);
+        if found {
+            self.notify();
+        }
+        found
+    }
+
+    /// When the task at the front of the queue is due, if there is one
+    #[cfg(feature = "test-util")]
+    pub(crate) fn next_due(&self) -> Option<Instant> {
+        self.shared.lock().tasks.peek().map(Task::next_execution)
+    }
+
+    pub(crate) fn re_arm_task(&self, task_id: u128, new_delay: Duration) -> Option<u128> {
+        let mut shared = self.shared.lock();
+        let new_id = shared.next_id();
+        let next_execution = self.now() + new_delay;
+        if !shared.modify_task(task_id, |task| {
+            task.set_id(new_id);
+            task.set_next_execution(next_execution);
+            true
+        }) {
+            return None;
+        }
+        shared.next_id += 1;
+        // Everything else kept by ID follows the task to its new one
+        if let Some(stats) = shared.stats.remove(&task_id) {
+            shared.stats.insert(new_id, stats);
+        }
+        if let Some(callbacks) = shared.completion_callbacks.remove(&task_id) {
+            shared.completion_callbacks.insert(new_id, callbacks);
+        }
+        if let Some(followers) = shared.pending_followers.remove(&task_id) {
+            shared.pending_followers.insert(new_id, followers);
+        }
+        if shared.gated.remove(&task_id) {
+            shared.gated.insert(new_id);
+        }
+        drop(shared);
+        self.notify();
+        Some(new_id)
+    }
+
+    /// Atomically take all pending tasks out of this timer, so that they can be put back later
+    /// with [`put_tasks`](Self::put_tasks) (for example, into a new timer whose executor thread
+    /// has different settings). Cancelled tasks are discarded. Tasks which are currently being
+    /// executed are not taken.
+    pub fn take_tasks(&self) -> TaskBundle {
+        let mut shared = self.shared.lock();
+        let (cancelled, tasks): (Vec<_>, Vec<_>) =
+            shared.tasks.take_all().into_iter().partition(Task::dropped);
+        shared.epoch += 1;
+        shared.record_finished(cancelled.iter().chain(&tasks).map(Task::id));
+        drop(shared);
+        self.completed.notify_all();
+        executor::discard_cancelled(observer::resolve(self.config.observer.as_ref()), cancelled);
+        TaskBundle::new(tasks)
+    }
+
+    /// Atomically put a bundle of tasks taken with [`take_tasks`](Self::take_tasks) into this
+    /// timer. They will keep their original deadlines and IDs; since every timer hands out IDs
+    /// from its own generation, those IDs won't coincide with the IDs of tasks already in this one
+    /// (unless they were chosen with [`schedule_in_with_id`](Self::schedule_in_with_id)).
+    pub fn put_tasks(&self, bundle: TaskBundle) {
+        let mut shared = self.shared.lock();
+        for mut task in bundle.into_tasks() {
+            // Make sure we never hand out these IDs again
+            if task.id() >> 64 == u128::from(shared.generation) {
+                shared.next_id = shared.next_id.max(task.id() as u64 + 1);
+            }
+            // Its place in the other timer's submission order means nothing here
+            task.set_fifo_seq(0);
+            shared.tasks.push(task);
+        }
+        shared.epoch += 1;
+        drop(shared);
+        self.notify();
+    }
+
+    /// Get the state shared between this timer and its executor, and the condition variable used
+    /// to wake the executor, for building other subsystems which observe the timer. The shared
+    /// state can only be read, not modified.
+    ///
+    /// Callers must uphold the following:
+    /// - the lock should only be held very briefly, since holding it blocks both the executor and
+    ///   anyone scheduling tasks
+    /// - the condition variable must never be waited on, since that could steal a notification
+    ///   meant for the executor and delay tasks; it may be notified, but the executor goes
+    ///   straight back to sleep unless the shared state has changed
+    pub fn shared_parts(&self) -> (Arc<Mutex<TimerShared>>, Arc<Condvar>) {
+        (Arc::clone(&self.shared), Arc::clone(&self.changed))
+    }
+
+    /// Get the number of times the executor has had to immediately rescan the queue because it
+    /// changed between the executor deciding to sleep and actually sleeping. A rapidly-increasing
+    /// value indicates heavy scheduling churn (see [`TimerBuilder::condvar_backoff`]).
+    pub fn rescan_count(&self) -> u64 {
+        self.shared.lock().rescans
+    }
+
+    /// Get the number of times the executor has been woken while waiting for a task even though
+    /// nothing had changed (a spurious wakeup, or a notification meant for another executor
+    /// thread). The executor goes straight back to waiting in that case rather than rescanning
+    /// the queue.
+    pub fn spurious_wakeup_count(&self) -> u64 {
+        self.shared.lock().spurious_wakeups
+    }
+
+    /// Check the timer's internal state for consistency, returning the first problem found. This
+    /// should never fail; it is meant as a canary in tests (particularly fuzz tests) which
+    /// exercise the APIs for modifying pending tasks.
+    ///
+    /// This is O(n) in the number of pending tasks, and holds the lock throughout.
+    pub fn verify_invariants(&self) -> Result<(), InvariantViolation> {
+        self.shared.lock().verify_invariants()
+    }
+
+    /// Get the reason the executor last woke up from waiting for a task to become due, or `None`
+    /// if it has never waited. This is also logged (at trace level) on every wakeup, which can
+    /// help diagnose tasks running late.
+    pub fn last_wake_reason(&self) -> Option<WakeReason> {
+        self.shared.lock().last_wake
+    }
+
+    /// Get the IDs and next firing times of all pending tasks due to fire within `within` from
+    /// now, sorted by when they will fire. Cancelled tasks are not included.
+    ///
+    /// The queue is only ordered enough to cheaply find the very next task, so this has to scan
+    /// and sort it, making it O(n log n) in the number of pending tasks.
+    pub fn upcoming(&self, within: Duration) -> Vec<(u128, Instant)> {
+        let horizon = self.now() + within;
+        let shared = self.shared.lock();
+        let mut upcoming = shared
+            .tasks
+            .iter()
+            .filter(|t| !t.dropped() && t.next_execution() <= horizon)
+            .map(|t| (t.id(), t.next_execution()))
+            .collect::<Vec<_>>();
+        drop(shared);
+        upcoming.sort_unstable_by_key(|&(id, when)| (when, id));
+        upcoming
+    }
+
+    /// Get the number of pending tasks which are due to run right now, which is a direct measure of
+    /// whether the executor is falling behind. Cancelled tasks are not counted.
+    ///
+    /// The queue isn't sorted, so this has to scan all of it, making it O(n) in the number of
+    /// pending tasks (but cheaper than [`upcoming`](Self::upcoming)).
+    pub fn ready_count(&self) -> usize {
+        let now = self.now();
+        let shared = self.shared.lock();
+        shared
+            .tasks
+            .iter()
+            .filter(|t| !t.dropped() && t.ready(now) == Ready::Now)
+            .count()
+    }
+
+    /// Get the number of pending repeating tasks (including those scheduled with
+    /// [`schedule_poll`](Self::schedule_poll)), which is the steady-state load on the timer.
+    /// Cancelled tasks are not counted, nor are tasks which are currently running.
+    ///
+    /// Like [`ready_count`](Self::ready_count), this is O(n) in the number of pending tasks.
+    pub fn pending_repeating_count(&self) -> usize {
+        self.count_pending(true)
+    }
+
+    /// Get the number of pending one-shot tasks. Cancelled tasks are not counted, nor are tasks
+    /// which are currently running.
+    ///
+    /// Like [`ready_count`](Self::ready_count), this is O(n) in the number of pending tasks.
+    pub fn pending_once_count(&self) -> usize {
+        self.count_pending(false)
+    }
+
+    /// Estimate how much memory the pending tasks are taking up, in bytes: the queue's capacity,
+    /// plus each task's boxed closure and the state shared with its guard. Whatever the closures
+    /// themselves own (such as captured `Vec`s or `String`s) can't be measured, so this is a lower
+    /// bound. Tasks which are currently running are not counted.
+    ///
+    /// This scans every pending task, so is O(n) in the number of pending tasks.
+    pub fn approx_memory_bytes(&self) -> usize {
+        let shared = self.shared.lock();
+        shared.tasks.approx_bytes()
+            + shared
+                .tasks
+                .iter()
+                .map(Task::approx_heap_bytes)
+                .sum::<usize>()
+    }
+
+    /// Get the IDs of tasks which have been cancelled (or whose guards have been dropped) but are
+    /// still taking up space in the queue, because they haven't come due yet. A long list here
+    /// usually means guards for far-future tasks are being dropped much earlier than expected;
+    /// [`purge_cancelled`](Self::purge_cancelled) can be used to reclaim the space.
+    ///
+    /// This scans every pending task, so is O(n) in the number of pending tasks.
+    pub fn dropped_pending(&self) -> Vec<u128> {
+        let shared = self.shared.lock();
+        shared
+            .pending_tasks()
+            .filter(|t| t.dropped())
+            .map(Task::id)
+            .collect()
+    }
+
+    /// Throw away all cancelled tasks in the queue now, rather than waiting for each of them to
+    /// come due. Returns the number of tasks removed.
+    ///
+    /// This is O(n) in the number of pending tasks.
+    pub fn purge_cancelled(&self) -> usize {
+        let cancelled = self.shared.lock().purge_cancelled();
+        self.completed.notify_all();
+        let count = cancelled.len();
+        executor::discard_cancelled(observer::resolve(self.config.observer.as_ref()), cancelled);
+        count
+    }
+
+    fn count_pending(&self, repeating: bool) -> usize {
+        let shared = self.shared.lock();
+        shared
+            .tasks
+            .iter()
+            .filter(|t| !t.dropped() && t.is_repeating() == repeating)
+            .count()
+    }
+
+    /// Returns whether the calling thread is one of this timer's executor threads, which is the
+    /// case inside a task it is running. Code which might run as a task can use this to avoid
+    /// blocking on the timer (such as with [`TaskGuard::wait`]), which would deadlock if there is
+    /// only one executor thread. This includes threads running an [`ExecutorRunner`] from
+    /// [`new_unstarted`](Self::new_unstarted), but not the blocking pool threads used by a timer
+    /// from [`spawn_on`](Self::spawn_on).
+    pub fn on_executor_thread(&self) -> bool {
+        let thread = std::thread::current().id();
+        self.shared.lock().executor_thread_ids.contains(&thread)
+    }
+
+    /// Get the ID of the task the executor is running right now, or `None` if it is idle. If this
+    /// keeps returning the same ID, that task is stuck. With several executor threads, this is
+    /// the running task of the first busy one; use [`current_tasks`](Self::current_tasks) to see
+    /// them all.
+    pub fn current_task(&self) -> Option<u128> {
+        self.current_tasks().into_iter().next()
+    }
+
+    /// Get the IDs of the tasks every executor thread is running right now. Idle executors
+    /// aren't included, so this is empty if none of them is running a task.
+    pub fn current_tasks(&self) -> Vec<u128> {
+        let slots = self.shared.lock().current_tasks.clone();
+        slots.iter().filter_map(|slot| slot.get()).collect()
+    }
+
+    /// Get a histogram of the sizes of the batches of tasks the executor has run: element `n` is
+    /// the number of batches which held `n` tasks, up to [`MAX_PER_LOOP`](Self::MAX_PER_LOOP)
+    /// (so element 0 is always zero). If most batches are at the cap, the executor is regularly
+    /// finding more tasks due at once than it can take in one go.
+    pub fn batch_size_histogram(&self) -> Vec<u64> {
+        let batch_sizes = Arc::clone(&self.shared.lock().batch_sizes);
+        batch_sizes
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Get execution statistics for the repeating task with the given ID. Returns `None` if the task
+    /// has not completed a run yet, is not a repeating task, or has been cancelled.
+    pub fn task_stats(&self, task_id: u128) -> Option<TaskStats> {
+        self.shared.lock().stats.get(&task_id).copied()
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Timer {
     /// Drop the timer and shut down the background thread
     fn drop(&mut self) {
-        if let Some(handle) = self.executor_thread.take() {
-            let mut s = self.shared.lock();
-            s.done = true;
-            self.changed.notify_one();
-            drop(s);
-            if let Err(e) = handle.join() {
-                log::error!("Error joining timer thread: {:?}", e);
+        self.begin_shutdown();
+        for thread in self.executor_threads.get_mut().drain(..) {
+            thread.join();
+        }
+        if let Some(observer) = observer::resolve(self.config.observer.as_ref()) {
+            let dropped = {
+                let shared = self.shared.lock();
+                shared
+                    .pending_tasks()
+                    .filter(|task| !task.dropped())
+                    .map(Task::id)
+                    .collect::<Vec<_>>()
+            };
+            for task_id in dropped {
+                observer.task_outcome(task_id, TaskOutcome::ShutdownDropped);
             }
         }
     }
@@ -0,0 +1,58 @@
+//! Counts allocations, so must be in its own test binary with a single test
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use synchronous_timer::Timer;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const TASKS: usize = 10_000;
+
+fn count_allocations<F: FnOnce(&mut Timer)>(f: F) -> usize {
+    // Never start the executor, so that only the scheduling is counted
+    let (mut t, _runner) = Timer::builder().capacity(TASKS).build_unstarted();
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f(&mut t);
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+#[test]
+fn test_lightweight_allocations() {
+    let normal = count_allocations(|t| {
+        for i in 0..TASKS {
+            t.schedule_immediately(move || {
+                std::hint::black_box(i);
+            });
+        }
+    });
+    let lightweight = count_allocations(|t| {
+        for i in 0..TASKS {
+            t.schedule_immediately_lightweight(move || {
+                std::hint::black_box(i);
+            });
+        }
+    });
+    assert!(
+        lightweight + TASKS <= normal,
+        "{} allocations for lightweight tasks, {} for normal",
+        lightweight,
+        normal
+    );
+}
@@ -0,0 +1,59 @@
+//! An example of testing code which uses a timer, as a downstream crate would, by driving it
+//! with virtual time
+#![cfg(feature = "test-util")]
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use synchronous_timer::{ManualTimer, TaskGuard, Timer};
+
+/// A cache entry which expires after a time-to-live
+struct Expiring {
+    value: Arc<Mutex<Option<String>>>,
+    _expiry: TaskGuard,
+}
+
+impl Expiring {
+    fn new(timer: &mut Timer, value: &str, ttl: Duration) -> Self {
+        let value = Arc::new(Mutex::new(Some(value.to_owned())));
+        let their_value = Arc::clone(&value);
+        let expiry = timer.schedule_in(ttl, move || {
+            their_value.lock().unwrap().take();
+        });
+        Self {
+            value,
+            _expiry: expiry,
+        }
+    }
+
+    fn get(&self) -> Option<String> {
+        self.value.lock().unwrap().clone()
+    }
+}
+
+#[test]
+fn test_expiry_with_virtual_time() {
+    let mut timer = ManualTimer::new();
+    let entry = Expiring::new(&mut timer, "hello", Duration::from_secs(30));
+    assert_eq!(timer.advance(Duration::from_secs(29)), 0);
+    assert_eq!(entry.get().as_deref(), Some("hello"));
+    assert_eq!(timer.advance(Duration::from_secs(1)), 1);
+    assert_eq!(entry.get(), None);
+}
+
+#[test]
+fn test_repeating_with_virtual_time() {
+    let mut timer = ManualTimer::new();
+    let start = timer.now();
+    let ticks = Arc::new(Mutex::new(0));
+    let their_ticks = Arc::clone(&ticks);
+    let _guard = timer.schedule_repeating(Duration::from_secs(10), move || {
+        *their_ticks.lock().unwrap() += 1;
+    });
+    // A single long advance runs the task every time it would have come due
+    assert_eq!(timer.advance(Duration::from_secs(55)), 5);
+    assert_eq!(*ticks.lock().unwrap(), 5);
+    assert_eq!(timer.now() - start, Duration::from_secs(55));
+    assert_eq!(timer.run_pending(), 0);
+    assert_eq!(timer.advance(Duration::from_secs(5)), 1);
+}
@@ -0,0 +1,46 @@
+//! The global observer is shared by every timer in the process, so it gets a test binary of its
+//! own
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use synchronous_timer::{set_global_observer, Timer, TimerObserver};
+
+#[derive(Default)]
+struct Recorder {
+    scheduled: Mutex<Vec<u128>>,
+    executed: Mutex<Vec<u128>>,
+}
+
+impl TimerObserver for Recorder {
+    fn task_scheduled(&self, task_id: u128) {
+        self.scheduled.lock().unwrap().push(task_id);
+    }
+
+    fn task_executed(&self, task_id: u128, _duration: Duration) {
+        self.executed.lock().unwrap().push(task_id);
+    }
+}
+
+#[test]
+fn test_global_observer() {
+    let global = Arc::new(Recorder::default());
+    assert!(set_global_observer(Arc::clone(&global) as Arc<dyn TimerObserver>).is_ok());
+    assert!(set_global_observer(Arc::new(Recorder::default())).is_err());
+
+    let mut t = Timer::new();
+    let guard = t.schedule_in(Duration::from_millis(1), || {});
+    let task_id = guard.task_id();
+    t.flush();
+    assert_eq!(*global.scheduled.lock().unwrap(), vec![task_id]);
+    assert_eq!(*global.executed.lock().unwrap(), vec![task_id]);
+
+    // A timer with its own observer doesn't report to the global one
+    let own = Arc::new(Recorder::default());
+    let mut t = Timer::builder().observer(Arc::clone(&own)).build();
+    let guard = t.schedule_in(Duration::from_millis(1), || {});
+    t.flush();
+    assert_eq!(*own.executed.lock().unwrap(), vec![guard.task_id()]);
+    assert_eq!(global.scheduled.lock().unwrap().len(), 1);
+    assert_eq!(global.executed.lock().unwrap().len(), 1);
+}
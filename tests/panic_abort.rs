@@ -0,0 +1,40 @@
+//! CI also builds this with `-C panic=abort`, where the timer can't catch panics and should say
+//! so. The test harness doesn't support that on stable, so this has its own `main`.
+
+use std::sync::Mutex;
+
+use synchronous_timer::Timer;
+
+static LOGGED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct Capture;
+
+impl log::Log for Capture {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        LOGGED.lock().unwrap().push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+fn main() {
+    log::set_logger(&Capture).unwrap();
+    log::set_max_level(log::LevelFilter::Warn);
+    let _t = Timer::new();
+    let warned = LOGGED
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|line| line.contains("panic=abort"));
+    if cfg!(panic = "abort") {
+        assert!(!Timer::catches_panics());
+        assert!(warned, "no warning about panic=abort was logged");
+    } else {
+        assert!(Timer::catches_panics());
+        assert!(!warned);
+    }
+}